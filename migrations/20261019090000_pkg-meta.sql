@@ -0,0 +1,39 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+CREATE TABLE pkg_meta (
+	name		VARCHAR(255) NOT NULL,
+	repo		VARCHAR(255) NOT NULL REFERENCES repos(name),
+	summary		TEXT,
+	description	TEXT,
+	license		TEXT,
+	url			TEXT,
+	srpm		VARCHAR(255),
+	PRIMARY KEY (name, repo)
+);
+
+CREATE TABLE pkg_subpkgs (
+	name	VARCHAR(255) NOT NULL,
+	repo	VARCHAR(255) NOT NULL REFERENCES repos(name),
+	subpkg	VARCHAR(255) NOT NULL,
+	PRIMARY KEY (name, repo, subpkg)
+);
+
+-- kind is either 'buildrequires' or 'requires'
+CREATE TABLE pkg_deps (
+	name	VARCHAR(255) NOT NULL,
+	repo	VARCHAR(255) NOT NULL REFERENCES repos(name),
+	kind	VARCHAR(16) NOT NULL,
+	dep		VARCHAR(255) NOT NULL,
+	PRIMARY KEY (name, repo, kind, dep)
+);
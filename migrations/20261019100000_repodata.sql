@@ -0,0 +1,31 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+CREATE TABLE repodata (
+	repo		VARCHAR(255) PRIMARY KEY REFERENCES repos(name),
+	revision	VARCHAR(255),
+	synced		TIMESTAMP NOT NULL
+);
+
+CREATE TABLE repodata_pkgs (
+	repo		VARCHAR(255) NOT NULL REFERENCES repos(name),
+	name		VARCHAR(255) NOT NULL,
+	arch		VARCHAR(255) NOT NULL,
+	epoch		VARCHAR(255) NOT NULL,
+	ver			VARCHAR(255) NOT NULL,
+	rel			VARCHAR(255) NOT NULL,
+	location	TEXT NOT NULL,
+	srpm		VARCHAR(255),
+	PRIMARY KEY (repo, location)
+);
+CREATE INDEX repodata_pkgs_name ON repodata_pkgs (repo, name);
@@ -0,0 +1,47 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+CREATE SEQUENCE advisory_seq;
+
+-- kind is one of 'bugfix', 'security' or 'enhancement'
+CREATE TABLE advisories (
+	id			VARCHAR(255) PRIMARY KEY,
+	repo		VARCHAR(255) NOT NULL REFERENCES repos(name),
+	kind		VARCHAR(16) NOT NULL,
+	title		TEXT NOT NULL,
+	description	TEXT NOT NULL,
+	severity	VARCHAR(32),
+	issued		TIMESTAMP NOT NULL,
+	updated		TIMESTAMP NOT NULL
+);
+
+CREATE TABLE advisory_pkgs (
+	advisory	VARCHAR(255) NOT NULL REFERENCES advisories(id) ON DELETE CASCADE,
+	name		VARCHAR(255) NOT NULL,
+	epoch		VARCHAR(255) NOT NULL,
+	ver			VARCHAR(255) NOT NULL,
+	rel			VARCHAR(255) NOT NULL,
+	arch		VARCHAR(255) NOT NULL,
+	filename	VARCHAR(255) NOT NULL,
+	PRIMARY KEY (advisory, name, arch)
+);
+
+-- kind is the updateinfo reference type, e.g. 'bugzilla', 'cve' or 'self'
+CREATE TABLE advisory_refs (
+	advisory	VARCHAR(255) NOT NULL REFERENCES advisories(id) ON DELETE CASCADE,
+	kind		VARCHAR(16) NOT NULL,
+	href		TEXT NOT NULL,
+	ref_id		VARCHAR(255),
+	title		TEXT,
+	PRIMARY KEY (advisory, href)
+);
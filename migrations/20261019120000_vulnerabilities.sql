@@ -0,0 +1,42 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+-- OSV advisories concerning at least one package we ship
+CREATE TABLE osv (
+	id		VARCHAR(255) PRIMARY KEY,
+	data	JSONB NOT NULL
+);
+
+-- upstream (ecosystem, name) of a package, when it cannot be guessed from its name
+CREATE TABLE upstream_names (
+	repo		VARCHAR(255) NOT NULL REFERENCES repos(name),
+	name		VARCHAR(255) NOT NULL,
+	ecosystem	VARCHAR(255) NOT NULL,
+	upstream	VARCHAR(255) NOT NULL,
+	PRIMARY KEY (repo, name)
+);
+
+CREATE TABLE vulnerabilities (
+	id			VARCHAR(255) NOT NULL,
+	repo		VARCHAR(255) NOT NULL REFERENCES repos(name),
+	name		VARCHAR(255) NOT NULL,
+	ver			VARCHAR(255) NOT NULL,
+	ecosystem	VARCHAR(255) NOT NULL,
+	upstream	VARCHAR(255) NOT NULL,
+	summary		TEXT NOT NULL,
+	aliases		TEXT[] NOT NULL,
+	severity	VARCHAR(255),
+	fixed		VARCHAR(255),
+	found		TIMESTAMP NOT NULL,
+	PRIMARY KEY (id, repo, name)
+);
@@ -0,0 +1,26 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+-- see `forge.rs` for the template placeholders
+ALTER TABLE repos ADD forge VARCHAR(16) NOT NULL DEFAULT 'github';
+ALTER TABLE repos ADD url VARCHAR(255);
+ALTER TABLE repos ADD branch VARCHAR(255);
+ALTER TABLE repos ADD tree_url VARCHAR(255);
+ALTER TABLE repos ADD raw_url VARCHAR(255);
+ALTER TABLE repos ADD commit_url VARCHAR(255);
+ALTER TABLE repos ADD run_url VARCHAR(255);
+
+-- `gh` used to be a GitHub tree URL, e.g. https://github.com/terrapkg/packages/tree/f41
+UPDATE repos SET
+	url = substring(gh FROM '^(.*)/tree/[^/]+/?$'),
+	branch = substring(gh FROM '/tree/([^/]+)/?$');
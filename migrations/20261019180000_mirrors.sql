@@ -0,0 +1,35 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+CREATE TABLE mirrors (
+	name	VARCHAR(255) PRIMARY KEY,
+	-- may contain `{repo}`, otherwise the repo name is appended
+	url		TEXT NOT NULL,
+	region	VARCHAR(255),
+	-- repos carried by the mirror, empty for all of them
+	repos	TEXT[] NOT NULL DEFAULT '{}',
+	contact	TEXT
+);
+
+CREATE TABLE mirror_status (
+	mirror		VARCHAR(255) NOT NULL REFERENCES mirrors(name) ON DELETE CASCADE,
+	repo		VARCHAR(255) NOT NULL REFERENCES repos(name),
+	revision	VARCHAR(255),
+	timestamp	BIGINT,
+	-- seconds behind the primary, NULL if unknown
+	lag			BIGINT,
+	behind		BOOLEAN NOT NULL DEFAULT FALSE,
+	checked		TIMESTAMP NOT NULL,
+	error		TEXT,
+	PRIMARY KEY (mirror, repo)
+);
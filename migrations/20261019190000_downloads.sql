@@ -0,0 +1,22 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+-- daily downloads from mirror access logs, without any client information
+CREATE TABLE downloads (
+	repo	VARCHAR(255) NOT NULL REFERENCES repos(name),
+	name	VARCHAR(255) NOT NULL,
+	arch	VARCHAR(255) NOT NULL,
+	day		DATE NOT NULL,
+	count	BIGINT NOT NULL,
+	PRIMARY KEY (repo, name, arch, day)
+);
@@ -0,0 +1,23 @@
+-- This file is part of Madoguchi.
+--
+-- Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+-- the GNU General Public License as published by the Free Software Foundation, either
+-- version 3 of the License, or (at your option) any later version.
+--
+-- Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+-- without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+-- See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with Madoguchi.
+-- If not, see <https://www.gnu.org/licenses/>.
+--
+-- build and package events, kept for a while so that SSE clients can resume
+CREATE TABLE events (
+	id		BIGSERIAL PRIMARY KEY,
+	kind	VARCHAR(31) NOT NULL,
+	repo	VARCHAR(255) NOT NULL,
+	name	VARCHAR(255),
+	data	JSONB NOT NULL,
+	time	TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc')
+);
+CREATE INDEX events_time ON events (time);
@@ -0,0 +1,347 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Resolution of a package's `anda.hcl`, cached in memory and in the `anda_cache` table.
+//!
+//! Files pinned to a commit never change, so they are fetched once. Files at the branch head
+//! are refreshed with a conditional request after `anda.cache_ttl` seconds (default 1 hour);
+//! if that fails the stale copy is served. Spec files are only cached when pinned to a commit.
+use crate::config;
+use crate::forge::{self, Forge};
+use anda_config::Project;
+use chrono::NaiveDateTime;
+use lru::LruCache;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use sqlx::{PgConnection, PgPool};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use tracing::{error, warn};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Cannot download anda.hcl: {0}")]
+	Http(#[from] reqwest::Error),
+	#[error("Database error: {0}")]
+	Db(#[from] sqlx::Error),
+	#[error("Malformed anda.hcl: {0}")]
+	Hcl(String),
+	#[error("anda.hcl has no project")]
+	NoProject,
+}
+
+/// Where the recipe of a package lives.
+#[derive(Clone, Debug)]
+pub struct Location {
+	pub repo: String,
+	pub dirs: String,
+	pub commit: Option<String>,
+	pub forge: Forge,
+}
+
+impl Location {
+	pub fn new(forge: Forge, repo: &str, dirs: &str, commit: Option<&str>) -> Self {
+		Self {
+			repo: repo.to_owned(),
+			dirs: dirs.to_owned(),
+			commit: commit.map(str::to_owned),
+			forge,
+		}
+	}
+
+	/// Link to `file` in the recipe directory, or to the directory itself if `file` is empty.
+	pub fn tree(&self, file: &str) -> String {
+		self.forge.tree(self.commit.as_deref(), &format!("{}/{file}", self.dirs))
+	}
+
+	/// Link to the raw content of `file` in the recipe directory.
+	pub fn raw(&self, file: &str) -> String {
+		self.forge.raw(self.commit.as_deref(), &format!("{}/{file}", self.dirs))
+	}
+}
+
+/// The recipe of the last successful build of a package, or `None` if it does not exist.
+pub async fn locate(db: &mut PgConnection, repo: &str, name: &str) -> Option<Location> {
+	let dirs = sqlx::query!(
+		"SELECT dirs FROM pkgs WHERE name = $1 AND repo = $2 ORDER BY ver DESC",
+		name,
+		repo
+	);
+	let dirs = dirs.fetch_one(&mut *db).await.ok()?.dirs;
+	let forge = forge::fetch(&mut *db, repo).await.ok()??;
+	let commit = sqlx::query!(
+		"SELECT commit FROM builds WHERE pname=$1 AND repo=$2 AND succ ORDER BY epoch DESC LIMIT 1",
+		name,
+		repo
+	);
+	let commit = commit.fetch_one(&mut *db).await.ok().and_then(|x| x.commit);
+	let loc = Location::new(forge, repo, &dirs, commit.as_deref());
+	tracing::trace!(?loc);
+	Some(loc)
+}
+
+/// The last successful build of `name` at `ver-rel` as `(build ID, recipe)`.
+///
+/// The recipe is `None` if the build predates commit tracking.
+pub async fn locate_version(
+	db: &mut PgConnection, repo: &str, name: &str, ver: &str, rel: &str,
+) -> Option<(String, Option<Location>)> {
+	let build = sqlx::query_as::<_, (String, Option<String>)>(
+		"SELECT id,commit FROM builds WHERE (pname,repo,pver,prel)=($1,$2,$3,$4) AND succ
+		ORDER BY epoch DESC LIMIT 1",
+	);
+	let q = build.bind(name).bind(repo).bind(ver).bind(rel);
+	let (id, commit) = q.fetch_optional(&mut *db).await.ok()??;
+	let Some(commit) = commit else { return Some((id, None)) };
+	let dirs = sqlx::query_scalar::<_, String>("SELECT dirs FROM pkgs WHERE (name,repo)=($1,$2)");
+	let dirs = dirs.bind(name).bind(repo).fetch_optional(&mut *db).await.ok()??;
+	let forge = forge::fetch(&mut *db, repo).await.ok()??;
+	Some((id, Some(Location::new(forge, repo, &dirs, Some(commit.trim())))))
+}
+
+/// A parsed `anda.hcl` along with what is needed to revalidate it.
+#[derive(Debug)]
+pub struct Resolved {
+	pub project: Project,
+	etag: Option<String>,
+	last_modified: Option<String>,
+	fetched: NaiveDateTime,
+}
+
+impl Resolved {
+	/// Path of the spec file, relative to the recipe directory.
+	pub fn spec(&self) -> Option<String> {
+		Some(self.project.rpm.as_ref()?.spec.display().to_string())
+	}
+
+	fn is_fresh(&self, settings: &config::Anda, loc: &Location) -> bool {
+		let ttl = chrono::Duration::seconds(settings.cache_ttl.try_into().unwrap_or(i64::MAX));
+		loc.commit.is_some() || chrono::Utc::now().naive_utc() - self.fetched < ttl
+	}
+}
+
+type Key = (String, String, String);
+
+/// Sized by the settings of the first resolution, which all share the managed [`config::Config`].
+static CACHE: OnceLock<Mutex<LruCache<Key, Arc<Resolved>>>> = OnceLock::new();
+
+/// The client fetching recipes, shared for its connection pool.
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// A poisoned cache is skipped rather than taking down every request that resolves recipes.
+fn lru(settings: &config::Anda) -> &'static Mutex<LruCache<Key, Arc<Resolved>>> {
+	CACHE.get_or_init(|| Mutex::new(LruCache::new(settings.cache_size)))
+}
+
+fn cached(settings: &config::Anda, k: &Key) -> Option<Arc<Resolved>> {
+	lru(settings).lock().ok()?.get(k).cloned()
+}
+
+fn cache(settings: &config::Anda, k: Key, r: &Arc<Resolved>) {
+	if let Ok(mut cache) = lru(settings).lock() {
+		cache.put(k, Arc::clone(r));
+	}
+}
+
+fn key(loc: &Location) -> Key {
+	(loc.repo.clone(), loc.dirs.clone(), loc.commit.clone().unwrap_or_default())
+}
+
+fn parse(hcl: &str) -> Result<Project, Error> {
+	let manifest = anda_config::load_from_string(hcl).map_err(|e| Error::Hcl(format!("{e:?}")))?;
+	manifest.project.into_values().next().ok_or(Error::NoProject)
+}
+
+async fn load(db: &mut PgConnection, loc: &Location) -> sqlx::Result<Option<Resolved>> {
+	let (repo, dirs, commit) = key(loc);
+	let row = sqlx::query_as::<_, (String, Option<String>, Option<String>, NaiveDateTime)>(
+		"SELECT hcl,etag,last_modified,fetched FROM anda_cache WHERE (repo,dirs,commit)=($1,$2,$3)",
+	)
+	.bind(repo)
+	.bind(dirs)
+	.bind(commit)
+	.fetch_optional(db)
+	.await?;
+	Ok(row.and_then(|(hcl, etag, last_modified, fetched)| match parse(&hcl) {
+		Ok(project) => Some(Resolved { project, etag, last_modified, fetched }),
+		Err(err) => {
+			warn!(%err, ?loc, "Ignoring cached anda.hcl");
+			None
+		},
+	}))
+}
+
+/// Cache `r` in the database. Without `hcl` (a revalidation), only an existing row is updated.
+async fn store(
+	db: &mut PgConnection, loc: &Location, hcl: Option<&str>, r: &Resolved,
+) -> sqlx::Result<()> {
+	let (repo, dirs, commit) = key(loc);
+	let q = match hcl {
+		Some(hcl) => sqlx::query(
+			"INSERT INTO anda_cache(repo,dirs,commit,hcl,etag,last_modified,fetched)
+			VALUES ($1,$2,$3,$4,$5,$6,$7) ON CONFLICT (repo,dirs,commit) DO UPDATE SET
+			hcl=$4,etag=$5,last_modified=$6,fetched=$7",
+		)
+		.bind(repo)
+		.bind(dirs)
+		.bind(commit)
+		.bind(hcl),
+		None => sqlx::query(
+			"UPDATE anda_cache SET etag=$4,last_modified=$5,fetched=$6
+			WHERE (repo,dirs,commit)=($1,$2,$3)",
+		)
+		.bind(repo)
+		.bind(dirs)
+		.bind(commit),
+	};
+	q.bind(&r.etag).bind(&r.last_modified).bind(r.fetched).execute(db).await?;
+	Ok(())
+}
+
+/// Download `anda.hcl`, revalidating `stale` if given.
+///
+/// Returns the new entry and its text, or no text if `stale` is still valid.
+async fn download(
+	loc: &Location, stale: Option<&Resolved>,
+) -> Result<(Resolved, Option<String>), Error> {
+	let mut req = CLIENT.get(loc.raw("anda.hcl"));
+	if let Some(etag) = stale.and_then(|s| s.etag.as_deref()) {
+		req = req.header(IF_NONE_MATCH, etag);
+	}
+	if let Some(lm) = stale.and_then(|s| s.last_modified.as_deref()) {
+		req = req.header(IF_MODIFIED_SINCE, lm);
+	}
+	let res = req.send().await?;
+	let fetched = chrono::Utc::now().naive_utc();
+	let header = |name| res.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+	let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+	if let (StatusCode::NOT_MODIFIED, Some(stale)) = (res.status(), stale) {
+		let project = stale.project.clone();
+		let etag = etag.or_else(|| stale.etag.clone());
+		let last_modified = last_modified.or_else(|| stale.last_modified.clone());
+		return Ok((Resolved { project, etag, last_modified, fetched }, None));
+	}
+	let hcl = res.error_for_status()?.text().await?;
+	let project = parse(&hcl)?;
+	Ok((Resolved { project, etag, last_modified, fetched }, Some(hcl)))
+}
+
+/// The `anda.hcl` of the recipe at `loc` if it is already cached, fresh or not, without fetching.
+pub async fn stored(
+	db: &mut PgConnection, settings: &config::Anda, loc: &Location,
+) -> sqlx::Result<Option<Arc<Resolved>>> {
+	if let Some(r) = cached(settings, &key(loc)) {
+		return Ok(Some(r));
+	}
+	Ok(load(db, loc).await?.map(Arc::new))
+}
+
+/// The `anda.hcl` of the recipe at `loc`.
+pub async fn resolve(
+	db: &mut PgConnection, settings: &config::Anda, loc: &Location,
+) -> Result<Arc<Resolved>, Error> {
+	let k = key(loc);
+	let stale = match cached(settings, &k) {
+		Some(r) if r.is_fresh(settings, loc) => return Ok(r),
+		Some(r) => Some(r),
+		None => match load(db, loc).await? {
+			Some(r) if r.is_fresh(settings, loc) => {
+				let r = Arc::new(r);
+				cache(settings, k, &r);
+				return Ok(r);
+			},
+			r => r.map(Arc::new),
+		},
+	};
+	let r = match download(loc, stale.as_deref()).await {
+		Ok((r, hcl)) => {
+			if let Err(err) = store(db, loc, hcl.as_deref(), &r).await {
+				error!(?err, ?loc, "Cannot cache anda.hcl");
+			}
+			Arc::new(r)
+		},
+		Err(err) => {
+			let Some(stale) = stale else { return Err(err) };
+			warn!(%err, ?loc, "Serving stale anda.hcl");
+			stale
+		},
+	};
+	cache(settings, k, &r);
+	Ok(r)
+}
+
+/// The spec file of the recipe at `loc` as `(path, content)`, or `None` if it has no spec.
+///
+/// Specs are cached in `spec_cache` when pinned to a commit.
+pub async fn spec(
+	db: &mut PgConnection, settings: &config::Anda, loc: &Location,
+) -> Result<Option<(String, String)>, Error> {
+	let Some(path) = resolve(db, settings, loc).await?.spec() else { return Ok(None) };
+	let (repo, dirs, commit) = key(loc);
+	if loc.commit.is_some() {
+		let q = sqlx::query_scalar(
+			"SELECT content FROM spec_cache WHERE (repo,dirs,commit,path)=($1,$2,$3,$4)",
+		);
+		let q = q.bind(&repo).bind(&dirs).bind(&commit).bind(&path);
+		if let Some(content) = q.fetch_optional(&mut *db).await? {
+			return Ok(Some((path, content)));
+		}
+	}
+	let res = CLIENT.get(loc.raw(&path)).send().await?.error_for_status()?;
+	let content = res.text().await?;
+	if loc.commit.is_some() {
+		sqlx::query(
+			"INSERT INTO spec_cache(repo,dirs,commit,path,content) VALUES ($1,$2,$3,$4,$5)
+			ON CONFLICT DO NOTHING",
+		)
+		.bind(repo)
+		.bind(dirs)
+		.bind(commit)
+		.bind(&path)
+		.bind(&content)
+		.execute(db)
+		.await?;
+	}
+	Ok(Some((path, content)))
+}
+
+/// Resolve the recipe of a freshly built package in the background so that it is cached
+/// before anyone asks for it.
+pub fn prefetch(pool: PgPool, settings: config::Anda, repo: String, dirs: String, commit: String) {
+	rocket::tokio::spawn(async move {
+		let mut db = match pool.acquire().await {
+			Ok(db) => db,
+			Err(err) => return error!(?err, "Cannot acquire connection for anda.hcl prefetch"),
+		};
+		let forge = match forge::fetch(&mut db, &repo).await {
+			Ok(Some(forge)) => forge,
+			Ok(None) => return error!(repo, "Cannot find repo for anda.hcl prefetch"),
+			Err(err) => return error!(?err, repo, "Cannot find repo for anda.hcl prefetch"),
+		};
+		let loc = Location::new(forge, &repo, &dirs, Some(&commit));
+		if let Err(err) = resolve(&mut db, &settings, &loc).await {
+			warn!(%err, ?loc, "Cannot prefetch anda.hcl");
+		}
+	});
+}
+
+/// Remove the cached recipes of `repo`.
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	for table in ["anda_cache", "spec_cache"] {
+		let q = format!("DELETE FROM {table} WHERE repo=$1");
+		sqlx::query(&q).bind(repo).execute(&mut *db).await?;
+	}
+	if let Some(Ok(mut cache)) = CACHE.get().map(Mutex::lock) {
+		cache.clear();
+	}
+	Ok(())
+}
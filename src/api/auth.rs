@@ -11,17 +11,12 @@
 /// You should have received a copy of the GNU General Public License along with Madoguchi.
 /// If not, see <https://www.gnu.org/licenses/>.
 ///
-use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use crate::config::Config;
 use jwt_simple::prelude::*;
 use rocket::{
 	http::Status,
 	request::{self, FromRequest},
 };
-use std::sync::LazyLock;
-
-pub static JWT_KEY: LazyLock<HS256Key> = LazyLock::new(|| {
-	HS256Key::from_bytes(&STANDARD_NO_PAD.decode(std::env::var("JWT_KEY").unwrap()).unwrap())
-});
 
 pub struct ApiAuth {
 	pub token: String,
@@ -44,9 +39,14 @@ impl std::error::Error for ApiError {}
 impl<'r> FromRequest<'r> for ApiAuth {
 	type Error = ApiError;
 	async fn from_request(req: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+		let Some(config) = req.rocket().state::<Config>() else {
+			tracing::error!("The configuration is not managed");
+			return request::Outcome::Error((Status::InternalServerError, ApiError::Nil));
+		};
 		for token in req.headers().get("Authorization").filter_map(|a| a.strip_prefix("Bearer ")) {
 			let options = VerificationOptions::default();
-			if let Ok(claims) = JWT_KEY.verify_token::<CustomClaims>(token, Some(options)) {
+			if let Ok(claims) = config.jwt_key.0.verify_token::<CustomClaims>(token, Some(options))
+			{
 				return if claims.custom.scopes.contains(&"admin".to_owned()) {
 					request::Outcome::Success(Self { token: token.to_owned() })
 				} else {
@@ -54,7 +54,7 @@ impl<'r> FromRequest<'r> for ApiAuth {
 				};
 			}
 		}
-		request::Outcome::Error((Status::Forbidden, ApiError::Nil))
+		request::Outcome::Error((Status::Unauthorized, ApiError::Nil))
 	}
 }
 
@@ -0,0 +1,112 @@
+/// This file is part of Madoguchi.
+///
+/// Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+/// the GNU General Public License as published by the Free Software Foundation, either
+/// version 3 of the License, or (at your option) any later version.
+///
+/// Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+/// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License along with Madoguchi.
+/// If not, see <https://www.gnu.org/licenses/>.
+///
+use crate::badge::{self, Badge, BLUE, GREEN, RED};
+use crate::db::{Build, Madoguchi as Mg};
+use crate::rpmver::rpmvercmp;
+use rocket::http::Status;
+use rocket::{get, routes, Route};
+use rocket_db_pools::Connection;
+use sqlx::PgConnection;
+use tracing::error;
+use utoipa::OpenApi;
+
+pub fn routes() -> Vec<Route> {
+	routes![pkg_badge, repo_badge]
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(pkg_badge, repo_badge))]
+pub struct Doc;
+
+async fn pkg(db: &mut PgConnection, repo: &str, name: &str) -> sqlx::Result<Option<String>> {
+	let q = sqlx::query_as::<_, Build>(
+		"SELECT * FROM builds WHERE (repo,pname)=($1,$2) ORDER BY epoch DESC LIMIT 1",
+	);
+	if let Some(b) = q.bind(repo).bind(name).fetch_optional(&mut *db).await? {
+		return Ok(Some(if b.succ {
+			badge::render(&badge::label(repo), &format!("{}-{}", b.pver, b.prel), BLUE)
+		} else {
+			badge::render(&badge::label(repo), "build failing", RED)
+		}));
+	}
+	// packages pushed without a build
+	let q =
+		sqlx::query_as::<_, (String, String)>("SELECT ver,rel FROM pkgs WHERE (repo,name)=($1,$2)");
+	let pkgs = q.bind(repo).bind(name).fetch_all(db).await?;
+	let pkg = pkgs
+		.into_iter()
+		.max_by(|(v1, r1), (v2, r2)| rpmvercmp(v1, v2).then_with(|| rpmvercmp(r1, r2)));
+	Ok(pkg.map(|(ver, rel)| badge::render(&badge::label(repo), &format!("{ver}-{rel}"), BLUE)))
+}
+
+/// The version of a package, or whether its last build failed; `file` is `<name>.svg`.
+#[utoipa::path(
+	tag = "badges",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "image/svg+xml"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/<file>")]
+async fn pkg_badge(mut db: Connection<Mg>, repo: String, file: String) -> Result<Badge, Status> {
+	let name = file.strip_suffix(".svg").ok_or(Status::NotFound)?;
+	match pkg(&mut db, &repo, name).await {
+		Ok(Some(svg)) => Ok(Badge(svg)),
+		Ok(None) => Err(Status::NotFound),
+		Err(err) => {
+			error!(?err, repo, name, "Cannot render badge");
+			Err(Status::InternalServerError)
+		},
+	}
+}
+
+async fn repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<Option<String>> {
+	let q = sqlx::query_scalar::<_, String>("SELECT name FROM repos WHERE name=$1");
+	if q.bind(repo).fetch_optional(&mut *db).await?.is_none() {
+		return Ok(None);
+	}
+	let failing: i64 = sqlx::query_scalar(
+		"SELECT COUNT(*) FROM (SELECT DISTINCT ON (pname) succ FROM builds WHERE repo=$1
+		ORDER BY pname, epoch DESC) b WHERE NOT succ",
+	)
+	.bind(repo)
+	.fetch_one(db)
+	.await?;
+	let label = badge::label(repo);
+	Ok(Some(match failing {
+		0 => badge::render(&label, "all passing", GREEN),
+		n => badge::render(&label, &format!("{n} failing"), RED),
+	}))
+}
+
+/// The number of failing packages of a repo; `file` is `<repo>.svg`.
+#[utoipa::path(
+	tag = "badges",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "image/svg+xml"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<file>")]
+async fn repo_badge(mut db: Connection<Mg>, file: String) -> Result<Badge, Status> {
+	let name = file.strip_suffix(".svg").ok_or(Status::NotFound)?;
+	match repo(&mut db, name).await {
+		Ok(Some(svg)) => Ok(Badge(svg)),
+		Ok(None) => Err(Status::NotFound),
+		Err(err) => {
+			error!(?err, repo = name, "Cannot render badge");
+			Err(Status::InternalServerError)
+		},
+	}
+}
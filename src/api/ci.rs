@@ -13,17 +13,17 @@
 ///
 use super::auth::ApiAuth;
 use crate::db::Madoguchi as Mg;
+use crate::events::{self, Kind};
+use crate::forge;
+use crate::metrics;
+use crate::notify::{send_webhook, Webhook};
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::{put, routes, Route};
+use rocket::{put, routes, Route, State};
 use rocket_db_pools::Connection;
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::types::chrono;
-use std::sync::LazyLock;
-
-static WEBHOOKER: LazyLock<webhook::client::WebhookClient> = LazyLock::new(|| {
-	webhook::client::WebhookClient::new(&std::env::var("DISCORD_WEBHOOK").unwrap())
-});
 
 pub fn routes() -> Vec<Route> {
 	routes![add_build]
@@ -41,11 +41,11 @@ struct AddBuildBody {
 
 #[put("/<repo>/builds/<name>", data = "<build_body>")]
 async fn add_build(
-	mut db: Connection<Mg>, repo: String, name: String, build_body: Json<AddBuildBody>,
-	_auth: ApiAuth,
+	mut db: Connection<Mg>, webhook: &State<Webhook>, repo: String, name: String,
+	build_body: Json<AddBuildBody>, _auth: ApiAuth,
 ) -> Status {
 	if !build_body.succ {
-		return add_failed_build(db, repo, build_body).await;
+		return add_failed_build(db, webhook, repo, build_body).await;
 	}
 	if sqlx::query!(
 		"SELECT name FROM pkgs WHERE name=$1 AND repo=$2 AND arch=$3",
@@ -72,6 +72,9 @@ async fn add_build(
 			tracing::error!(?build_body, repo, name, ?err, "Cannot add pkgs");
 			return Status::InternalServerError;
 		}
+		let b = &build_body;
+		let data = json!({ "ver": b.ver, "rel": b.rel, "arch": b.arch });
+		events::publish(&mut db, Kind::PackageAdded, &repo, Some(&name), data).await;
 	} else if build_body.succ {
 		// don't want to update if it doesn't even build
 		if let Err(err) = sqlx::query!(
@@ -90,6 +93,7 @@ async fn add_build(
 			return Status::InternalServerError;
 		}
 	}
+	let fixed = events::failing(&mut db, &repo, &name, &build_body.arch).await;
 	let ep = chrono::Utc::now().naive_utc();
 	let q = sqlx::query_as!(
 		Build,
@@ -104,7 +108,14 @@ async fn add_build(
 		build_body.succ,
 	);
 	match q.execute(&mut **db).await {
-		Ok(_) => Status::Created,
+		Ok(_) => {
+			let b = &build_body;
+			let kind = if fixed { Kind::BuildFixed } else { Kind::BuildFinished };
+			let data = json!({ "id": b.id, "ver": b.ver, "rel": b.rel, "arch": b.arch });
+			events::publish(&mut db, kind, &repo, Some(&name), data).await;
+			metrics::build(&repo, &build_body.arch, true);
+			Status::Created
+		},
 		Err(e) => {
 			eprintln!("{e:?}");
 			Status::InternalServerError
@@ -112,36 +123,39 @@ async fn add_build(
 	}
 }
 
-async fn add_failed_build(mut db: Connection<Mg>, r: String, b: Json<AddBuildBody>) -> Status {
+async fn add_failed_build(
+	mut db: Connection<Mg>, webhook: &Webhook, r: String, b: Json<AddBuildBody>,
+) -> Status {
 	let q = sqlx::query!("SELECT name FROM pkgs WHERE (dirs,repo)=($1,$2)", b.dirs, &r);
 	let names: Vec<String> = match q.fetch_all(&mut **db).await {
 		Ok(r) => r.into_iter().map(|r| r.name).collect(),
 		Err(_) => return Status::NotFound,
 	};
 	let ep = chrono::Utc::now().naive_utc();
+	let mut stored = false;
 	for name in names {
 		let q = sqlx::query!("INSERT INTO builds(pname,pver,prel,parch,id,repo,epoch,succ) VALUES ($1,$2,$3,$4,$5,$6,$7,false)",name,b.ver,b.rel,b.arch,b.id,r,ep);
 		if let Err(e) = q.execute(&mut **db).await {
 			tracing::error!(err=?e, "Ignoring error during insertion of failed build");
+			continue;
 		}
+		stored = true;
+		let data = json!({ "id": b.id, "ver": b.ver, "rel": b.rel, "arch": b.arch });
+		events::publish(&mut db, Kind::BuildFailed, &r, Some(&name), data).await;
+	}
+	if stored {
+		metrics::build(&r, &b.arch, false);
 	}
-	let (arch, dir, bid) = (&b.arch, &b.dirs, &b.id);
+	let (arch, dir) = (&b.arch, &b.dirs);
+	let run = forge::fetch_logged(&mut db, &r).await;
+	let run = run.map(|f| format!("\n⇒ [{}]({})", f.kind.ci_name(), f.run(&b.id)));
+	let run = run.unwrap_or_default();
 
-	send_webhook(format!(
+	let msg = format!(
 		"
-<:incorrect:1176633989864362094> Build Failing on **{r}**: **{dir}** (*{arch}*)
-⇒ [gha](https://github.com/terrapkg/packages/actions/runs/{bid}/)",
-	))
-	.await;
+<:incorrect:1176633989864362094> Build Failing on **{r}**: **{dir}** (*{arch}*){run}",
+	);
+	send_webhook(webhook, msg).await;
 
 	Status::NoContent
 }
-
-async fn send_webhook(s: String) {
-	let msg = WEBHOOKER.send(|msg| {
-		msg.username("Terra Webhook (mg)")
-			.avatar_url("https://avatars.githubusercontent.com/u/114906088")
-			.content(&s)
-	});
-	_ = msg.await;
-}
@@ -12,52 +12,79 @@
 /// If not, see <https://www.gnu.org/licenses/>.
 ///
 use super::auth::ApiAuth;
-use crate::db::Madoguchi as Mg;
+use super::problem::Problem;
+use crate::config::Config;
+use crate::db::{Madoguchi as Mg, PkgMeta};
+use crate::events::{self, Kind};
+use crate::forge;
+use crate::metrics;
+use crate::notify::{send_webhook, Webhook};
+use crate::{anda, updateinfo};
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::{put, routes, Route};
+use rocket::{post, put, routes, Route, State};
 use rocket_db_pools::Connection;
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::types::chrono;
-use std::sync::LazyLock;
-
-static WEBHOOKER: LazyLock<webhook::client::WebhookClient> = LazyLock::new(|| {
-	webhook::client::WebhookClient::new(&std::env::var("DISCORD_WEBHOOK").unwrap())
-});
+use utoipa::OpenApi;
 
 pub fn routes() -> Vec<Route> {
-	routes![add_build]
+	routes![add_build, start_build]
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(OpenApi)]
+#[openapi(paths(add_build, start_build))]
+pub struct Doc;
+
+#[derive(Deserialize, Debug, utoipa::ToSchema)]
 struct AddBuildBody<'a> {
+	/// ID of the CI run
 	id: &'a str,
+	/// Epoch of the package, if it has one
+	#[serde(default)]
+	epoch: Option<&'a str>,
 	ver: &'a str,
 	rel: &'a str,
 	arch: &'a str,
+	/// Directory of the recipe in the repository
 	dirs: &'a str,
 	succ: bool,
 	commit: &'a str,
+	#[serde(default)]
+	meta: Option<PkgMeta>,
 }
 
+/// Record a build of a package; a failed build is recorded for every package of `dirs`.
+#[utoipa::path(
+	tag = "ci",
+	request_body = AddBuildBody,
+	responses(
+		(status = 201, description = "Recorded"),
+		(status = 204, description = "Recorded failure"),
+		(status = 404, description = "No such repo"),
+	),
+	security(("token" = [])),
+)]
 #[put("/<repo>/builds/<name>", data = "<build_body>")]
+#[allow(clippy::too_many_arguments)]
 async fn add_build(
-	mut db: Connection<Mg>, repo: String, name: String, build_body: Json<AddBuildBody<'_>>,
-	_auth: ApiAuth,
-) -> Status {
+	mut db: Connection<Mg>, mg: &State<Mg>, config: &State<Config>, webhook: &State<Webhook>,
+	repo: String, name: String, build_body: Json<AddBuildBody<'_>>, _auth: ApiAuth,
+) -> Result<Status, Problem> {
 	if !build_body.succ {
-		return add_failed_build(db, repo, build_body).await;
+		return add_failed_build(db, webhook, repo, build_body).await;
 	}
-	if sqlx::query!(
-		"SELECT name FROM pkgs WHERE name=$1 AND repo=$2 AND arch=$3",
-		name,
-		repo,
-		build_body.arch
+	let old = sqlx::query_as::<_, (String, String)>(
+		"SELECT ver,rel FROM pkgs WHERE name=$1 AND repo=$2 AND arch=$3",
 	)
+	.bind(&name)
+	.bind(&repo)
+	.bind(build_body.arch)
 	.fetch_one(&mut **db)
 	.await
-	.is_err()
-	{
+	.ok();
+	if old.is_none() {
 		if let Err(err) = sqlx::query!(
 			"INSERT INTO pkgs(name, repo, ver, rel, arch, dirs) VALUES ($1,$2,$3,$4,$5,$6)",
 			name,
@@ -70,9 +97,18 @@ async fn add_build(
 		.execute(&mut **db)
 		.await
 		{
+			if err
+				.as_database_error()
+				.is_some_and(sqlx::error::DatabaseError::is_foreign_key_violation)
+			{
+				return Err(Problem::not_found(format!("No repo named {repo}")));
+			}
 			tracing::error!(?build_body, repo, name, ?err, "Cannot add pkgs");
-			return Status::InternalServerError;
+			return Err(Problem::internal());
 		}
+		let (ver, rel, arch) = (build_body.ver, build_body.rel, build_body.arch);
+		let data = json!({ "ver": ver, "rel": rel, "arch": arch });
+		events::publish(&mut db, Kind::PackageAdded, &repo, Some(&name), data).await;
 	} else if build_body.succ {
 		// don't want to update if it doesn't even build
 		if let Err(err) = sqlx::query!(
@@ -87,9 +123,22 @@ async fn add_build(
 		.await
 		{
 			tracing::error!(?build_body, repo, name, ?err, "Cannot update pkgs");
-			return Status::InternalServerError;
+			return Err(Problem::internal());
+		}
+	}
+	let (ver, rel, arch) = (build_body.ver, build_body.rel, build_body.arch);
+	let old = old.as_ref().map(|(v, r)| (v.as_str(), r.as_str()));
+	let built = updateinfo::Built { name: &name, epoch: build_body.epoch, ver, rel, arch };
+	if let Err(err) = updateinfo::record_build(&mut db, &repo, old, &built).await {
+		tracing::error!(?build_body, repo, name, ?err, "Cannot record advisory");
+	}
+	if let Some(meta) = &build_body.meta {
+		if let Err(err) = meta.store(&mut db, &repo, &name).await {
+			tracing::error!(?build_body, repo, name, ?err, "Cannot store pkg meta");
+			return Err(Problem::internal());
 		}
 	}
+	let fixed = events::failing(&mut db, &repo, &name, build_body.arch).await;
 	let ep = chrono::Utc::now().naive_utc();
 	let q = sqlx::query_as!(
 		Build,
@@ -105,44 +154,104 @@ async fn add_build(
 		build_body.commit,
 	);
 	match q.execute(&mut **db).await {
-		Ok(_) => Status::Created,
+		Ok(_) => {
+			let (dirs, commit) = (build_body.dirs.trim_matches('/'), build_body.commit);
+			let kind = if fixed { Kind::BuildFixed } else { Kind::BuildFinished };
+			let data = json!({ "id": build_body.id, "ver": ver, "rel": rel, "arch": arch, "commit": commit });
+			events::publish(&mut db, kind, &repo, Some(&name), data).await;
+			metrics::build(&repo, build_body.arch, true);
+			let (pool, settings) = ((***mg).clone(), config.anda.clone());
+			anda::prefetch(pool, settings, repo, dirs.to_owned(), commit.to_owned());
+			Ok(Status::Created)
+		},
 		Err(e) => {
 			eprintln!("{e:?}");
-			Status::InternalServerError
+			Err(Problem::internal())
 		},
 	}
 }
 
-async fn add_failed_build(mut db: Connection<Mg>, r: String, b: Json<AddBuildBody<'_>>) -> Status {
+async fn add_failed_build(
+	mut db: Connection<Mg>, webhook: &Webhook, r: String, b: Json<AddBuildBody<'_>>,
+) -> Result<Status, Problem> {
 	let q = sqlx::query!("SELECT name FROM pkgs WHERE (dirs,repo)=($1,$2)", b.dirs, &r);
 	let names: Vec<String> = match q.fetch_all(&mut **db).await {
 		Ok(r) => r.into_iter().map(|r| r.name).collect(),
-		Err(_) => return Status::NotFound,
+		Err(err) => {
+			tracing::error!(?b, r, ?err, "Cannot find pkgs of build");
+			return Err(Problem::internal());
+		},
 	};
 	let ep = chrono::Utc::now().naive_utc();
+	let mut stored = false;
 	for name in names {
 		let q = sqlx::query!("INSERT INTO builds(pname,pver,prel,parch,id,repo,epoch,commit,succ) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,false)",name,b.ver,b.rel,b.arch,b.id,r,ep,b.commit);
 		if let Err(e) = q.execute(&mut **db).await {
 			tracing::error!(err=?e, "Ignoring error during insertion of failed build");
+			continue;
 		}
+		stored = true;
+		let data =
+			json!({ "id": b.id, "ver": b.ver, "rel": b.rel, "arch": b.arch, "commit": b.commit });
+		events::publish(&mut db, Kind::BuildFailed, &r, Some(&name), data).await;
 	}
-	let (arch, dir, bid) = (&b.arch, &b.dirs, &b.id);
+	if stored {
+		metrics::build(&r, b.arch, false);
+	}
+	let (arch, dir) = (&b.arch, &b.dirs);
+	let run = forge::fetch_logged(&mut db, &r).await.map(|f| {
+		let (ci, commit) = (f.kind.ci_name(), b.commit.get(..7).unwrap_or(b.commit));
+		format!("\n⇒ [{ci}]({}) @ [{commit}]({})", f.run(b.id), f.commit(b.commit))
+	});
+	let run = run.unwrap_or_default();
 
-	send_webhook(format!(
+	let msg = format!(
 		"
-<:incorrect:1176633989864362094> Build Failing on **{r}**: **{dir}** (*{arch}*)
-⇒ [gha](https://github.com/terrapkg/packages/actions/runs/{bid}/)",
-	))
-	.await;
+<:incorrect:1176633989864362094> Build Failing on **{r}**: **{dir}** (*{arch}*){run}",
+	);
+	send_webhook(webhook, msg).await;
 
-	Status::NoContent
+	Ok(Status::NoContent)
 }
 
-async fn send_webhook(s: String) {
-	let msg = WEBHOOKER.send(|msg| {
-		msg.username("Terra Webhook (mg)")
-			.avatar_url("https://avatars.githubusercontent.com/u/114906088")
-			.content(&s)
-	});
-	_ = msg.await;
+#[derive(Deserialize, Debug, utoipa::ToSchema)]
+struct StartBuildBody<'a> {
+	id: &'a str,
+	arch: &'a str,
+	dirs: &'a str,
+	commit: &'a str,
+}
+
+/// Announce a build of `dirs`, for the event stream only.
+#[utoipa::path(
+	tag = "ci",
+	request_body = StartBuildBody,
+	responses(
+		(status = 204, description = "Announced"),
+	),
+	security(("token" = [])),
+)]
+#[post("/<repo>/started", data = "<b>")]
+async fn start_build(
+	mut db: Connection<Mg>, repo: String, b: Json<StartBuildBody<'_>>, _auth: ApiAuth,
+) -> Result<Status, Problem> {
+	let dirs = b.dirs.trim_matches('/');
+	let q =
+		sqlx::query_scalar::<_, String>("SELECT DISTINCT name FROM pkgs WHERE (dirs,repo)=($1,$2)");
+	let names = match q.bind(dirs).bind(&repo).fetch_all(&mut **db).await {
+		Ok(names) => names,
+		Err(err) => {
+			tracing::error!(?b, repo, ?err, "Cannot find pkgs of build");
+			return Err(Problem::internal());
+		},
+	};
+	let data = json!({ "id": b.id, "arch": b.arch, "dirs": dirs, "commit": b.commit });
+	if names.is_empty() {
+		events::publish(&mut db, Kind::BuildStarted, &repo, None, data).await;
+		return Ok(Status::NoContent);
+	}
+	for name in names {
+		events::publish(&mut db, Kind::BuildStarted, &repo, Some(&name), data.clone()).await;
+	}
+	Ok(Status::NoContent)
 }
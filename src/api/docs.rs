@@ -0,0 +1,146 @@
+/// This file is part of Madoguchi.
+///
+/// Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+/// the GNU General Public License as published by the Free Software Foundation, either
+/// version 3 of the License, or (at your option) any later version.
+///
+/// Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+/// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License along with Madoguchi.
+/// If not, see <https://www.gnu.org/licenses/>.
+///
+// The OpenAPI document of the API, generated from the routes and their types, and a page to
+// browse it. `openapi.json` at the root of the repository is a snapshot kept in sync by the tests.
+use rocket::serde::json::Json;
+use rocket::{get, routes, Route};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{Content, Ref, RefOr, Response};
+use utoipa::{Modify, OpenApi};
+use utoipa_scalar::{Scalar, Servable};
+
+struct Token;
+
+impl Modify for Token {
+	fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+		let scheme = HttpBuilder::new()
+			.scheme(HttpAuthScheme::Bearer)
+			.bearer_format("JWT")
+			.description(Some("A token with the `admin` scope"))
+			.build();
+		let components = openapi.components.get_or_insert_with(Default::default);
+		components.add_security_scheme("token", SecurityScheme::Http(scheme));
+	}
+}
+
+/// Errors are [`Problem`](super::problem::Problem)s, and the operations needing a token can
+/// also fail with 401 and 403.
+struct Problems;
+
+impl Modify for Problems {
+	fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+		for item in openapi.paths.paths.values_mut() {
+			let ops = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete];
+			for op in ops.into_iter().flatten() {
+				let responses = &mut op.responses.responses;
+				if op.security.is_some() {
+					for (code, description) in [
+						("401", "Missing or invalid token"),
+						("403", "The token does not have the admin scope"),
+					] {
+						responses
+							.entry(code.into())
+							.or_insert_with(|| Response::new(description).into());
+					}
+				}
+				for (code, response) in responses.iter_mut() {
+					let RefOr::T(response) = response else { continue };
+					if code.as_str() >= "400" && response.content.is_empty() {
+						let problem = Content::new(Some(Ref::from_schema_name("Problem")));
+						response.content.insert("application/problem+json".into(), problem);
+					}
+				}
+			}
+		}
+	}
+}
+
+#[derive(OpenApi)]
+#[openapi(
+	info(title = "madoguchi", version = "4", description = "Packages, builds and repos of Terra."),
+	nest(
+		(path = "/v4", api = super::v4::Doc),
+		(path = "/ci5", api = super::ci5::Doc),
+		(path = "/redirect", api = super::repology::Doc),
+		(path = "/badge", api = super::badge::Doc),
+		(path = "/feeds", api = super::feeds::Doc),
+	),
+	components(schemas(super::problem::Problem)),
+	modifiers(&Token, &Problems),
+)]
+pub struct ApiDoc;
+
+pub fn routes() -> Vec<Route> {
+	let mut routes = routes![openapi];
+	routes.extend(Vec::<Route>::from(Scalar::with_url("/docs", ApiDoc::openapi())));
+	routes
+}
+
+#[get("/openapi.json")]
+fn openapi() -> Json<utoipa::openapi::OpenApi> {
+	Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::BTreeSet;
+
+	const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+	/// `/<repo>/packages/<name>` to `/{repo}/packages/{name}`
+	fn openapi_path(base: &str, route: &Route) -> String {
+		let segments = route.uri.path().split('/').map(|s| {
+			let param = s.strip_prefix('<').and_then(|s| s.strip_suffix('>'));
+			param.map_or_else(|| s.to_owned(), |p| format!("{{{}}}", p.trim_end_matches("..")))
+		});
+		format!("{base}{}", segments.collect::<Vec<_>>().join("/"))
+	}
+
+	#[test]
+	fn every_route_is_documented() {
+		let mounts = [
+			("/v4", crate::api::v4::routes()),
+			("/ci5", crate::api::ci5::routes()),
+			("/redirect", crate::api::repology::routes()),
+			("/badge", crate::api::badge::routes()),
+			("/feeds", crate::api::feeds::routes()),
+		];
+		let routes: BTreeSet<_> = (mounts.iter())
+			.flat_map(|(base, routes)| routes.iter().map(|r| (openapi_path(base, r), r.method)))
+			.map(|(path, method)| (path, method.as_str().to_lowercase()))
+			.collect();
+		let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+		let documented: BTreeSet<_> = (spec["paths"].as_object().unwrap().iter())
+			.flat_map(|(path, ops)| {
+				ops.as_object().unwrap().keys().map(|m| (path.clone(), m.clone()))
+			})
+			.collect();
+		assert_eq!(routes, documented);
+	}
+
+	#[test]
+	fn snapshot_is_current() {
+		let spec = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+		if std::env::var("UPDATE_OPENAPI").is_ok() {
+			std::fs::write(SNAPSHOT, &spec).unwrap();
+			return;
+		}
+		let snapshot = std::fs::read_to_string(SNAPSHOT).unwrap_or_default();
+		assert!(
+			snapshot == spec,
+			"openapi.json is outdated, update it with `UPDATE_OPENAPI=1 cargo test snapshot`"
+		);
+	}
+}
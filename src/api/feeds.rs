@@ -0,0 +1,144 @@
+/// This file is part of Madoguchi.
+///
+/// Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+/// the GNU General Public License as published by the Free Software Foundation, either
+/// version 3 of the License, or (at your option) any later version.
+///
+/// Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+/// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License along with Madoguchi.
+/// If not, see <https://www.gnu.org/licenses/>.
+///
+use crate::config::Config;
+use crate::db::Madoguchi as Mg;
+use crate::feeds::{self, Entry, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{get, routes, Route};
+use rocket_db_pools::Connection;
+use sqlx::PgConnection;
+use tracing::error;
+use utoipa::OpenApi;
+
+pub fn routes() -> Vec<Route> {
+	routes![updates, new, failures, package]
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(updates, new, failures, package))]
+pub struct Doc;
+
+/// The public URL of madoguchi, `public_url` if configured. Without it, links are relative to
+/// the root: the `Host` header is chosen by the client and never trusted for links.
+pub struct BaseUrl(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BaseUrl {
+	type Error = std::convert::Infallible;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let public = request.rocket().state::<Config>().and_then(|c| c.public_url.clone());
+		Outcome::Success(Self(public.unwrap_or_default()))
+	}
+}
+
+type Atom = (ContentType, String);
+
+async fn exists(db: &mut PgConnection, repo: &str) -> Result<(), Status> {
+	let q = sqlx::query_scalar::<_, String>("SELECT name FROM repos WHERE name=$1");
+	match q.bind(repo).fetch_optional(db).await {
+		Ok(Some(_)) => Ok(()),
+		Ok(None) => Err(Status::NotFound),
+		Err(err) => {
+			error!(?err, repo, "Cannot find repo");
+			Err(Status::InternalServerError)
+		},
+	}
+}
+
+fn atom(
+	id: &str, title: &str, base: &BaseUrl, path: &str, entries: sqlx::Result<Vec<Entry>>,
+) -> Result<Atom, Status> {
+	let entries = entries.map_err(|err| {
+		error!(?err, id, "Cannot list feed entries");
+		Status::InternalServerError
+	})?;
+	match feeds::render(id, title, &base.0, path, &entries) {
+		Ok(xml) => Ok((ContentType::new("application", "atom+xml"), xml)),
+		Err(err) => {
+			error!(?err, id, "Cannot render feed");
+			Err(Status::InternalServerError)
+		},
+	}
+}
+
+async fn repo_feed(
+	db: &mut PgConnection, repo: &str, kind: Kind, title: &str, base: &BaseUrl,
+) -> Result<Atom, Status> {
+	exists(&mut *db, repo).await?;
+	let entries = feeds::entries(db, repo, kind).await;
+	let id = format!("urn:madoguchi:{repo}:{}", kind.as_str());
+	let path = format!("/feeds/{repo}/{}.atom", kind.as_str());
+	atom(&id, &format!("{title} in {repo}"), base, &path, entries)
+}
+
+/// Updated packages of a repo.
+#[utoipa::path(
+	tag = "feeds",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "application/atom+xml"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/updates.atom")]
+async fn updates(mut db: Connection<Mg>, repo: String, base: BaseUrl) -> Result<Atom, Status> {
+	repo_feed(&mut db, &repo, Kind::Updates, "Package updates", &base).await
+}
+
+/// New packages of a repo.
+#[utoipa::path(
+	tag = "feeds",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "application/atom+xml"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/new.atom")]
+async fn new(mut db: Connection<Mg>, repo: String, base: BaseUrl) -> Result<Atom, Status> {
+	repo_feed(&mut db, &repo, Kind::New, "New packages", &base).await
+}
+
+/// Build failures of a repo.
+#[utoipa::path(
+	tag = "feeds",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "application/atom+xml"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/failures.atom")]
+async fn failures(mut db: Connection<Mg>, repo: String, base: BaseUrl) -> Result<Atom, Status> {
+	repo_feed(&mut db, &repo, Kind::Failures, "Build failures", &base).await
+}
+
+/// Versions and build failures of a package; `file` is `<name>.atom`.
+#[utoipa::path(
+	tag = "feeds",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "application/atom+xml"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<file>")]
+async fn package(
+	mut db: Connection<Mg>, repo: String, file: String, base: BaseUrl,
+) -> Result<Atom, Status> {
+	let name = file.strip_suffix(".atom").ok_or(Status::NotFound)?;
+	exists(&mut db, &repo).await?;
+	let entries = feeds::package(&mut db, &repo, name).await;
+	let id = format!("urn:madoguchi:{repo}:{name}");
+	let path = format!("/feeds/{repo}/packages/{file}");
+	atom(&id, &format!("{name} in {repo}"), &base, &path, entries)
+}
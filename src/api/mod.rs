@@ -1,5 +1,10 @@
 pub mod auth;
+pub mod badge;
 pub mod ci;
 pub mod ci5;
+pub mod docs;
+pub mod feeds;
+pub mod problem;
 pub mod repology;
 pub mod v4;
+pub mod web;
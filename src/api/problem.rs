@@ -0,0 +1,197 @@
+/// This file is part of Madoguchi.
+///
+/// Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+/// the GNU General Public License as published by the Free Software Foundation, either
+/// version 3 of the License, or (at your option) any later version.
+///
+/// Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+/// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License along with Madoguchi.
+/// If not, see <https://www.gnu.org/licenses/>.
+///
+// Errors of the API, answered as `application/problem+json` (RFC 9457) bodies.
+use crate::request_id::RequestId;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::{catch, catchers, Catcher, Request};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Cursor;
+use tracing::error;
+
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+pub struct Problem {
+	/// HTTP status code
+	pub status: u16,
+	/// Kind of the error, e.g. `not_found`
+	pub code: &'static str,
+	/// Human-readable explanation
+	pub message: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub details: Option<Value>,
+	/// The `X-Request-Id` of the request, to find it in the logs
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub request_id: Option<String>,
+}
+
+const fn code(status: Status) -> &'static str {
+	match status.code {
+		400 => "bad_request",
+		401 => "unauthorized",
+		403 => "forbidden",
+		404 => "not_found",
+		409 => "conflict",
+		413 => "payload_too_large",
+		422 => "unprocessable_entity",
+		502 => "bad_gateway",
+		503 => "unavailable",
+		_ if status.code >= 500 => "internal",
+		_ => "error",
+	}
+}
+
+impl Problem {
+	pub fn new<M: Into<String>>(status: Status, message: M) -> Self {
+		let (code, message) = (code(status), message.into());
+		Self { status: status.code, code, message, details: None, request_id: None }
+	}
+
+	/// Invalid input: 400.
+	pub fn bad_request<M: Into<String>>(message: M) -> Self {
+		Self::new(Status::BadRequest, message)
+	}
+
+	/// Missing or invalid token: 401.
+	pub fn unauthorized<M: Into<String>>(message: M) -> Self {
+		Self::new(Status::Unauthorized, message)
+	}
+
+	/// Valid token without the required scope: 403.
+	pub fn forbidden<M: Into<String>>(message: M) -> Self {
+		Self::new(Status::Forbidden, message)
+	}
+
+	pub fn not_found<M: Into<String>>(message: M) -> Self {
+		Self::new(Status::NotFound, message)
+	}
+
+	/// The request contradicts the stored state: 409.
+	pub fn conflict<M: Into<String>>(message: M) -> Self {
+		Self::new(Status::Conflict, message)
+	}
+
+	/// A failure of an upstream server, e.g. a forge or the repository: 502.
+	pub fn bad_gateway<M: Into<String>>(message: M) -> Self {
+		Self::new(Status::BadGateway, message)
+	}
+
+	/// A failure on our side; the cause is logged, not shown.
+	pub fn internal() -> Self {
+		Self::new(Status::InternalServerError, "Internal server error")
+	}
+
+	#[must_use]
+	pub fn with_details(mut self, details: Value) -> Self {
+		self.details = Some(details);
+		self
+	}
+
+	pub fn status(&self) -> Status {
+		Status::from_code(self.status).unwrap_or(Status::InternalServerError)
+	}
+}
+
+impl From<Status> for Problem {
+	fn from(status: Status) -> Self {
+		Self::new(status, status.reason_lossy())
+	}
+}
+
+/// Missing rows are 404, unique and foreign key violations 409, anything else is logged and 500.
+impl From<sqlx::Error> for Problem {
+	fn from(err: sqlx::Error) -> Self {
+		if matches!(err, sqlx::Error::RowNotFound) {
+			return Self::not_found("Not found");
+		}
+		if let Some(db) = err.as_database_error() {
+			if db.is_unique_violation() {
+				return Self::conflict("Already exists")
+					.with_details(serde_json::json!({ "constraint": db.constraint() }));
+			}
+			if db.is_foreign_key_violation() {
+				return Self::conflict("Refers to something that does not exist")
+					.with_details(serde_json::json!({ "constraint": db.constraint() }));
+			}
+		}
+		error!(?err, "Database error");
+		Self::internal()
+	}
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+	fn respond_to(mut self, request: &'r Request<'_>) -> response::Result<'static> {
+		self.request_id = Some(RequestId::of(request).to_owned());
+		let body = serde_json::to_string(&self).map_err(|_| Status::InternalServerError)?;
+		Response::build()
+			.status(self.status())
+			.header(ContentType::new("application", "problem+json"))
+			.sized_body(body.len(), Cursor::new(body))
+			.ok()
+	}
+}
+
+#[catch(400)]
+fn bad_request() -> Problem {
+	Problem::bad_request("The request is malformed")
+}
+
+#[catch(401)]
+fn unauthorized() -> Problem {
+	Problem::unauthorized("Missing or invalid token")
+}
+
+#[catch(403)]
+fn forbidden() -> Problem {
+	Problem::forbidden("The token does not have the admin scope")
+}
+
+#[catch(404)]
+fn not_found(req: &Request) -> Problem {
+	Problem::not_found(format!("Nothing at {}", req.uri().path()))
+}
+
+#[catch(413)]
+fn payload_too_large() -> Problem {
+	Problem::new(Status::PayloadTooLarge, "The body is too large")
+}
+
+#[catch(422)]
+fn unprocessable_entity() -> Problem {
+	Problem::new(Status::UnprocessableEntity, "The body does not have the expected fields")
+}
+
+#[catch(500)]
+fn internal() -> Problem {
+	Problem::internal()
+}
+
+#[catch(default)]
+fn default(status: Status, _: &Request) -> Problem {
+	status.into()
+}
+
+/// Catchers answering every error of the API with a [`Problem`], instead of an HTML page.
+pub fn catchers() -> Vec<Catcher> {
+	catchers![
+		bad_request,
+		unauthorized,
+		forbidden,
+		not_found,
+		payload_too_large,
+		unprocessable_entity,
+		internal,
+		default
+	]
+}
@@ -11,74 +11,470 @@
 /// You should have received a copy of the GNU General Public License along with Madoguchi.
 /// If not, see <https://www.gnu.org/licenses/>.
 ///
+use crate::anda::{self, Location};
+use crate::config::{self, Config};
 use crate::db::Madoguchi as Mg;
+use crate::forge;
+use crate::mirrors::{self, Region};
+use crate::repodata::{self, parse_nevra};
+use crate::rpmver::rpmvercmp;
 use rocket::response::Redirect;
-use rocket::{get, routes, Route};
+use rocket::{get, routes, Route, State};
 use rocket_db_pools::Connection;
+use serde::Serialize;
+use sqlx::PgConnection;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use utoipa::OpenApi;
 
 pub fn routes() -> Vec<Route> {
-	routes![redirect_pkg, redirect_andahcl, redirect_andaspec, redirect_andaspecraw]
+	routes![
+		redirect_pkg,
+		redirect_andahcl,
+		redirect_andaspec,
+		redirect_andaspecraw,
+		redirect_ver,
+		redirect_ver_andahcl,
+		redirect_ver_andaspec,
+		redirect_ver_andaspecraw,
+		redirect_ver_build,
+		redirect_build,
+		redirect_build_commit,
+		redirect_rpm,
+		redirect_srpm
+	]
 }
 
-async fn rootdir(mut db: Connection<Mg>, repo: String, name: String) -> Option<String> {
-	let dirs = sqlx::query!(
-		"SELECT dirs FROM pkgs WHERE name = $1 AND repo = $2 ORDER BY ver DESC",
-		name,
-		repo
+#[derive(OpenApi)]
+#[openapi(paths(
+	redirect_pkg,
+	redirect_andahcl,
+	redirect_andaspec,
+	redirect_andaspecraw,
+	redirect_ver,
+	redirect_ver_andahcl,
+	redirect_ver_andaspec,
+	redirect_ver_andaspecraw,
+	redirect_ver_build,
+	redirect_build,
+	redirect_build_commit,
+	redirect_rpm,
+	redirect_srpm
+))]
+pub struct Doc;
+
+/// The spec file path of the recipe at `loc`.
+async fn spec(
+	db: &mut PgConnection, settings: &config::Anda, loc: Location,
+) -> Option<(Location, String)> {
+	match anda::resolve(db, settings, &loc).await {
+		Ok(r) => Some((loc, r.spec()?)),
+		Err(err) => {
+			tracing::error!(%err, ?loc, "No hcl found.");
+			None
+		},
+	}
+}
+
+/// A package in [Repology's JSON format](https://repology.org/docs/requirements).
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+#[schema(as = RepologyPackage)]
+pub struct Package {
+	pub name: String,
+	pub srcname: String,
+	pub binnames: Vec<String>,
+	pub version: String,
+	pub release: String,
+	pub summary: Option<String>,
+	pub licenses: Vec<String>,
+	pub maintainers: Vec<String>,
+	pub homepage: Option<String>,
+	pub categories: Vec<String>,
+	pub recipe: String,
+	/// The spec file, when the `anda.hcl` of the recipe is cached and names one
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub spec: Option<String>,
+}
+
+type FeedRow = (
+	String,
+	String,
+	String,
+	String,
+	Option<String>,
+	Option<String>,
+	Option<String>,
+	Option<String>,
+	Option<Vec<String>>,
+);
+
+/// All packages of `repo` for Repology, or `None` if the repo does not exist.
+///
+/// Recipes are never fetched here, the spec files come from the cached `anda.hcl`s only.
+pub async fn feed(
+	db: &mut PgConnection, settings: &config::Anda, repo: &str,
+) -> sqlx::Result<Option<Vec<Package>>> {
+	let Some(forge) = forge::fetch(&mut *db, repo).await? else { return Ok(None) };
+	let q = sqlx::query_as::<_, (String, String)>(
+		"SELECT DISTINCT ON (pname) pname,commit FROM builds
+		WHERE repo=$1 AND succ AND commit IS NOT NULL ORDER BY pname, epoch DESC",
 	);
-	let dirs = dirs.fetch_one(&mut **db).await.ok()?.dirs;
-	let link = sqlx::query!("SELECT gh FROM repos WHERE name = $1", repo);
-	let link = link.fetch_one(&mut **db).await.ok()?.gh;
-	let commit = sqlx::query!(
-		"SELECT commit FROM builds WHERE pname=$1 AND repo=$2 AND succ ORDER BY epoch DESC LIMIT 1",
-		name,
-		repo
+	let commits: HashMap<_, _> = q.bind(repo).fetch_all(&mut *db).await?.into_iter().collect();
+	let q = sqlx::query_as::<_, (String, String)>(
+		"SELECT name,subpkg FROM pkg_subpkgs WHERE repo=$1 ORDER BY subpkg",
 	);
-	let mut s = format!("{link}/{dirs}");
-	if let Some(commit) = commit.fetch_one(&mut **db).await.ok().and_then(|x| x.commit) {
-		s = format!(
-			"https://github.com/terrapkg/packages/tree/{commit}{}",
-			s.trim_start_matches("https://github.com/terrapkg/packages/tree/")
-				.trim_start_matches(|ch| ch != '/')
-		);
+	let mut binnames: HashMap<_, Vec<_>> = HashMap::new();
+	for (name, subpkg) in q.bind(repo).fetch_all(&mut *db).await? {
+		binnames.entry(name).or_default().push(subpkg);
 	}
-	tracing::trace!(s);
-	Some(s)
+	let rows = sqlx::query_as::<_, FeedRow>(
+		"SELECT p.name,p.ver,p.rel,p.dirs,m.summary,m.license,m.url,m.srpm,m.maintainers
+		FROM pkgs p LEFT JOIN pkg_meta m ON (m.name,m.repo)=(p.name,p.repo) WHERE p.repo=$1",
+	)
+	.bind(repo)
+	.fetch_all(&mut *db)
+	.await?;
+	let mut pkgs = Vec::new();
+	for (dirs, mut pkg) in packages(&forge, rows, &commits, binnames) {
+		let commit = commits.get(&pkg.name).map(String::as_str);
+		let loc = Location::new(forge.clone(), repo, &dirs, commit);
+		let recipe = anda::stored(db, settings, &loc).await?;
+		pkg.spec = recipe.and_then(|r| r.spec()).map(|spec| loc.tree(&spec));
+		pkgs.push(pkg);
+	}
+	Ok(Some(pkgs))
+}
+
+/// The newest version of each package in `rows`, compared with `rpmvercmp`, sorted by name,
+/// with the recipe directory of each.
+fn packages(
+	forge: &forge::Forge, rows: Vec<FeedRow>, commits: &HashMap<String, String>,
+	mut binnames: HashMap<String, Vec<String>>,
+) -> Vec<(String, Package)> {
+	let mut newest: BTreeMap<String, FeedRow> = BTreeMap::new();
+	for row in rows {
+		if let Some(cur) = newest.get(&row.0) {
+			let cmp = rpmvercmp(&row.1, &cur.1).then_with(|| rpmvercmp(&row.2, &cur.2));
+			if cmp != Ordering::Greater {
+				continue;
+			}
+		}
+		newest.insert(row.0.clone(), row);
+	}
+	(newest.into_values())
+		.map(|(name, ver, rel, dirs, summary, license, url, srpm, maintainers)| {
+			let srcname = srpm
+				.as_deref()
+				.and_then(parse_nevra)
+				.map_or_else(|| name.clone(), |n| n.name.to_owned());
+			// `anda/<category>/.../<name>`
+			let parts: Vec<_> = dirs.split('/').filter(|s| !s.is_empty()).collect();
+			let categories = parts.get(1..parts.len().saturating_sub(1)).unwrap_or_default();
+			let pkg = Package {
+				binnames: binnames.remove(&name).unwrap_or_else(|| vec![name.clone()]),
+				recipe: forge.tree(commits.get(&name).map(String::as_str), &dirs),
+				spec: None,
+				categories: categories.iter().map(|&s| s.to_owned()).collect(),
+				srcname,
+				name,
+				version: ver,
+				release: rel,
+				summary,
+				licenses: license.into_iter().collect(),
+				maintainers: maintainers.unwrap_or_default(),
+				homepage: url,
+			};
+			(dirs, pkg)
+		})
+		.collect()
 }
 
+/// The recipe directory of a package.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the recipe directory"),
+		(status = 404, description = "Not found"),
+	),
+)]
 #[get("/<repo>/packages/<name>")]
-async fn redirect_pkg(db: Connection<Mg>, repo: String, name: String) -> Option<Redirect> {
-	Some(Redirect::to(rootdir(db, repo, name).await?))
+async fn redirect_pkg(mut db: Connection<Mg>, repo: String, name: String) -> Option<Redirect> {
+	Some(Redirect::to(anda::locate(&mut db, &repo, &name).await?.tree("")))
 }
+/// The `anda.hcl` of a package.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the `anda.hcl`"),
+		(status = 404, description = "Not found"),
+	),
+)]
 #[get("/<repo>/packages/<name>/hcl")]
-async fn redirect_andahcl(db: Connection<Mg>, repo: String, name: String) -> Option<Redirect> {
-	Some(Redirect::to(format!("{}/anda.hcl", rootdir(db, repo, name).await?)))
+async fn redirect_andahcl(mut db: Connection<Mg>, repo: String, name: String) -> Option<Redirect> {
+	Some(Redirect::to(anda::locate(&mut db, &repo, &name).await?.tree("anda.hcl")))
 }
+/// The spec file of a package.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the spec file"),
+		(status = 404, description = "Not found"),
+	),
+)]
 #[get("/<repo>/packages/<name>/spec")]
-async fn redirect_andaspec(db: Connection<Mg>, repo: String, name: String) -> Option<Redirect> {
-	let rootdir = rootdir(db, repo, name).await?;
-	let rawurl = rootdir.replace("github.com", "raw.githubusercontent.com").replace("/tree/", "/");
-	let hcl = match reqwest::get(format!("{rawurl}/anda.hcl")).await {
-		Ok(r) => anda_config::load_from_string(&r.text().await.ok()?).ok()?,
-		Err(err) => {
-			tracing::error!(?err, ?rawurl, "No hcl found.");
-			return None;
-		},
-	};
-	let (_, p) = hcl.project.into_iter().next()?;
-	Some(Redirect::to(format!("{rootdir}/{}", p.rpm?.spec.display())))
+async fn redirect_andaspec(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String,
+) -> Option<Redirect> {
+	let loc = anda::locate(&mut db, &repo, &name).await?;
+	let (loc, spec) = spec(&mut db, &config.anda, loc).await?;
+	Some(Redirect::to(loc.tree(&spec)))
 }
+/// The raw spec file of a package.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the raw spec file"),
+		(status = 404, description = "Not found"),
+	),
+)]
 #[get("/<repo>/packages/<name>/spec/raw")]
-async fn redirect_andaspecraw(db: Connection<Mg>, repo: String, name: String) -> Option<Redirect> {
-	let rootdir = rootdir(db, repo, name).await?;
-	let rawurl = rootdir.replace("github.com", "raw.githubusercontent.com").replace("/tree/", "/");
-	let hcl = match reqwest::get(format!("{rawurl}/anda.hcl")).await {
-		Ok(r) => anda_config::load_from_string(&r.text().await.ok()?).ok()?,
+async fn redirect_andaspecraw(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String,
+) -> Option<Redirect> {
+	let loc = anda::locate(&mut db, &repo, &name).await?;
+	let (loc, spec) = spec(&mut db, &config.anda, loc).await?;
+	Some(Redirect::to(loc.raw(&spec)))
+}
+
+/// The recipe that produced `name` at `verrel` (`ver-rel`).
+async fn ver_rootdir(
+	db: &mut PgConnection, repo: &str, name: &str, verrel: &str,
+) -> Option<Location> {
+	let (ver, rel) = verrel.rsplit_once('-')?;
+	anda::locate_version(db, repo, name, ver, rel).await?.1
+}
+/// The recipe directory of a package version.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the recipe directory at the commit of the build"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/v/<verrel>")]
+async fn redirect_ver(
+	mut db: Connection<Mg>, repo: String, name: String, verrel: String,
+) -> Option<Redirect> {
+	Some(Redirect::to(ver_rootdir(&mut db, &repo, &name, &verrel).await?.tree("")))
+}
+/// The `anda.hcl` of a package version.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the `anda.hcl`"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/v/<verrel>/hcl")]
+async fn redirect_ver_andahcl(
+	mut db: Connection<Mg>, repo: String, name: String, verrel: String,
+) -> Option<Redirect> {
+	Some(Redirect::to(ver_rootdir(&mut db, &repo, &name, &verrel).await?.tree("anda.hcl")))
+}
+/// The spec file of a package version.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the spec file"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/v/<verrel>/spec")]
+async fn redirect_ver_andaspec(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String, verrel: String,
+) -> Option<Redirect> {
+	let loc = ver_rootdir(&mut db, &repo, &name, &verrel).await?;
+	let (loc, spec) = spec(&mut db, &config.anda, loc).await?;
+	Some(Redirect::to(loc.tree(&spec)))
+}
+/// The raw spec file of a package version.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the raw spec file"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/v/<verrel>/spec/raw")]
+async fn redirect_ver_andaspecraw(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String, verrel: String,
+) -> Option<Redirect> {
+	let loc = ver_rootdir(&mut db, &repo, &name, &verrel).await?;
+	let (loc, spec) = spec(&mut db, &config.anda, loc).await?;
+	Some(Redirect::to(loc.raw(&spec)))
+}
+/// The CI run that built a package version.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the CI run"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/v/<verrel>/build")]
+async fn redirect_ver_build(
+	mut db: Connection<Mg>, repo: String, name: String, verrel: String,
+) -> Option<Redirect> {
+	let (ver, rel) = verrel.rsplit_once('-')?;
+	let (id, _) = anda::locate_version(&mut db, &repo, &name, ver, rel).await?;
+	let forge = forge::fetch(&mut db, &repo).await.ok()??;
+	Some(Redirect::to(forge.run(&id)))
+}
+
+/// The commit of build `id`, if the build exists.
+async fn build_commit(db: &mut PgConnection, repo: &str, id: &str) -> Option<Option<String>> {
+	let q = sqlx::query_scalar("SELECT commit FROM builds WHERE (id,repo)=($1,$2) LIMIT 1");
+	q.bind(id).bind(repo).fetch_optional(db).await.ok()?
+}
+/// The CI run of a build.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the CI run"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/builds/<id>")]
+async fn redirect_build(mut db: Connection<Mg>, repo: String, id: String) -> Option<Redirect> {
+	build_commit(&mut db, &repo, &id).await?;
+	let forge = forge::fetch(&mut db, &repo).await.ok()??;
+	Some(Redirect::to(forge.run(&id)))
+}
+/// The commit of a build.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the commit"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/builds/<id>/commit")]
+async fn redirect_build_commit(
+	mut db: Connection<Mg>, repo: String, id: String,
+) -> Option<Redirect> {
+	let commit = build_commit(&mut db, &repo, &id).await??;
+	let forge = forge::fetch(&mut db, &repo).await.ok()??;
+	Some(Redirect::to(forge.commit(commit.trim())))
+}
+
+async fn rpm_url(
+	db: &mut PgConnection, settings: &config::Repodata, repo: &str, name: &str, arch: Option<&str>,
+	region: Option<&str>,
+) -> Option<Redirect> {
+	let path = match repodata::locate_rpm(db, settings, repo, name, arch).await {
+		Ok(path) => path?,
 		Err(err) => {
-			tracing::error!(?err, ?rawurl, "No hcl found.");
+			tracing::error!(?err, repo, name, arch, "Cannot locate rpm");
 			return None;
 		},
 	};
-	let (_, p) = hcl.project.into_iter().next()?;
-	Some(Redirect::to(format!("{rawurl}/{}", p.rpm?.spec.display())))
+	match mirrors::url_for(db, repo, &path, region).await {
+		Ok(url) => Some(Redirect::to(url?)),
+		Err(err) => {
+			tracing::error!(?err, repo, name, "Cannot find mirror");
+			None
+		},
+	}
+}
+/// The latest RPM of a package, on a mirror near the client.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the RPM"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/rpm?<arch>")]
+async fn redirect_rpm(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String,
+	arch: Option<String>, region: Region,
+) -> Option<Redirect> {
+	let (arch, region) = (arch.as_deref(), region.0.as_deref());
+	rpm_url(&mut db, &config.repodata, &repo, &name, arch, region).await
+}
+/// The latest source RPM of a package, on a mirror near the client.
+#[utoipa::path(
+	tag = "redirects",
+	responses(
+		(status = 303, description = "To the source RPM"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/srpm")]
+async fn redirect_srpm(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String, region: Region,
+) -> Option<Redirect> {
+	rpm_url(&mut db, &config.repodata, &repo, &name, Some("src"), region.0.as_deref()).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn row(name: &str, ver: &str, rel: &str, dirs: &str) -> FeedRow {
+		let srpm = Some(format!("{name}-src-{ver}-{rel}.src.rpm"));
+		let (name, ver, rel, dirs) = (name.into(), ver.into(), rel.into(), dirs.into());
+		(name, ver, rel, dirs, Some("Foo".into()), Some("MIT".into()), None, srpm, None)
+	}
+
+	#[test]
+	fn feed_shape() {
+		let forge = forge::Forge {
+			url: Some("https://github.com/terrapkg/packages".into()),
+			..forge::Forge::default()
+		};
+		let rows = vec![
+			row("foo", "1.10", "1", "anda/langs/rust/foo"),
+			row("foo", "1.9", "3", "anda/langs/rust/foo"),
+			row("foo", "1.10", "2", "anda/langs/rust/foo"),
+			row("bar", "2.0", "1", "anda/bar"),
+		];
+		let commits = HashMap::from([("foo".into(), "abc".into())]);
+		let binnames = HashMap::from([("foo".into(), vec!["foo".into(), "foo-devel".into()])]);
+		let pkgs = packages(&forge, rows, &commits, binnames);
+		let dirs: Vec<_> = pkgs.iter().map(|(dirs, _)| dirs.as_str()).collect();
+		assert_eq!(dirs, ["anda/bar", "anda/langs/rust/foo"]);
+		let json = serde_json::to_value(pkgs.into_iter().map(|(_, p)| p).collect::<Vec<_>>());
+		assert_eq!(
+			json.unwrap(),
+			serde_json::json!([
+				{
+					"name": "bar",
+					"srcname": "bar-src",
+					"binnames": ["bar"],
+					"version": "2.0",
+					"release": "1",
+					"summary": "Foo",
+					"licenses": ["MIT"],
+					"maintainers": [],
+					"homepage": null,
+					"categories": [],
+					"recipe": "https://github.com/terrapkg/packages/tree/HEAD/anda/bar",
+				},
+				{
+					"name": "foo",
+					"srcname": "foo-src",
+					"binnames": ["foo", "foo-devel"],
+					"version": "1.10",
+					"release": "2",
+					"summary": "Foo",
+					"licenses": ["MIT"],
+					"maintainers": [],
+					"homepage": null,
+					"categories": ["langs", "rust"],
+					"recipe": "https://github.com/terrapkg/packages/tree/abc/anda/langs/rust/foo",
+				},
+			])
+		);
+	}
 }
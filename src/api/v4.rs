@@ -12,23 +12,108 @@
 /// If not, see <https://www.gnu.org/licenses/>.
 ///
 use super::auth::ApiAuth;
-use crate::db::{Build, Madoguchi as Mg, Pkg, Repo};
+use super::problem::Problem;
+use super::repology;
+use crate::config::Config;
+use crate::db::{Build, Madoguchi as Mg, Pkg, PkgMeta, Repo};
+use crate::events::{self, Kind, LastEventId};
+use crate::forge::{self, Forge};
+use crate::mirrors::{self, Region};
+use crate::notify::Webhook;
+use crate::{anda, downloads, metalink, osv, repodata, updateinfo, upstream};
+use rocket::data::{Data, ToByteUnit};
 use rocket::futures::StreamExt;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::Json;
-use rocket::{delete, get, put, routes, Route};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{delete, get, post, put, routes, Route, Shutdown, State};
 use rocket_db_pools::Connection;
 use serde::{Deserialize, Serialize};
-use sqlx::{query as q, query_as as qa};
+use serde_json::json;
+use sqlx::{query as q, query_as as qa, Connection as _};
 use tracing::error;
+use utoipa::OpenApi;
 
 const MAX_LIM: i64 = 100;
 
 pub fn routes() -> Vec<Route> {
-	routes![add_pkg, del_pkg, add_repo, del_repo, list_repos, search_pkgs, pkg_info, list_builds]
+	routes![
+		add_pkg,
+		del_pkg,
+		set_pkg_meta,
+		add_repo,
+		del_repo,
+		list_repos,
+		search_pkgs,
+		pkg_info,
+		list_builds,
+		sync_repo,
+		list_advisories,
+		edit_advisory,
+		del_advisory,
+		updateinfo_xml,
+		import_osv,
+		rescan_osv,
+		list_vulns,
+		set_upstream_name,
+		set_upstream,
+		check_upstream,
+		list_outdated,
+		repology_json,
+		pkg_anda,
+		pkg_spec,
+		list_mirrors,
+		set_mirror,
+		del_mirror,
+		mirror_status,
+		get_metalink,
+		import_downloads,
+		pkg_downloads,
+		popular,
+		event_stream
+	]
 }
 
-#[derive(Deserialize)]
+#[derive(OpenApi)]
+#[openapi(paths(
+	add_pkg,
+	del_pkg,
+	set_pkg_meta,
+	add_repo,
+	del_repo,
+	list_repos,
+	search_pkgs,
+	pkg_info,
+	list_builds,
+	sync_repo,
+	list_advisories,
+	edit_advisory,
+	del_advisory,
+	updateinfo_xml,
+	import_osv,
+	rescan_osv,
+	list_vulns,
+	set_upstream_name,
+	set_upstream,
+	check_upstream,
+	list_outdated,
+	repology_json,
+	pkg_anda,
+	pkg_spec,
+	list_mirrors,
+	set_mirror,
+	del_mirror,
+	mirror_status,
+	get_metalink,
+	import_downloads,
+	pkg_downloads,
+	popular,
+	event_stream
+))]
+pub struct Doc;
+
+#[derive(Deserialize, utoipa::ToSchema)]
 struct AddPkgBody {
 	ver: String,
 	rel: String,
@@ -36,10 +121,20 @@ struct AddPkgBody {
 	dirs: String,
 }
 
+/// Add a package, or update its version if it exists.
+#[utoipa::path(
+	tag = "packages",
+	request_body = AddPkgBody,
+	responses(
+		(status = 201, description = "Added"),
+		(status = 204, description = "Updated"),
+	),
+	security(("token" = [])),
+)]
 #[put("/<repo>/packages/<name>", data = "<p>")]
 async fn add_pkg(
 	mut db: Connection<Mg>, _auth: ApiAuth, repo: String, name: String, p: Json<AddPkgBody>,
-) -> Status {
+) -> Result<Status, Problem> {
 	let dirs = p.dirs.strip_suffix('/').unwrap_or(&p.dirs);
 	let q = q!(
 		"INSERT INTO pkgs(name,repo,ver,rel,arch,dirs) VALUES ($1,$2,$3,$4,$5,$6)",
@@ -53,35 +148,48 @@ async fn add_pkg(
 	match q.execute(&mut **db).await {
 		Ok(res) => {
 			if res.rows_affected() == 1 {
-				Status::Created
+				let data = json!({ "ver": p.ver, "rel": p.rel, "arch": p.arch });
+				events::publish(&mut db, Kind::PackageAdded, &repo, Some(&name), data).await;
+				Ok(Status::Created)
 			} else {
 				tracing::error!("Affected more than 1 rows?");
-				Status::InternalServerError
+				Err(Problem::internal())
 			}
 		},
 		Err(e) => {
-			tracing::error!("{e:#?}");
 			if let Some(e) = e.as_database_error() {
 				if e.code() == Some("23505".into()) {
 					let q = q!(
 						"UPDATE pkgs SET (ver,rel,dirs)=($3,$4,$6) WHERE (name,repo,arch)=($1,$2,$5)",
 						name, repo, p.ver, p.rel, p.arch, dirs
 					);
-					if q.execute(&mut **db).await.is_ok() {
-						return Status::NoContent;
-					}
+					q.execute(&mut **db).await?;
+					return Ok(Status::NoContent);
+				}
+				if e.is_foreign_key_violation() {
+					return Err(Problem::not_found(format!("No repo named {repo}")));
 				}
 			}
-			Status::InternalServerError
+			tracing::error!("{e:#?}");
+			Err(Problem::internal())
 		},
 	}
 }
 
+/// Remove a package version.
+#[utoipa::path(
+	tag = "packages",
+	responses(
+		(status = 204, description = "Removed"),
+		(status = 404, description = "No such package version"),
+	),
+	security(("token" = [])),
+)]
 #[delete("/<repo>/packages/<name>?<ver>&<arch>&<rel>")]
 async fn del_pkg(
 	mut db: Connection<Mg>, repo: String, name: String, ver: String, arch: String, rel: String,
 	_auth: ApiAuth,
-) -> Status {
+) -> Result<Status, Problem> {
 	let q = q!(
 		"DELETE FROM pkgs WHERE name=$1 AND repo=$2 AND ver=$3 AND arch=$4 AND rel=$5",
 		name,
@@ -90,88 +198,170 @@ async fn del_pkg(
 		arch,
 		rel
 	);
-	if q.execute(&mut **db).await.is_ok() {
-		Status::NoContent
-	} else {
-		Status::InternalServerError
+	if q.execute(&mut **db).await?.rows_affected() == 0 {
+		return Err(Problem::not_found(format!("No {name} {ver}-{rel}.{arch} in {repo}")));
 	}
+	let data = json!({ "ver": ver, "rel": rel, "arch": arch });
+	events::publish(&mut db, Kind::PackageRemoved, &repo, Some(&name), data).await;
+	Ok(Status::NoContent)
 }
 
-#[derive(Deserialize)]
+/// Set the metadata of a package.
+#[utoipa::path(
+	tag = "packages",
+	request_body = PkgMeta,
+	responses(
+		(status = 204, description = "Stored"),
+	),
+	security(("token" = [])),
+)]
+#[put("/<repo>/packages/<name>/meta", data = "<meta>")]
+async fn set_pkg_meta(
+	mut db: Connection<Mg>, repo: String, name: String, meta: Json<PkgMeta>, _auth: ApiAuth,
+) -> Result<Status, Problem> {
+	meta.store(&mut db, &repo, &name).await?;
+	Ok(Status::NoContent)
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
 struct AddRepoBody {
 	link: String,
 	gh: String,
+	/// Forge settings; `url` and `branch` default to those in `gh`.
+	#[serde(flatten)]
+	forge: Forge,
 }
 
+/// Add a repo, or update it if it exists.
+#[utoipa::path(
+	tag = "repos",
+	request_body = AddRepoBody,
+	responses(
+		(status = 201, description = "Added"),
+		(status = 204, description = "Updated"),
+	),
+	security(("token" = [])),
+)]
 #[put("/repos/<name>", data = "<repo>")]
 async fn add_repo(
 	mut db: Connection<Mg>, name: String, repo: Json<AddRepoBody>, _auth: ApiAuth,
-) -> Status {
+) -> Result<Status, Problem> {
 	let link = repo.link.strip_suffix('/').unwrap_or(&repo.link);
 	let gh = repo.gh.strip_suffix('/').unwrap_or(&repo.gh);
-	let q = q!("INSERT INTO repos(name, link, gh) VALUES ($1,$2,$3)", name, link, gh);
-	match q.execute(&mut **db).await {
-		Ok(res) => {
-			if res.rows_affected() == 1 {
-				Status::Created
-			} else {
-				Status::InternalServerError
-			}
-		},
-		Err(e) => {
-			if let Some(e) = e.as_database_error() {
-				if e.code() == Some("23505".into()) {
-					let q =
-						q!("UPDATE repos SET (link, gh) = ($2,$3) WHERE name=$1", name, link, gh);
-					if q.execute(&mut **db).await.is_ok() {
-						return Status::NoContent;
-					}
-				}
-			}
-			Status::InternalServerError
-		},
+	let mut tx = (**db).begin().await?;
+	let status = upsert_repo(&mut tx, &name, link, gh).await?;
+	let forge = Forge { gh: gh.to_owned(), ..repo.into_inner().forge };
+	if let Err(err) = forge::store(&mut tx, &name, &forge).await {
+		error!(?err, name, "Cannot store forge");
+		return Err(Problem::internal());
 	}
+	tx.commit().await?;
+	Ok(status)
 }
 
+/// Insert or update a repo, answering whether it was created.
+async fn upsert_repo(
+	db: &mut sqlx::PgConnection, name: &str, link: &str, gh: &str,
+) -> sqlx::Result<Status> {
+	// xmax is only set on the rows that already existed
+	let created = sqlx::query_scalar::<_, bool>(
+		"INSERT INTO repos(name,link,gh) VALUES ($1,$2,$3)
+		ON CONFLICT (name) DO UPDATE SET (link,gh)=($2,$3) RETURNING xmax = 0",
+	)
+	.bind(name)
+	.bind(link)
+	.bind(gh)
+	.fetch_one(db)
+	.await?;
+	Ok(if created { Status::Created } else { Status::NoContent })
+}
+
+/// Remove a repo and everything recorded about it.
+#[utoipa::path(
+	tag = "repos",
+	responses(
+		(status = 204, description = "Removed"),
+		(status = 404, description = "No such repo"),
+	),
+	security(("token" = [])),
+)]
 #[delete("/repos/<name>")]
-async fn del_repo(mut db: Connection<Mg>, name: String, _auth: ApiAuth) -> Status {
+async fn del_repo(mut db: Connection<Mg>, name: String, _auth: ApiAuth) -> Result<Status, Problem> {
 	// the main point is to delete from the `repos` table, so we ignore errors
 	// we erase repo refs in pkgs and builds due to the "REFERENCES" (repo is fk)
 	let q = q!("DELETE FROM pkgs WHERE repo = $1", name);
 	if let Err(e) = q.execute(&mut **db).await {
 		error!("DEL REPO {name} pkgs FAIL: {e:#?}");
 	}
+	if let Err(e) = PkgMeta::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} meta FAIL: {e:#?}");
+	}
+	if let Err(e) = repodata::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} repodata FAIL: {e:#?}");
+	}
+	if let Err(e) = updateinfo::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} advisories FAIL: {e:#?}");
+	}
+	if let Err(e) = osv::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} vulnerabilities FAIL: {e:#?}");
+	}
+	if let Err(e) = upstream::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} upstream FAIL: {e:#?}");
+	}
+	if let Err(e) = anda::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} anda cache FAIL: {e:#?}");
+	}
+	if let Err(e) = mirrors::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} mirror status FAIL: {e:#?}");
+	}
+	if let Err(e) = downloads::delete_repo(&mut db, &name).await {
+		error!("DEL REPO {name} downloads FAIL: {e:#?}");
+	}
 	let q = q!("DELETE FROM builds WHERE repo = $1", name);
 	if let Err(e) = q.execute(&mut **db).await {
 		eprintln!("DEL REPO {name} builds FAIL: {e:#?}");
 	}
 	let q = q!("DELETE FROM repos WHERE name = $1", name);
-	q.execute(&mut **db).await.map_or(Status::InternalServerError, |r| {
-		if r.rows_affected() == 1 {
-			Status::NoContent
-		} else if r.rows_affected() == 0 {
-			Status::BadRequest
-		} else {
+	match q.execute(&mut **db).await?.rows_affected() {
+		1 => Ok(Status::NoContent),
+		0 => Err(Problem::not_found(format!("No repo named {name}"))),
+		_ => {
 			eprintln!("[BUG] Somehow we deleted more than 1 repos?");
-			Status::InternalServerError
-		}
-	})
+			Err(Problem::internal())
+		},
+	}
 }
 
+/// List the repos.
+#[utoipa::path(
+	tag = "repos",
+	responses(
+		(status = 200, description = "OK", body = Vec<Repo>),
+	),
+)]
 #[get("/repos")]
 async fn list_repos(mut db: Connection<Mg>) -> rocket::serde::json::Value {
 	let q = qa::<_, Repo>("SELECT * FROM repos").fetch(&mut **db);
 	serde_json::json!(q.map(|x| { x.expect("Can't list repos?") }).collect::<Vec<Repo>>().await)
 }
 
+/// List the packages of a repo.
+#[utoipa::path(
+	tag = "packages",
+	responses(
+		(status = 200, description = "OK", body = Vec<Pkg>),
+		(status = 400, description = "`n` is over 100"),
+	),
+)]
 #[get("/<repo>/packages?<n>&<order>&<offset>")]
 async fn search_pkgs(
 	mut db: Connection<Mg>, repo: String, n: Option<i64>, order: Option<String>,
 	offset: Option<i64>,
-) -> Result<rocket::serde::json::Value, Status> {
+) -> Result<rocket::serde::json::Value, Problem> {
 	if let Some(n) = n {
 		if n > MAX_LIM {
-			return Err(Status::NotFound);
+			return Err(Problem::bad_request(format!("n must be at most {MAX_LIM}"))
+				.with_details(json!({ "n": n, "max": MAX_LIM })));
 		}
 	}
 	// highly electronegative atoms :3
@@ -185,25 +375,716 @@ async fn search_pkgs(
 			.collect::<Vec<Option<Pkg>>>()
 			.await;
 	if res.iter().any(Option::is_none) {
-		Err(Status::NotFound)
+		Err(Problem::internal())
 	} else {
 		Ok(serde_json::json!(res))
 	}
 }
 
+#[derive(Serialize, utoipa::ToSchema)]
+struct PkgInfo {
+	#[serde(flatten)]
+	pkg: Pkg,
+	meta: Option<PkgMeta>,
+	vulns: Vec<osv::Vulnerability>,
+	upstream: Option<upstream::Upstream>,
+}
+
+/// A package with its metadata, vulnerabilities and upstream version.
+#[utoipa::path(
+	tag = "packages",
+	responses(
+		(status = 200, description = "OK", body = PkgInfo),
+		(status = 404, description = "Not found"),
+	),
+)]
 #[get("/<repo>/packages/<name>")]
 async fn pkg_info(
 	mut db: Connection<Mg>, repo: String, name: String,
-) -> Result<rocket::serde::json::Value, Status> {
+) -> Result<rocket::serde::json::Value, Problem> {
 	let res = qa!(Pkg, "SELECT * FROM pkgs WHERE repo=$1 AND name=$2", repo, name);
-	res.fetch_one(&mut **db).await.map_or(Err(Status::NotFound), |res| Ok(serde_json::json!(res)))
+	let pkg = res.fetch_optional(&mut **db).await?;
+	let pkg = pkg.ok_or_else(|| Problem::not_found(format!("No package {name} in {repo}")))?;
+	let meta = PkgMeta::fetch(&mut db, &repo, &name).await.map_err(|err| {
+		error!(?err, repo, name, "Cannot fetch pkg meta");
+		Problem::internal()
+	})?;
+	let vulns = osv::list(&mut db, &repo, Some(&name)).await.map_err(|err| {
+		error!(?err, repo, name, "Cannot fetch vulnerabilities");
+		Problem::internal()
+	})?;
+	let upstream = upstream::fetch(&mut db, &repo, &name).await.map_err(|err| {
+		error!(?err, repo, name, "Cannot fetch upstream");
+		Problem::internal()
+	})?;
+	Ok(serde_json::json!(PkgInfo { pkg, meta, vulns, upstream }))
 }
 
+/// List the builds of a package.
+#[utoipa::path(
+	tag = "builds",
+	responses(
+		(status = 200, description = "OK", body = Vec<Build>),
+	),
+)]
 #[get("/<repo>/builds/<pkg>")]
 async fn list_builds(
 	mut db: Connection<Mg>, repo: String, pkg: String,
-) -> Result<rocket::serde::json::Value, Status> {
+) -> Result<rocket::serde::json::Value, Problem> {
 	let res = qa!(Build, "SELECT * FROM builds WHERE repo=$1 AND pname=$2", repo, pkg);
-	(res.fetch_all(&mut **db).await)
-		.map_or(Err(Status::NotFound), |builds| Ok(serde_json::json!(builds)))
+	Ok(serde_json::json!(res.fetch_all(&mut **db).await?))
+}
+
+/// Compare the packages with the published repodata.
+#[utoipa::path(
+	tag = "repos",
+	responses(
+		(status = 200, description = "OK", body = repodata::SyncReport),
+		(status = 404, description = "Not found"),
+		(status = 502, description = "Cannot fetch the repodata"),
+	),
+	security(("token" = [])),
+)]
+#[post("/<repo>/sync")]
+async fn sync_repo(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, _auth: ApiAuth,
+) -> Result<rocket::serde::json::Value, Problem> {
+	let link = sqlx::query_scalar::<_, String>("SELECT link FROM repos WHERE name=$1").bind(&repo);
+	let link = link.fetch_optional(&mut **db).await?;
+	let link = link.ok_or_else(|| Problem::not_found(format!("No repo named {repo}")))?;
+	let base = repodata::base_for(&config.repodata, &repo, &link);
+	match repodata::sync(&mut db, &repo, &base).await {
+		Ok(report) => Ok(serde_json::json!(report)),
+		Err(err) => {
+			error!(%err, repo, "Cannot sync repodata");
+			Err(Problem::bad_gateway(format!("Cannot sync repodata: {err}")))
+		},
+	}
+}
+
+/// List the advisories of a repo.
+#[utoipa::path(
+	tag = "advisories",
+	responses(
+		(status = 200, description = "OK", body = Vec<updateinfo::Advisory>),
+	),
+)]
+#[get("/<repo>/advisories")]
+async fn list_advisories(
+	mut db: Connection<Mg>, repo: String,
+) -> Result<rocket::serde::json::Value, Problem> {
+	match updateinfo::list(&mut db, &repo).await {
+		Ok(advisories) => Ok(serde_json::json!(advisories)),
+		Err(err) => {
+			error!(?err, repo, "Cannot list advisories");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Edit an advisory.
+#[utoipa::path(
+	tag = "advisories",
+	request_body = updateinfo::AdvisoryEdit,
+	responses(
+		(status = 204, description = "Edited"),
+		(status = 404, description = "Not found"),
+	),
+	security(("token" = [])),
+)]
+#[put("/<repo>/advisories/<id>", data = "<edit>")]
+async fn edit_advisory(
+	mut db: Connection<Mg>, repo: String, id: String, edit: Json<updateinfo::AdvisoryEdit>,
+	_auth: ApiAuth,
+) -> Result<Status, Problem> {
+	match updateinfo::edit(&mut db, &repo, &id, &edit).await {
+		Ok(true) => Ok(Status::NoContent),
+		Ok(false) => Err(Problem::not_found(format!("No advisory {id} in {repo}"))),
+		Err(err) => {
+			error!(?err, repo, id, "Cannot edit advisory");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Remove an advisory.
+#[utoipa::path(
+	tag = "advisories",
+	responses(
+		(status = 204, description = "Removed"),
+		(status = 404, description = "Not found"),
+	),
+	security(("token" = [])),
+)]
+#[delete("/<repo>/advisories/<id>")]
+async fn del_advisory(
+	mut db: Connection<Mg>, repo: String, id: String, _auth: ApiAuth,
+) -> Result<Status, Problem> {
+	match updateinfo::delete(&mut db, &repo, &id).await {
+		Ok(true) => Ok(Status::NoContent),
+		Ok(false) => Err(Problem::not_found(format!("No advisory {id} in {repo}"))),
+		Err(err) => {
+			error!(?err, repo, id, "Cannot delete advisory");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// The advisories of a repo as `updateinfo.xml`.
+#[utoipa::path(
+	tag = "advisories",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "application/xml"),
+	),
+)]
+#[get("/<repo>/updateinfo.xml")]
+async fn updateinfo_xml(
+	mut db: Connection<Mg>, repo: String,
+) -> Result<(ContentType, String), Problem> {
+	let advisories = updateinfo::list(&mut db, &repo).await.map_err(|err| {
+		error!(?err, repo, "Cannot list advisories");
+		Problem::internal()
+	})?;
+	match updateinfo::render(&repo, &advisories) {
+		Ok(xml) => Ok((ContentType::XML, xml)),
+		Err(err) => {
+			error!(?err, repo, "Cannot render updateinfo.xml");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Import an OSV dump, e.g. `curl --data-binary @all.json`.
+#[utoipa::path(
+	tag = "vulnerabilities",
+	request_body(content = Vec<u8>, description = "OSV dump, a JSON array", content_type = "application/json"),
+	responses(
+		(status = 200, description = "Number of imported advisories and the vulnerabilities found", body = serde_json::Value),
+		(status = 400, description = "Invalid dump"),
+		(status = 413, description = "Dump over 512 MiB"),
+	),
+	security(("token" = [])),
+)]
+#[post("/vulnerabilities/import?<notify>", data = "<dump>")]
+async fn import_osv(
+	mut db: Connection<Mg>, webhook: &State<Webhook>, notify: Option<bool>, dump: Data<'_>,
+	_auth: ApiAuth,
+) -> Result<rocket::serde::json::Value, Problem> {
+	let dump = match dump.open(512.mebibytes()).into_bytes().await {
+		Ok(dump) if dump.is_complete() => dump.into_inner(),
+		Ok(_) => return Err(Problem::new(Status::PayloadTooLarge, "The dump is over 512 MiB")),
+		Err(err) => {
+			error!(?err, "Cannot read OSV dump");
+			return Err(Problem::bad_request("Cannot read the dump"));
+		},
+	};
+	let imported = match osv::import(&mut db, &dump).await {
+		Ok(n) => n,
+		Err(osv::ImportError::Json(err)) => {
+			error!(%err, "Cannot parse OSV dump");
+			return Err(Problem::bad_request(format!("Invalid dump: {err}")));
+		},
+		Err(err) => {
+			error!(%err, "Cannot import OSV dump");
+			return Err(Problem::internal());
+		},
+	};
+	let found = rescan(&mut db, notify.unwrap_or_default().then_some(webhook)).await?;
+	Ok(serde_json::json!({ "imported": imported, "found": found }))
+}
+
+/// Match the packages against the imported advisories.
+#[utoipa::path(
+	tag = "vulnerabilities",
+	responses(
+		(status = 200, description = "Vulnerabilities found", body = serde_json::Value),
+	),
+	security(("token" = [])),
+)]
+#[post("/vulnerabilities/rescan?<notify>")]
+async fn rescan_osv(
+	mut db: Connection<Mg>, webhook: &State<Webhook>, notify: Option<bool>, _auth: ApiAuth,
+) -> Result<rocket::serde::json::Value, Problem> {
+	let webhook = notify.unwrap_or_default().then_some(&**webhook);
+	Ok(serde_json::json!({ "found": rescan(&mut db, webhook).await? }))
+}
+
+/// Match the packages, notifying `webhook` of the new vulnerabilities if given.
+async fn rescan(
+	db: &mut sqlx::PgConnection, webhook: Option<&Webhook>,
+) -> Result<Vec<osv::Vulnerability>, Problem> {
+	let found = osv::rescan(db).await.map_err(|err| {
+		error!(?err, "Cannot match vulnerabilities");
+		Problem::internal()
+	})?;
+	if let Some(webhook) = webhook {
+		osv::notify(webhook, &found).await;
+	}
+	Ok(found)
+}
+
+/// List the vulnerabilities of a repo.
+#[utoipa::path(
+	tag = "vulnerabilities",
+	responses(
+		(status = 200, description = "OK", body = Vec<osv::Vulnerability>),
+	),
+)]
+#[get("/<repo>/vulnerabilities")]
+async fn list_vulns(
+	mut db: Connection<Mg>, repo: String,
+) -> Result<rocket::serde::json::Value, Problem> {
+	match osv::list(&mut db, &repo, None).await {
+		Ok(vulns) => Ok(serde_json::json!(vulns)),
+		Err(err) => {
+			error!(?err, repo, "Cannot list vulnerabilities");
+			Err(Problem::internal())
+		},
+	}
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+struct UpstreamNameBody {
+	ecosystem: String,
+	upstream: String,
+}
+
+/// Set the upstream name of a package in an OSV ecosystem.
+#[utoipa::path(
+	tag = "vulnerabilities",
+	request_body = UpstreamNameBody,
+	responses(
+		(status = 204, description = "Stored"),
+	),
+	security(("token" = [])),
+)]
+#[put("/<repo>/packages/<name>/upstream-name", data = "<body>")]
+async fn set_upstream_name(
+	mut db: Connection<Mg>, repo: String, name: String, body: Json<UpstreamNameBody>,
+	_auth: ApiAuth,
+) -> Result<Status, Problem> {
+	match osv::set_upstream_name(&mut db, &repo, &name, &body.ecosystem, &body.upstream).await {
+		Ok(()) => Ok(Status::NoContent),
+		Err(err) => {
+			error!(?err, repo, name, "Cannot set upstream name");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Set the upstream source or version of a package.
+#[utoipa::path(
+	tag = "upstream",
+	request_body = upstream::UpstreamEdit,
+	responses(
+		(status = 204, description = "Stored"),
+		(status = 400, description = "Unknown source"),
+	),
+	security(("token" = [])),
+)]
+#[put("/<repo>/packages/<name>/upstream", data = "<edit>")]
+async fn set_upstream(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String,
+	edit: Json<upstream::UpstreamEdit>, _auth: ApiAuth,
+) -> Result<Status, Problem> {
+	match upstream::set(&mut db, &config.upstream, &repo, &name, &edit).await {
+		Ok(()) => Ok(Status::NoContent),
+		Err(err @ upstream::Error::BadSource(_)) => Err(Problem::bad_request(err.to_string())),
+		Err(err) => {
+			error!(%err, repo, name, "Cannot set upstream");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Check the upstream versions of a repo now.
+#[utoipa::path(
+	tag = "upstream",
+	responses(
+		(status = 200, description = "OK", body = BTreeMap<String, upstream::Upstream>),
+	),
+	security(("token" = [])),
+)]
+#[post("/<repo>/upstream/check")]
+async fn check_upstream(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, _auth: ApiAuth,
+) -> Result<rocket::serde::json::Value, Problem> {
+	match upstream::check(&mut db, &config.upstream, &repo).await {
+		Ok(checked) => Ok(serde_json::json!(checked
+			.into_iter()
+			.map(|u| (u.name.clone(), u))
+			.collect::<std::collections::BTreeMap<_, _>>())),
+		Err(err) => {
+			error!(?err, repo, "Cannot check upstream versions");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// List the packages behind upstream.
+#[utoipa::path(
+	tag = "upstream",
+	responses(
+		(status = 200, description = "OK", body = Vec<upstream::Outdated>),
+	),
+)]
+#[get("/<repo>/outdated")]
+async fn list_outdated(
+	mut db: Connection<Mg>, repo: String,
+) -> Result<rocket::serde::json::Value, Problem> {
+	match upstream::outdated(&mut db, &repo).await {
+		Ok(outdated) => Ok(serde_json::json!(outdated)),
+		Err(err) => {
+			error!(?err, repo, "Cannot list outdated packages");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// The packages of a repo in Repology's format.
+#[utoipa::path(
+	tag = "repos",
+	responses(
+		(status = 200, description = "OK", body = Vec<repology::Package>),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/repology.json")]
+async fn repology_json(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String,
+) -> Result<Json<Vec<repology::Package>>, Problem> {
+	match repology::feed(&mut db, &config.anda, &repo).await {
+		Ok(Some(pkgs)) => Ok(Json(pkgs)),
+		Ok(None) => Err(Problem::not_found(format!("No repo named {repo}"))),
+		Err(err) => {
+			error!(?err, repo, "Cannot generate Repology feed");
+			Err(Problem::internal())
+		},
+	}
+}
+
+fn no_recipe(repo: &str, name: &str) -> Problem {
+	Problem::not_found(format!("No recipe known for {name} in {repo}"))
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+struct AndaInfo {
+	url: String,
+	commit: Option<String>,
+	#[schema(value_type = Object)]
+	project: anda_config::Project,
+}
+
+/// The resolved `anda.hcl` of a package.
+#[utoipa::path(
+	tag = "packages",
+	responses(
+		(status = 200, description = "OK", body = AndaInfo),
+		(status = 404, description = "Not found"),
+		(status = 502, description = "Cannot fetch the recipe"),
+	),
+)]
+#[get("/<repo>/packages/<name>/anda")]
+async fn pkg_anda(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String,
+) -> Result<Json<AndaInfo>, Problem> {
+	let loc = anda::locate(&mut db, &repo, &name).await.ok_or_else(|| no_recipe(&repo, &name))?;
+	match anda::resolve(&mut db, &config.anda, &loc).await {
+		Ok(r) => Ok(Json(AndaInfo {
+			url: loc.tree("anda.hcl"),
+			project: r.project.clone(),
+			commit: loc.commit,
+		})),
+		Err(err) => {
+			error!(%err, repo, name, "Cannot resolve anda.hcl");
+			Err(Problem::bad_gateway(format!("Cannot resolve anda.hcl: {err}")))
+		},
+	}
+}
+
+/// The spec file of a package.
+#[utoipa::path(
+	tag = "packages",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "text/plain"),
+		(status = 404, description = "Not found"),
+		(status = 502, description = "Cannot fetch the spec"),
+	),
+)]
+#[get("/<repo>/packages/<name>/spec")]
+async fn pkg_spec(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, name: String,
+) -> Result<(ContentType, String), Problem> {
+	let loc = anda::locate(&mut db, &repo, &name).await.ok_or_else(|| no_recipe(&repo, &name))?;
+	match anda::spec(&mut db, &config.anda, &loc).await {
+		Ok(Some((_, content))) => Ok((ContentType::Plain, content)),
+		Ok(None) => Err(Problem::not_found(format!("No spec file for {name} in {repo}"))),
+		Err(err) => {
+			error!(%err, repo, name, "Cannot fetch spec");
+			Err(Problem::bad_gateway(format!("Cannot fetch the spec: {err}")))
+		},
+	}
+}
+
+/// List the mirrors with their contacts.
+#[utoipa::path(
+	tag = "mirrors",
+	responses(
+		(status = 200, description = "OK", body = Vec<mirrors::Mirror>),
+	),
+	security(("token" = [])),
+)]
+#[get("/mirrors")]
+async fn list_mirrors(
+	mut db: Connection<Mg>, _auth: ApiAuth,
+) -> Result<Json<Vec<mirrors::Mirror>>, Problem> {
+	match mirrors::list(&mut db).await {
+		Ok(list) => Ok(Json(list)),
+		Err(err) => {
+			error!(?err, "Cannot list mirrors");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Add or update a mirror.
+#[utoipa::path(
+	tag = "mirrors",
+	request_body = mirrors::Mirror,
+	responses(
+		(status = 201, description = "Added"),
+		(status = 204, description = "Updated"),
+	),
+	security(("token" = [])),
+)]
+#[put("/mirrors/<name>", data = "<mirror>")]
+async fn set_mirror(
+	mut db: Connection<Mg>, name: String, mirror: Json<mirrors::Mirror>, _auth: ApiAuth,
+) -> Result<Status, Problem> {
+	match mirrors::store(&mut db, &name, &mirror).await {
+		Ok(true) => Ok(Status::Created),
+		Ok(false) => Ok(Status::NoContent),
+		Err(err) => {
+			error!(?err, name, "Cannot store mirror");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Remove a mirror.
+#[utoipa::path(
+	tag = "mirrors",
+	responses(
+		(status = 204, description = "Removed"),
+		(status = 404, description = "Not found"),
+	),
+	security(("token" = [])),
+)]
+#[delete("/mirrors/<name>")]
+async fn del_mirror(
+	mut db: Connection<Mg>, name: String, _auth: ApiAuth,
+) -> Result<Status, Problem> {
+	match mirrors::delete(&mut db, &name).await {
+		Ok(true) => Ok(Status::NoContent),
+		Ok(false) => Err(Problem::not_found(format!("No mirror named {name}"))),
+		Err(err) => {
+			error!(?err, name, "Cannot delete mirror");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// The state of each repo on each mirror.
+#[utoipa::path(
+	tag = "mirrors",
+	responses(
+		(status = 200, description = "OK", body = Vec<mirrors::MirrorStatus>),
+	),
+)]
+#[get("/mirrors/status")]
+async fn mirror_status(
+	mut db: Connection<Mg>,
+) -> Result<Json<Vec<mirrors::MirrorStatus>>, Problem> {
+	match mirrors::status(&mut db).await {
+		Ok(status) => Ok(Json(status)),
+		Err(err) => {
+			error!(?err, "Cannot read mirror status");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// A metalink for dnf listing the fresh mirrors of a repo.
+#[utoipa::path(
+	tag = "mirrors",
+	responses(
+		(status = 200, description = "OK", body = String, content_type = "application/metalink+xml"),
+		(status = 400, description = "Invalid arch"),
+		(status = 404, description = "Not found"),
+		(status = 502, description = "Cannot fetch the repodata"),
+	),
+)]
+#[get("/metalink?<repo>&<arch>")]
+async fn get_metalink(
+	mut db: Connection<Mg>, config: &State<Config>, repo: String, arch: Option<String>,
+	region: Region,
+) -> Result<(ContentType, String), Problem> {
+	let (arch, region) = (arch.as_deref(), region.0.as_deref());
+	if let Some(arch) = arch.filter(|a| !metalink::valid_arch(a)) {
+		return Err(Problem::bad_request(format!("Invalid arch {arch:?}")));
+	}
+	match metalink::metalink(&mut db, config, &repo, arch, region).await {
+		Ok(Some(xml)) => Ok((ContentType::new("application", "metalink+xml"), xml)),
+		Ok(None) => Err(Problem::not_found(format!("No repo named {repo}"))),
+		Err(err @ repodata::Error::Db(_)) => {
+			error!(%err, repo, "Cannot generate metalink");
+			Err(Problem::internal())
+		},
+		Err(err) => {
+			error!(%err, repo, "Cannot generate metalink");
+			Err(Problem::bad_gateway(format!("Cannot fetch the repodata: {err}")))
+		},
+	}
+}
+
+/// Count the downloads in a mirror access log, optionally gzipped.
+#[utoipa::path(
+	tag = "downloads",
+	request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+	responses(
+		(status = 200, description = "OK", body = downloads::Ingested),
+		(status = 400, description = "Cannot decompress the log"),
+		(status = 413, description = "Log over 1 GiB"),
+	),
+	security(("token" = [])),
+)]
+#[post("/downloads/import?<repo>", data = "<log>")]
+async fn import_downloads(
+	mut db: Connection<Mg>, repo: Option<String>, log: Data<'_>, _auth: ApiAuth,
+) -> Result<Json<downloads::Ingested>, Problem> {
+	let log = match log.open(1.gibibytes()).into_bytes().await {
+		Ok(log) if log.is_complete() => log.into_inner(),
+		Ok(_) => return Err(Problem::new(Status::PayloadTooLarge, "The log is over 1 GiB")),
+		Err(err) => {
+			error!(?err, "Cannot read access log");
+			return Err(Problem::bad_request("Cannot read the log"));
+		},
+	};
+	match downloads::ingest(&mut db, &log, repo.as_deref()).await {
+		Ok(report) => Ok(Json(report)),
+		Err(repodata::Error::Io(err)) => {
+			error!(%err, "Cannot decompress access log");
+			Err(Problem::bad_request(format!("Cannot decompress the log: {err}")))
+		},
+		Err(err) => {
+			error!(%err, "Cannot ingest access log");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// `days` of the download endpoints, 30 by default.
+fn check_days(days: Option<u32>) -> Result<u32, Problem> {
+	let max = downloads::MAX_DAYS;
+	match days.unwrap_or(30) {
+		days if days > max => Err(Problem::bad_request(format!("days must be at most {max}"))
+			.with_details(json!({ "days": days, "max": max }))),
+		days => Ok(days),
+	}
+}
+
+/// Downloads of a package in the last `days` days (default 30).
+#[utoipa::path(
+	tag = "downloads",
+	responses(
+		(status = 200, description = "OK", body = downloads::Downloads),
+		(status = 400, description = "`days` is over 3650"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/packages/<name>/downloads?<days>")]
+async fn pkg_downloads(
+	mut db: Connection<Mg>, repo: String, name: String, days: Option<u32>,
+) -> Result<Json<downloads::Downloads>, Problem> {
+	let days = check_days(days)?;
+	match downloads::of(&mut db, &repo, &name, days).await {
+		Ok(Some(dl)) => Ok(Json(dl)),
+		Ok(None) => Err(Problem::not_found(format!("No repo named {repo}"))),
+		Err(err) => {
+			error!(?err, repo, name, "Cannot count downloads");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// The `n` (default 20) most downloaded packages in the last `days` days.
+#[utoipa::path(
+	tag = "downloads",
+	responses(
+		(status = 200, description = "OK", body = Vec<downloads::Popular>),
+		(status = 400, description = "`days` is over 3650"),
+		(status = 404, description = "Not found"),
+	),
+)]
+#[get("/<repo>/popular?<n>&<days>")]
+async fn popular(
+	mut db: Connection<Mg>, repo: String, n: Option<i64>, days: Option<u32>,
+) -> Result<Json<Vec<downloads::Popular>>, Problem> {
+	let n = n.unwrap_or(20).clamp(1, MAX_LIM);
+	let days = check_days(days)?;
+	match downloads::popular(&mut db, &repo, days, n).await {
+		Ok(Some(pkgs)) => Ok(Json(pkgs)),
+		Ok(None) => Err(Problem::not_found(format!("No repo named {repo}"))),
+		Err(err) => {
+			error!(?err, repo, "Cannot list popular packages");
+			Err(Problem::internal())
+		},
+	}
+}
+
+/// Build and package events as they happen, optionally only those of `repo` and `package`.
+/// Clients resuming with `Last-Event-ID` first get the events they missed.
+#[utoipa::path(
+	tag = "events",
+	responses(
+		(status = 200, description = "Server-sent events, named after their kind", body = events::Event, content_type = "text/event-stream"),
+	),
+)]
+#[get("/events?<repo>&<package>")]
+async fn event_stream(
+	mut db: Connection<Mg>, repo: Option<String>, package: Option<String>, last: LastEventId,
+	mut shutdown: Shutdown,
+) -> Result<EventStream![], Problem> {
+	// subscribe before replaying so that nothing falls in between
+	let mut rx = events::subscribe();
+	let missed = match last.0 {
+		Some(id) => events::since(&mut db, id, repo.as_deref(), package.as_deref()).await,
+		None => Ok(vec![]),
+	};
+	let missed = missed.map_err(|err| {
+		error!(?err, "Cannot replay events");
+		Problem::internal()
+	})?;
+	// live events are only skipped when they were just replayed; ids are not in commit order, so
+	// a lower id may still arrive live
+	let replayed: std::collections::HashSet<_> = missed.iter().map(|e| e.id).collect();
+	let sse = |e: &events::Event| Event::json(e).id(e.id.to_string()).event(e.kind.as_str());
+	Ok(EventStream! {
+		for e in &missed {
+			yield sse(e);
+		}
+		loop {
+			let e = rocket::tokio::select! {
+				e = rx.recv() => match e {
+					Ok(e) => e,
+					// the client reconnects and replays what it missed
+					Err(RecvError::Lagged(_) | RecvError::Closed) => break,
+				},
+				() = &mut shutdown => break,
+			};
+			if !replayed.contains(&e.id) && e.matches(repo.as_deref(), package.as_deref()) {
+				yield sse(&e);
+			}
+		}
+	})
 }
@@ -0,0 +1,194 @@
+/// This file is part of Madoguchi.
+///
+/// Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+/// the GNU General Public License as published by the Free Software Foundation, either
+/// version 3 of the License, or (at your option) any later version.
+///
+/// Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+/// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License along with Madoguchi.
+/// If not, see <https://www.gnu.org/licenses/>.
+///
+// HTML pages for browsing the repos, usable without JavaScript.
+// The routes have a low rank so that they never shadow the API and redirects.
+use crate::badge;
+use crate::db::{Build, Madoguchi as Mg, PkgMeta};
+use chrono::NaiveDateTime;
+use rocket::http::Status;
+use rocket::{get, routes, Route};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgConnection;
+use tracing::error;
+
+const PAGE: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+	routes![index, repo, package, failures]
+}
+
+fn date(t: NaiveDateTime) -> String {
+	t.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn internal(err: &sqlx::Error) -> Status {
+	error!(?err, "Cannot render page");
+	Status::InternalServerError
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct RepoRow {
+	name: String,
+	link: String,
+	packages: i64,
+	failing: i64,
+}
+
+#[get("/")]
+async fn index(mut db: Connection<Mg>) -> Result<Template, Status> {
+	let repos = sqlx::query_as::<_, RepoRow>(
+		"SELECT r.name,r.link,
+			(SELECT COUNT(DISTINCT name) FROM pkgs WHERE repo=r.name) AS packages,
+			(SELECT COUNT(*) FROM (SELECT DISTINCT ON (pname) succ FROM builds WHERE repo=r.name
+				ORDER BY pname, epoch DESC) b WHERE NOT succ) AS failing
+		FROM repos r ORDER BY r.name",
+	)
+	.fetch_all(&mut **db)
+	.await
+	.map_err(|e| internal(&e))?;
+	let repos: Vec<_> =
+		(repos.into_iter()).map(|r| json!({ "label": badge::label(&r.name), "repo": r })).collect();
+	Ok(Template::render("index", json!({ "repos": repos })))
+}
+
+async fn exists(db: &mut PgConnection, repo: &str) -> Result<(), Status> {
+	let q = sqlx::query_scalar::<_, String>("SELECT name FROM repos WHERE name=$1");
+	q.bind(repo).fetch_optional(db).await.map_err(|e| internal(&e))?.ok_or(Status::NotFound)?;
+	Ok(())
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct PkgRow {
+	name: String,
+	ver: String,
+	rel: String,
+	arches: Vec<String>,
+	summary: Option<String>,
+}
+
+#[get("/<repo>?<q>&<page>", rank = 1)]
+async fn repo(
+	mut db: Connection<Mg>, repo: String, q: Option<String>, page: Option<u32>,
+) -> Result<Template, Status> {
+	exists(&mut db, &repo).await?;
+	let q = q.filter(|q| !q.trim().is_empty());
+	let page = page.unwrap_or(1).max(1);
+	let mut pkgs = sqlx::query_as::<_, PkgRow>(
+		"SELECT p.name,p.ver,p.rel,array_agg(p.arch ORDER BY p.arch) AS arches,m.summary
+		FROM pkgs p LEFT JOIN pkg_meta m ON (m.repo,m.name)=(p.repo,p.name)
+		WHERE p.repo=$1 AND ($2::text IS NULL OR strpos(lower(p.name),lower(trim($2)))>0)
+		GROUP BY p.name,p.ver,p.rel,m.summary ORDER BY p.name, p.ver DESC LIMIT $3 OFFSET $4",
+	)
+	.bind(&repo)
+	.bind(&q)
+	.bind(PAGE + 1)
+	.bind(PAGE * (i64::from(page) - 1))
+	.fetch_all(&mut **db)
+	.await
+	.map_err(|e| internal(&e))?;
+	let next = pkgs.len() > usize::try_from(PAGE).unwrap_or_default();
+	if next {
+		pkgs.pop();
+	}
+	Ok(Template::render(
+		"repo",
+		json!({
+			"repo": repo,
+			"label": badge::label(&repo),
+			"q": q,
+			"page": page,
+			"next": next.then_some(page + 1),
+			"prev": (page > 1).then(|| page - 1),
+			"pkgs": pkgs,
+		}),
+	))
+}
+
+#[derive(Serialize)]
+struct BuildRow {
+	id: String,
+	date: String,
+	ver: String,
+	rel: String,
+	arch: String,
+	succ: bool,
+	commit: Option<String>,
+}
+
+impl From<Build> for BuildRow {
+	fn from(b: Build) -> Self {
+		Self {
+			date: date(b.epoch),
+			id: b.id,
+			ver: b.pver,
+			rel: b.prel,
+			arch: b.parch,
+			succ: b.succ,
+			commit: b.commit.map(|c| c.trim().to_owned()),
+		}
+	}
+}
+
+#[get("/<repo>/packages/<name>", rank = 1)]
+async fn package(mut db: Connection<Mg>, repo: String, name: String) -> Result<Template, Status> {
+	let q = sqlx::query_as::<_, (String, String, String)>(
+		"SELECT arch,ver,rel FROM pkgs WHERE (repo,name)=($1,$2) ORDER BY arch",
+	);
+	let versions = q.bind(&repo).bind(&name).fetch_all(&mut **db).await;
+	let versions = versions.map_err(|e| internal(&e))?;
+	let q = sqlx::query_as::<_, Build>(
+		"SELECT * FROM builds WHERE (repo,pname)=($1,$2) ORDER BY epoch DESC LIMIT 50",
+	);
+	let builds = q.bind(&repo).bind(&name).fetch_all(&mut **db).await;
+	let builds: Vec<BuildRow> =
+		builds.map_err(|e| internal(&e))?.into_iter().map(Into::into).collect();
+	if versions.is_empty() && builds.is_empty() {
+		return Err(Status::NotFound);
+	}
+	let meta = PkgMeta::fetch(&mut db, &repo, &name).await.map_err(|e| internal(&e))?;
+	let versions: Vec<_> = (versions.into_iter())
+		.map(|(arch, ver, rel)| json!({ "arch": arch, "ver": ver, "rel": rel }))
+		.collect();
+	Ok(Template::render(
+		"package",
+		json!({
+			"repo": repo,
+			"label": badge::label(&repo),
+			"name": name,
+			"meta": meta,
+			"versions": versions,
+			"builds": builds,
+		}),
+	))
+}
+
+#[get("/<repo>/failures", rank = 1)]
+async fn failures(mut db: Connection<Mg>, repo: String) -> Result<Template, Status> {
+	exists(&mut db, &repo).await?;
+	let q = sqlx::query_as::<_, Build>(
+		"SELECT * FROM (SELECT DISTINCT ON (pname,parch) * FROM builds WHERE repo=$1
+		ORDER BY pname, parch, epoch DESC) b WHERE NOT succ ORDER BY epoch DESC",
+	);
+	let builds = q.bind(&repo).fetch_all(&mut **db).await.map_err(|e| internal(&e))?;
+	let failures: Vec<_> = (builds.into_iter())
+		.map(|b| json!({ "name": b.pname, "build": BuildRow::from(b) }))
+		.collect();
+	Ok(Template::render(
+		"failures",
+		json!({ "repo": repo, "label": badge::label(&repo), "failures": failures }),
+	))
+}
@@ -0,0 +1,138 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! SVG badges in the flat style of shields.io.
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+pub const BLUE: &str = "#007ec6";
+pub const GREEN: &str = "#4c1";
+pub const RED: &str = "#e05d44";
+
+/// Advance widths of ASCII 32..=126 in Verdana 11px, in tenths of a pixel.
+const WIDTHS: [u8; 95] = [
+	39, 43, 51, 90, 70, 118, 80, 30, 50, 50, 70, 90, 40, 50, 40, 50, // ` ` to `/`
+	70, 70, 70, 70, 70, 70, 70, 70, 70, 70, // digits
+	50, 50, 90, 90, 90, 60, 110, // `:` to `@`
+	75, 75, 77, 85, 70, 63, 85, 83, 46, 50, 76, 61, 93, 82, 87, 66, 87, 77, 75, 68, 81, 75, 109,
+	75, 68, 75, // uppercase
+	50, 50, 50, 90, 70, 70, // `[` to `` ` ``
+	66, 69, 57, 69, 66, 39, 69, 70, 30, 38, 65, 30, 107, 70, 67, 69, 69, 47, 57, 43, 70, 65, 90,
+	65, 65, 58, // lowercase
+	70, 50, 70, 90, // `{` to `~`
+];
+
+/// Width of `s` in tenths of a pixel.
+fn text_width(s: &str) -> u32 {
+	(s.chars())
+		.map(|c| {
+			let i = (c as usize).wrapping_sub(32);
+			WIDTHS.get(i).map_or(90, |&w| u32::from(w))
+		})
+		.sum()
+}
+
+fn escape(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Render a badge reading `label | message`, with `color` behind the message.
+pub fn render(label: &str, message: &str, color: &str) -> String {
+	// each side has 5px of padding, widths are rounded up to whole pixels
+	let lw = text_width(label).div_ceil(10) + 10;
+	let mw = text_width(message).div_ceil(10) + 10;
+	let w = lw + mw;
+	let (lx, mx) = (lw * 5, lw * 10 + mw * 5);
+	let (ltw, mtw) = (text_width(label), text_width(message));
+	let (label, message) = (escape(label), escape(message));
+	format!(
+		r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{w}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{lw}" height="20" fill="#555"/><rect x="{lw}" width="{mw}" height="20" fill="{color}"/><rect width="{w}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" text-rendering="geometricPrecision" font-size="110"><text aria-hidden="true" x="{lx}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{ltw}">{label}</text><text x="{lx}" y="140" transform="scale(.1)" fill="#fff" textLength="{ltw}">{label}</text><text aria-hidden="true" x="{mx}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{mtw}">{message}</text><text x="{mx}" y="140" transform="scale(.1)" fill="#fff" textLength="{mtw}">{message}</text></g></svg>"##
+	)
+}
+
+/// The badge label of a repo, e.g. `terra 41` for `terra41`.
+pub fn label(repo: &str) -> String {
+	let name = repo.trim_end_matches(|c: char| c.is_ascii_digit());
+	match &repo[name.len()..] {
+		ver if !name.is_empty() && !ver.is_empty() => format!("{name} {ver}"),
+		_ => repo.to_owned(),
+	}
+}
+
+/// Whether an `If-None-Match` header lists `etag`. Weak tags match too, as for `GET`.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+	(if_none_match.split(',').map(str::trim))
+		.any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == etag)
+}
+
+/// A rendered badge, answered with `304 Not Modified` when the client has it already.
+pub struct Badge(pub String);
+
+impl<'r> Responder<'r, 'static> for Badge {
+	fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+		let etag = format!("\"{:.16x}\"", Sha256::digest(&self.0));
+		let cache = Header::new("Cache-Control", "max-age=300");
+		if request.headers().get("If-None-Match").any(|h| matches(h, &etag)) {
+			return Response::build()
+				.status(Status::NotModified)
+				.header(Header::new("ETag", etag))
+				.header(cache)
+				.ok();
+		}
+		Response::build()
+			.header(ContentType::SVG)
+			.header(Header::new("ETag", etag))
+			.header(cache)
+			.sized_body(self.0.len(), Cursor::new(self.0))
+			.ok()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn labels() {
+		assert_eq!(label("terra41"), "terra 41");
+		assert_eq!(label("terrarawhide"), "terrarawhide");
+		assert_eq!(label("41"), "41");
+		assert_eq!(label(""), "");
+	}
+
+	#[test]
+	fn rendering() {
+		let svg = render("terra 41", "1.0-1 <rc>", BLUE);
+		assert!(
+			svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="128" "#),
+			"{svg}"
+		);
+		assert!(svg.contains(r#"aria-label="terra 41: 1.0-1 &lt;rc&gt;""#), "{svg}");
+		assert!(svg.contains(r##"<rect x="55" width="73" height="20" fill="#007ec6"/>"##), "{svg}");
+		assert!(svg.contains(r#"textLength="448">terra 41</text>"#), "{svg}");
+		assert!(!svg.contains("<rc>"));
+	}
+
+	#[test]
+	fn if_none_match() {
+		let etag = r#""abc""#;
+		assert!(matches(r#""abc""#, etag));
+		assert!(matches(r#""x", W/"abc""#, etag));
+		assert!(matches("*", etag));
+		assert!(!matches(r#""abcd", "ab""#, etag));
+		assert!(!matches("", etag));
+	}
+}
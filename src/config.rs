@@ -0,0 +1,483 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! The configuration of madoguchi, extracted from Rocket's figment.
+//!
+//! Every key can be set in `Rocket.toml` (per profile, selected by `ROCKET_PROFILE`), as
+//! `ROCKET_<KEY>`, or as the plain upper-case environment variable (e.g. `JWT_KEY`), which
+//! wins. The configuration is validated before the server starts, and by the `check-config`
+//! task of xtask.
+//!
+//! The settings of the caches and background tasks are tables with defaults (e.g. `[anda]`),
+//! whose keys can also be set as the environment variables of [`NESTED`]. Like `RUST_LOG`, the
+//! `OTEL_*` variables are read where the telemetry is set up, see [`crate::telemetry`].
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use jwt_simple::prelude::HS256Key;
+use rocket::fairing::AdHoc;
+use rocket::figment::providers::Env;
+use rocket::figment::{self, Figment};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::net::SocketAddr;
+use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use tracing::error;
+
+/// The keys also read from the plain environment variables of the same name.
+const KEYS: [&str; 10] = [
+	"jwt_key",
+	"discord_webhook",
+	"public_url",
+	"metrics_token",
+	"metrics_address",
+	"health_checks",
+	"sentry_dsn",
+	"sentry_environment",
+	"sentry_sample_rate",
+	"sentry_send_pii",
+];
+
+/// The environment variables setting the keys of the tables.
+const NESTED: [(&str, &str); 17] = [
+	("ANDA_CACHE_TTL", "anda.cache_ttl"),
+	("ANDA_CACHE_SIZE", "anda.cache_size"),
+	("EVENTS_NOTIFY", "events.notify"),
+	("EVENTS_RETENTION_DAYS", "events.retention_days"),
+	("METALINK_CACHE_TTL", "metalink.cache_ttl"),
+	("MIRROR_REGION_HEADER", "mirrors.region_header"),
+	("MIRROR_MAX_LAG", "mirrors.max_lag"),
+	("MIRROR_CHECK_INTERVAL", "mirrors.check_interval"),
+	("REPODATA_BASE", "repodata.base"),
+	("REPODATA_SYNC_INTERVAL", "repodata.sync_interval"),
+	("RPM_PATH_TEMPLATE", "repodata.rpm_path_template"),
+	("UPSTREAM_GITHUB_API", "upstream.github_api"),
+	("UPSTREAM_PYPI", "upstream.pypi"),
+	("UPSTREAM_CRATES", "upstream.crates"),
+	("UPSTREAM_LOCAL", "upstream.local"),
+	("UPSTREAM_CHECK_INTERVAL", "upstream.check_interval"),
+	("GITHUB_TOKEN", "upstream.github_token"),
+];
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+	/// The key verifying the API tokens, in unpadded base64, see `generate-jwt-key` of xtask
+	#[serde(deserialize_with = "jwt_key")]
+	pub jwt_key: JwtKey,
+	/// The Discord webhook receiving the notifications
+	#[serde(deserialize_with = "url")]
+	pub discord_webhook: Secret,
+	/// The base of the links in feeds, which are relative without it
+	#[serde(default, deserialize_with = "optional_url")]
+	pub public_url: Option<String>,
+	/// The bearer token required by `/metrics`
+	#[serde(default, deserialize_with = "optional")]
+	pub metrics_token: Option<Secret>,
+	/// Where `/metrics` is served instead of the main address
+	#[serde(default)]
+	pub metrics_address: Option<SocketAddr>,
+	/// The optional checks of `/health/ready`, as a list or separated by commas
+	#[serde(default, deserialize_with = "health_checks")]
+	pub health_checks: Vec<HealthCheck>,
+	/// Where errors are reported; Sentry is disabled without it
+	#[serde(default, deserialize_with = "sentry_dsn")]
+	pub sentry_dsn: Option<sentry::types::Dsn>,
+	#[serde(default, deserialize_with = "optional")]
+	pub sentry_environment: Option<String>,
+	/// The share of the errors sent, between 0 and 1
+	#[serde(default = "all", deserialize_with = "rate")]
+	pub sentry_sample_rate: f32,
+	/// Whether IPs and headers are sent to Sentry
+	#[serde(default)]
+	pub sentry_send_pii: bool,
+	#[serde(default)]
+	pub anda: Anda,
+	#[serde(default)]
+	pub events: Events,
+	#[serde(default)]
+	pub metalink: Metalink,
+	#[serde(default)]
+	pub mirrors: Mirrors,
+	#[serde(default)]
+	pub repodata: Repodata,
+	#[serde(default)]
+	pub upstream: Upstream,
+}
+
+/// The cache of the `anda.hcl` recipes.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Anda {
+	/// Seconds before a recipe that is not pinned to a commit is revalidated
+	pub cache_ttl: u64,
+	/// The number of recipes kept in memory
+	pub cache_size: NonZeroUsize,
+}
+
+impl Default for Anda {
+	fn default() -> Self {
+		Self { cache_ttl: 3600, cache_size: NonZeroUsize::new(1024).unwrap() }
+	}
+}
+
+/// The event stream of `/v4/events`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Events {
+	/// Deliver events through Postgres `NOTIFY`, for several replicas sharing a database
+	pub notify: bool,
+	/// Days before events are pruned
+	pub retention_days: NonZeroU16,
+}
+
+impl Default for Events {
+	fn default() -> Self {
+		Self { notify: false, retention_days: NonZeroU16::new(7).unwrap() }
+	}
+}
+
+/// The metalinks of `/v4/metalink`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Metalink {
+	/// Seconds the `repomd.xml` of the primary is kept
+	pub cache_ttl: u64,
+}
+
+impl Default for Metalink {
+	fn default() -> Self {
+		Self { cache_ttl: 60 }
+	}
+}
+
+/// The download mirrors and their checks.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Mirrors {
+	/// The request header with the country of the client
+	#[serde(deserialize_with = "required")]
+	pub region_header: String,
+	/// Seconds a mirror may lag behind the primary
+	pub max_lag: u32,
+	/// Seconds between two checks of the mirrors
+	pub check_interval: NonZeroU64,
+}
+
+impl Default for Mirrors {
+	fn default() -> Self {
+		Self {
+			region_header: "CF-IPCountry".to_owned(),
+			max_lag: 6 * 3600,
+			check_interval: NonZeroU64::new(300).unwrap(),
+		}
+	}
+}
+
+/// The published repodata and its syncs.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Repodata {
+	/// A URL or directory with one subdirectory per repo (e.g. a local mirror) read instead of
+	/// the links of the repos
+	#[serde(deserialize_with = "optional")]
+	pub base: Option<String>,
+	/// Seconds between two syncs of all repos; they are only synced on demand without it
+	pub sync_interval: Option<NonZeroU64>,
+	/// The path of an RPM in a repo that has not been synced
+	#[serde(deserialize_with = "required")]
+	pub rpm_path_template: String,
+}
+
+impl Default for Repodata {
+	fn default() -> Self {
+		Self {
+			base: None,
+			sync_interval: None,
+			rpm_path_template: "Packages/{initial}/{name}-{ver}-{rel}.{arch}.rpm".to_owned(),
+		}
+	}
+}
+
+/// The sources of the upstream versions.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Upstream {
+	#[serde(deserialize_with = "base_url")]
+	pub github_api: String,
+	/// Avoids the rate limits of GitHub
+	#[serde(deserialize_with = "optional")]
+	pub github_token: Option<Secret>,
+	#[serde(deserialize_with = "base_url")]
+	pub pypi: String,
+	#[serde(deserialize_with = "base_url")]
+	pub crates: String,
+	/// A JSON object of `project => version` for the `local` source
+	pub local: PathBuf,
+	/// Seconds between two checks of all repos; they are only checked on demand without it
+	pub check_interval: Option<NonZeroU64>,
+}
+
+impl Default for Upstream {
+	fn default() -> Self {
+		Self {
+			github_api: "https://api.github.com".to_owned(),
+			github_token: None,
+			pypi: "https://pypi.org".to_owned(),
+			crates: "https://crates.io".to_owned(),
+			local: "upstream.json".into(),
+			check_interval: None,
+		}
+	}
+}
+
+/// A secret setting, kept out of the logs.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("<redacted>")
+	}
+}
+
+#[derive(Clone)]
+pub struct JwtKey(pub HS256Key);
+
+impl std::fmt::Debug for JwtKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("<redacted>")
+	}
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheck {
+	/// The Discord webhook answers
+	Notify,
+	/// The `repomd.xml` of every repo can be fetched
+	Storage,
+}
+
+const fn all() -> f32 {
+	1.0
+}
+
+fn required<'de, D: Deserializer<'de>>(de: D) -> Result<String, D::Error> {
+	let s = String::deserialize(de)?;
+	if s.trim().is_empty() {
+		return Err(D::Error::custom("cannot be empty"));
+	}
+	Ok(s)
+}
+
+/// Empty values, e.g. `SENTRY_DSN=`, count as unset.
+fn optional<'de, D: Deserializer<'de>, T: From<String>>(de: D) -> Result<Option<T>, D::Error> {
+	let s = Option::<String>::deserialize(de)?;
+	Ok(s.filter(|s| !s.trim().is_empty()).map(Into::into))
+}
+
+impl From<String> for Secret {
+	fn from(s: String) -> Self {
+		Self(s)
+	}
+}
+
+fn jwt_key<'de, D: Deserializer<'de>>(de: D) -> Result<JwtKey, D::Error> {
+	let key = STANDARD_NO_PAD.decode(required(de)?.trim());
+	let key = key.map_err(|e| D::Error::custom(format!("not unpadded base64: {e}")))?;
+	Ok(JwtKey(HS256Key::from_bytes(&key)))
+}
+
+fn check_url<E: serde::de::Error>(s: &str) -> Result<(), E> {
+	match reqwest::Url::parse(s) {
+		Ok(url) if ["http", "https"].contains(&url.scheme()) => Ok(()),
+		Ok(url) => Err(E::custom(format!("unsupported scheme {}", url.scheme()))),
+		// the URL may be secret, only the reason is given
+		Err(err) => Err(E::custom(format!("not a URL: {err}"))),
+	}
+}
+
+fn url<'de, D: Deserializer<'de>>(de: D) -> Result<Secret, D::Error> {
+	let s = required(de)?;
+	check_url(&s)?;
+	Ok(Secret(s))
+}
+
+fn base_url<'de, D: Deserializer<'de>>(de: D) -> Result<String, D::Error> {
+	let s = required(de)?;
+	check_url(&s)?;
+	Ok(s.trim_end_matches('/').to_owned())
+}
+
+fn optional_url<'de, D: Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+	let s: Option<String> = optional(de)?;
+	s.as_deref().map(check_url).transpose()?;
+	Ok(s)
+}
+
+fn health_checks<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<HealthCheck>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum List {
+		Joined(String),
+		Items(Vec<HealthCheck>),
+	}
+	let joined = match List::deserialize(de)? {
+		List::Items(checks) => return Ok(checks),
+		List::Joined(s) => s,
+	};
+	(joined.split(',').map(str::trim).filter(|s| !s.is_empty()))
+		.map(|name| match name {
+			"notify" => Ok(HealthCheck::Notify),
+			"storage" => Ok(HealthCheck::Storage),
+			_ => Err(D::Error::unknown_variant(name, &["notify", "storage"])),
+		})
+		.collect()
+}
+
+fn sentry_dsn<'de, D: Deserializer<'de>>(de: D) -> Result<Option<sentry::types::Dsn>, D::Error> {
+	let s: Option<String> = optional(de)?;
+	s.map(|s| s.parse().map_err(|e| D::Error::custom(format!("invalid DSN: {e}")))).transpose()
+}
+
+fn rate<'de, D: Deserializer<'de>>(de: D) -> Result<f32, D::Error> {
+	let rate = f32::deserialize(de)?;
+	if !(0.0..=1.0).contains(&rate) {
+		return Err(D::Error::custom(format!("{rate} is not between 0 and 1")));
+	}
+	Ok(rate)
+}
+
+/// Rocket's figment, with the plain environment variables of [`KEYS`] and [`NESTED`].
+pub fn figment() -> Figment {
+	let nested = Env::raw().filter_map(|var| {
+		let (_, key) = NESTED.iter().find(|(name, _)| var == *name)?;
+		Some((*key).into())
+	});
+	rocket::Config::figment().merge(Env::raw().only(&KEYS).global()).merge(nested.global())
+}
+
+/// Everything madoguchi needs to start: Rocket's own settings, the database and [`Config`].
+pub fn load(figment: &Figment) -> Result<Config, Box<figment::Error>> {
+	figment.extract::<rocket::Config>()?;
+	figment.extract_inner::<String>("databases.madoguchi.url")?;
+	Ok(figment.extract()?)
+}
+
+/// Print the errors of an invalid configuration, before the logs are set up.
+pub fn report(err: &figment::Error) {
+	eprintln!("Invalid configuration:");
+	for err in err.clone() {
+		eprintln!("  {err}");
+	}
+}
+
+/// Validates the configuration at ignite and manages it.
+pub fn fairing() -> AdHoc {
+	AdHoc::try_on_ignite("Configuration", |rocket| async {
+		match rocket.figment().extract::<Config>() {
+			Ok(config) => Ok(rocket.manage(config)),
+			Err(err) => {
+				for err in err {
+					error!("Invalid configuration: {err}");
+				}
+				Err(rocket)
+			},
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rocket::figment::providers::{Format, Toml};
+
+	fn required() -> Figment {
+		Figment::new()
+			.merge(("jwt_key", "c2VjcmV0"))
+			.merge(("discord_webhook", "https://discord.com/api/webhooks/1/x"))
+	}
+
+	fn extract(values: &[(&str, &str)]) -> Result<Config, String> {
+		let mut figment = required();
+		for &(key, value) in values {
+			figment = figment.merge((key, value));
+		}
+		figment.extract().map_err(|e| e.to_string())
+	}
+
+	#[test]
+	fn defaults() {
+		let config = extract(&[("sentry_dsn", "")]).unwrap();
+		assert!(config.sentry_dsn.is_none());
+		assert!(config.health_checks.is_empty());
+		assert!((config.sentry_sample_rate - 1.0).abs() < f32::EPSILON);
+		assert!(!format!("{config:?}").contains("discord.com"));
+		assert_eq!(config.anda.cache_size.get(), 1024);
+		assert_eq!(config.mirrors.region_header, "CF-IPCountry");
+		assert!(config.repodata.sync_interval.is_none());
+	}
+
+	fn toml(toml: &str) -> Result<Config, String> {
+		required().merge(Toml::string(toml)).extract().map_err(|e| e.to_string())
+	}
+
+	#[test]
+	fn tables() {
+		let config = toml(
+			"anda.cache_ttl = 60
+			events.notify = true
+			[upstream]
+			pypi = 'http://localhost:8000/'
+			github_token = 'ghp_x'",
+		)
+		.unwrap();
+		assert_eq!(config.anda.cache_ttl, 60);
+		assert_eq!(config.anda.cache_size.get(), 1024);
+		assert!(config.events.notify);
+		assert_eq!(config.upstream.pypi, "http://localhost:8000");
+		assert_eq!(config.upstream.crates, "https://crates.io");
+		assert!(!format!("{config:?}").contains("ghp_x"));
+	}
+
+	#[test]
+	fn health_checks_from_a_string() {
+		let config = extract(&[("health_checks", "storage, notify")]).unwrap();
+		assert_eq!(config.health_checks, [HealthCheck::Storage, HealthCheck::Notify]);
+		let err = extract(&[("health_checks", "storage,disk")]).unwrap_err();
+		assert!(err.contains("disk"), "{err}");
+	}
+
+	#[test]
+	fn invalid_values_name_their_key() {
+		for (key, value) in
+			[("jwt_key", "not base64!"), ("discord_webhook", "discord"), ("jwt_key", "")]
+		{
+			let err = extract(&[(key, value)]).unwrap_err();
+			assert!(err.contains(key), "{err}");
+		}
+		required().merge(("sentry_sample_rate", 2.0)).extract::<Config>().unwrap_err();
+	}
+
+	#[test]
+	fn invalid_tables() {
+		for (key, value) in [
+			("anda.cache_size", "0"),
+			("mirrors.check_interval", "0"),
+			("repodata.sync_interval", "'soon'"),
+			("upstream.crates", "'crates.io'"),
+		] {
+			let err = toml(&format!("{key} = {value}")).unwrap_err();
+			assert!(err.contains(key), "{err}");
+		}
+	}
+}
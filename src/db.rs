@@ -14,20 +14,24 @@
 // sqlx bug
 #![allow(clippy::option_if_let_else, clippy::renamed_function_params)]
 use rocket_db_pools::{sqlx::PgPool, Database};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::Migrator;
+use sqlx::{Connection, PgConnection};
 
 #[derive(Database)]
 #[database("madoguchi")]
 pub struct Madoguchi(PgPool);
 
-#[derive(sqlx::FromRow, Serialize)]
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(sqlx::FromRow, Serialize, utoipa::ToSchema)]
 pub struct Repo {
 	pub name: String,
 	pub link: String,
 	pub gh: String,
 }
 
-#[derive(sqlx::FromRow, Serialize, Debug)]
+#[derive(sqlx::FromRow, Serialize, Debug, utoipa::ToSchema)]
 pub struct Pkg {
 	pub name: String,
 	pub repo: String,
@@ -37,7 +41,7 @@ pub struct Pkg {
 	pub dirs: String,
 }
 
-#[derive(sqlx::FromRow, Serialize)]
+#[derive(sqlx::FromRow, Serialize, utoipa::ToSchema)]
 pub struct Build {
 	pub id: String,
 	pub epoch: chrono::NaiveDateTime,
@@ -49,3 +53,114 @@ pub struct Build {
 	pub succ: bool,
 	pub commit: Option<String>,
 }
+
+/// Package metadata as extracted from the spec file.
+///
+/// The list fields are stored in their own tables (`pkg_subpkgs` and `pkg_deps`), hence the
+/// `sqlx(skip)`; use [`PkgMeta::fetch`] to get a fully populated struct.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug, Default, utoipa::ToSchema)]
+pub struct PkgMeta {
+	pub summary: Option<String>,
+	pub description: Option<String>,
+	pub license: Option<String>,
+	pub url: Option<String>,
+	pub srpm: Option<String>,
+	#[serde(default)]
+	pub maintainers: Vec<String>,
+	#[sqlx(skip)]
+	#[serde(default)]
+	pub subpkgs: Vec<String>,
+	#[sqlx(skip)]
+	#[serde(default)]
+	pub build_requires: Vec<String>,
+	#[sqlx(skip)]
+	#[serde(default)]
+	pub requires: Vec<String>,
+}
+
+impl PkgMeta {
+	pub async fn fetch(
+		db: &mut PgConnection, repo: &str, name: &str,
+	) -> sqlx::Result<Option<Self>> {
+		let q = sqlx::query_as::<_, Self>(
+			"SELECT summary,description,license,url,srpm,maintainers FROM pkg_meta
+			WHERE (name,repo)=($1,$2)",
+		);
+		let Some(mut meta) = q.bind(name).bind(repo).fetch_optional(&mut *db).await? else {
+			return Ok(None);
+		};
+		let q = sqlx::query_scalar(
+			"SELECT subpkg FROM pkg_subpkgs WHERE (name,repo)=($1,$2) ORDER BY subpkg",
+		);
+		meta.subpkgs = q.bind(name).bind(repo).fetch_all(&mut *db).await?;
+		let q = sqlx::query_as::<_, (String, String)>(
+			"SELECT kind,dep FROM pkg_deps WHERE (name,repo)=($1,$2) ORDER BY dep",
+		);
+		for (kind, dep) in q.bind(name).bind(repo).fetch_all(&mut *db).await? {
+			match kind.as_str() {
+				"buildrequires" => meta.build_requires.push(dep),
+				"requires" => meta.requires.push(dep),
+				_ => tracing::warn!(kind, dep, name, repo, "Unknown dependency kind"),
+			}
+		}
+		Ok(Some(meta))
+	}
+
+	/// Replace the stored metadata of the package with `self`.
+	pub async fn store(&self, db: &mut PgConnection, repo: &str, name: &str) -> sqlx::Result<()> {
+		let mut tx = db.begin().await?;
+		sqlx::query(
+			"INSERT INTO pkg_meta(name,repo,summary,description,license,url,srpm,maintainers)
+			VALUES ($1,$2,$3,$4,$5,$6,$7,$8) ON CONFLICT (name,repo) DO UPDATE SET
+			(summary,description,license,url,srpm,maintainers)=($3,$4,$5,$6,$7,$8)",
+		)
+		.bind(name)
+		.bind(repo)
+		.bind(&self.summary)
+		.bind(&self.description)
+		.bind(&self.license)
+		.bind(&self.url)
+		.bind(&self.srpm)
+		.bind(&self.maintainers)
+		.execute(&mut *tx)
+		.await?;
+		let q = sqlx::query("DELETE FROM pkg_subpkgs WHERE (name,repo)=($1,$2)");
+		q.bind(name).bind(repo).execute(&mut *tx).await?;
+		let q = sqlx::query("DELETE FROM pkg_deps WHERE (name,repo)=($1,$2)");
+		q.bind(name).bind(repo).execute(&mut *tx).await?;
+		sqlx::query(
+			"INSERT INTO pkg_subpkgs(name,repo,subpkg) SELECT $1,$2,UNNEST($3::text[])
+			ON CONFLICT DO NOTHING",
+		)
+		.bind(name)
+		.bind(repo)
+		.bind(&self.subpkgs)
+		.execute(&mut *tx)
+		.await?;
+		for (kind, deps) in [("buildrequires", &self.build_requires), ("requires", &self.requires)]
+		{
+			sqlx::query(
+				"INSERT INTO pkg_deps(name,repo,kind,dep) SELECT $1,$2,$3,UNNEST($4::text[])
+				ON CONFLICT DO NOTHING",
+			)
+			.bind(name)
+			.bind(repo)
+			.bind(kind)
+			.bind(deps)
+			.execute(&mut *tx)
+			.await?;
+		}
+		tx.commit().await
+	}
+
+	/// Remove all metadata of every package in `repo`.
+	pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+		for table in ["pkg_subpkgs", "pkg_deps", "pkg_meta"] {
+			sqlx::query(&format!("DELETE FROM {table} WHERE repo = $1"))
+				.bind(repo)
+				.execute(&mut *db)
+				.await?;
+		}
+		Ok(())
+	}
+}
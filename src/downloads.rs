@@ -0,0 +1,227 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Download counts from mirror access logs.
+//!
+//! Logs in common or combined log format are reduced to daily counts per package and arch as
+//! they are read; client addresses and the other fields are never stored. Ingesting the same
+//! log twice counts its downloads twice.
+use crate::repodata::parse_nevra;
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::PgConnection;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+/// A successful download of `path` on `day`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hit<'a> {
+	pub day: NaiveDate,
+	pub path: &'a str,
+}
+
+/// Parse a line like
+/// `1.2.3.4 - - [19/Oct/2026:07:00:00 +0000] "GET /terra41/foo.rpm HTTP/1.1" 200 1234 ...`.
+///
+/// Only complete `GET`s are returned; partial content (206) is not counted.
+pub fn parse_line(line: &str) -> Option<Hit<'_>> {
+	let (_, rest) = line.split_once(" [")?;
+	let (ts, rest) = rest.split_once("] \"")?;
+	let day = NaiveDate::parse_from_str(ts.split(':').next()?, "%d/%b/%Y").ok()?;
+	let (req, rest) = rest.split_once("\" ")?;
+	let mut req = req.split(' ');
+	let (method, path) = (req.next()?, req.next()?);
+	let status: u16 = rest.split(' ').next()?.parse().ok()?;
+	if method != "GET" || !(200..300).contains(&status) || status == 206 {
+		return None;
+	}
+	Some(Hit { day, path: path.split(['?', '#']).next()? })
+}
+
+/// A downloaded RPM.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Rpm<'a> {
+	pub repo: &'a str,
+	pub name: &'a str,
+	pub arch: &'a str,
+}
+
+/// Map `path` to an RPM of `repo`, or of the first of `repos` found in the path.
+pub fn locate<'a>(path: &'a str, repo: Option<&'a str>, repos: &'a [String]) -> Option<Rpm<'a>> {
+	let nevra = parse_nevra(path)?;
+	let repo = repo.or_else(|| {
+		let mut parts = path.split('/');
+		parts.find_map(|p| repos.iter().find(|r| *r == p).map(String::as_str))
+	})?;
+	Some(Rpm { repo, name: nevra.name, arch: nevra.arch })
+}
+
+#[derive(Serialize, Debug, Default, utoipa::ToSchema)]
+pub struct Ingested {
+	pub lines: usize,
+	/// Downloads counted
+	pub counted: i64,
+	/// Lines that are not downloads of a known repo's RPMs
+	pub skipped: usize,
+}
+
+/// Count the downloads in `log`, which may be gzip-compressed.
+///
+/// Paths are mapped to `repo` if given, otherwise to the repo named in them.
+pub async fn ingest(
+	db: &mut PgConnection, log: &[u8], repo: Option<&str>,
+) -> Result<Ingested, crate::repodata::Error> {
+	let mut buf = vec![];
+	let log = if log.starts_with(&[0x1f, 0x8b]) {
+		flate2::read::MultiGzDecoder::new(log).read_to_end(&mut buf)?;
+		&buf
+	} else {
+		log
+	};
+	let repos = sqlx::query_scalar::<_, String>("SELECT name FROM repos");
+	let repos = repos.fetch_all(&mut *db).await?;
+	if repo.is_some_and(|r| !repos.iter().any(|n| n == r)) {
+		return Ok(Ingested::default());
+	}
+	let mut counts: HashMap<_, i64> = HashMap::new();
+	let mut report = Ingested::default();
+	for line in String::from_utf8_lossy(log).lines() {
+		report.lines += 1;
+		let hit = parse_line(line);
+		match hit.as_ref().and_then(|h| Some((locate(h.path, repo, &repos)?, h.day))) {
+			Some((rpm, day)) => {
+				let key = (rpm.repo.to_owned(), rpm.name.to_owned(), rpm.arch.to_owned(), day);
+				*counts.entry(key).or_default() += 1;
+				report.counted += 1;
+			},
+			None => report.skipped += 1,
+		}
+	}
+	let counts: Vec<_> = counts.into_iter().collect();
+	sqlx::query(
+		"INSERT INTO downloads(repo,name,arch,day,count)
+		SELECT * FROM UNNEST($1::text[],$2::text[],$3::text[],$4::date[],$5::bigint[])
+		ON CONFLICT (repo,name,arch,day) DO UPDATE SET count=downloads.count+EXCLUDED.count",
+	)
+	.bind(counts.iter().map(|((r, ..), _)| r.as_str()).collect::<Vec<_>>())
+	.bind(counts.iter().map(|((_, n, ..), _)| n.as_str()).collect::<Vec<_>>())
+	.bind(counts.iter().map(|((.., a, _), _)| a.as_str()).collect::<Vec<_>>())
+	.bind(counts.iter().map(|((.., d), _)| *d).collect::<Vec<_>>())
+	.bind(counts.iter().map(|(_, c)| *c).collect::<Vec<_>>())
+	.execute(db)
+	.await?;
+	Ok(report)
+}
+
+/// The longest period counts are looked up over, about ten years.
+pub const MAX_DAYS: u32 = 3650;
+
+fn since(days: u32) -> NaiveDate {
+	let days = chrono::Days::new(days.min(MAX_DAYS).into());
+	Utc::now().date_naive().checked_sub_days(days).unwrap_or(NaiveDate::MIN)
+}
+
+async fn exists(db: &mut PgConnection, repo: &str) -> sqlx::Result<bool> {
+	let q = sqlx::query_scalar::<_, String>("SELECT name FROM repos WHERE name=$1");
+	Ok(q.bind(repo).fetch_optional(db).await?.is_some())
+}
+
+#[derive(Serialize, Debug, Default, utoipa::ToSchema)]
+pub struct Downloads {
+	pub total: i64,
+	pub arches: BTreeMap<String, i64>,
+	pub daily: BTreeMap<NaiveDate, i64>,
+}
+
+/// Downloads of package `name` in the last `days` days, or `None` if the repo does not exist.
+pub async fn of(
+	db: &mut PgConnection, repo: &str, name: &str, days: u32,
+) -> sqlx::Result<Option<Downloads>> {
+	if !exists(&mut *db, repo).await? {
+		return Ok(None);
+	}
+	let q = sqlx::query_as::<_, (String, NaiveDate, i64)>(
+		"SELECT arch,day,count FROM downloads WHERE (repo,name)=($1,$2) AND day>$3",
+	);
+	let rows = q.bind(repo).bind(name).bind(since(days)).fetch_all(db).await?;
+	let mut dl = Downloads::default();
+	for (arch, day, count) in rows {
+		dl.total += count;
+		*dl.arches.entry(arch).or_default() += count;
+		*dl.daily.entry(day).or_default() += count;
+	}
+	Ok(Some(dl))
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Popular {
+	pub name: String,
+	pub count: i64,
+}
+
+/// The `n` most downloaded packages of `repo` in the last `days` days, or `None` if the repo
+/// does not exist.
+pub async fn popular(
+	db: &mut PgConnection, repo: &str, days: u32, n: i64,
+) -> sqlx::Result<Option<Vec<Popular>>> {
+	if !exists(&mut *db, repo).await? {
+		return Ok(None);
+	}
+	let q = sqlx::query_as(
+		"SELECT name,SUM(count)::bigint AS count FROM downloads WHERE repo=$1 AND day>$2
+		GROUP BY name ORDER BY count DESC, name LIMIT $3",
+	)
+	.bind(repo)
+	.bind(since(days))
+	.bind(n);
+	q.fetch_all(db).await.map(Some)
+}
+
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	sqlx::query("DELETE FROM downloads WHERE repo=$1").bind(repo).execute(db).await?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lines() {
+		let line = |req: &str, status: u16| {
+			format!(r#"1.2.3.4 - - [19/Oct/2026:07:00:00 +0000] "{req}" {status} 1234 "-" "dnf""#)
+		};
+		let day = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+		let hit = line("GET /terra41/foo-1.0-1.x86_64.rpm?x=1 HTTP/1.1", 200);
+		assert_eq!(parse_line(&hit), Some(Hit { day, path: "/terra41/foo-1.0-1.x86_64.rpm" }));
+		assert_eq!(parse_line(&line("GET /a.rpm HTTP/1.1", 304)), None);
+		assert_eq!(parse_line(&line("GET /a.rpm HTTP/1.1", 206)), None);
+		assert_eq!(parse_line(&line("HEAD /a.rpm HTTP/1.1", 200)), None);
+		assert_eq!(parse_line(&line("-", 400)), None);
+		assert_eq!(parse_line("1.2.3.4 - - [yesterday] \"GET /a.rpm HTTP/1.1\" 200 1"), None);
+		assert_eq!(parse_line(""), None);
+	}
+
+	#[test]
+	fn paths() {
+		let repos = ["terra40".to_owned(), "terra41".to_owned()];
+		let rpm = |repo, name, arch| Some(Rpm { repo, name, arch });
+		let path = "/mirror/terra41/x86_64/foo-bar-1.0-1.fc41.x86_64.rpm";
+		assert_eq!(locate(path, None, &repos), rpm("terra41", "foo-bar", "x86_64"));
+		assert_eq!(locate(path, Some("terra40"), &repos), rpm("terra40", "foo-bar", "x86_64"));
+		let path = "/terra40/source/foo-2:1.0-1.fc40.src.rpm";
+		assert_eq!(locate(path, None, &repos), rpm("terra40", "foo", "src"));
+		assert_eq!(locate("/terra42/foo-1.0-1.noarch.rpm", None, &repos), None);
+		assert_eq!(locate("/terra41/repodata/repomd.xml", None, &repos), None);
+	}
+}
@@ -0,0 +1,244 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Build and package events for the `/v4/events` stream.
+//!
+//! Events are stored in the `events` table, so that reconnecting clients can resume from
+//! their `Last-Event-ID`, and handed to subscribers through an in-process broadcast channel.
+//! With `events.notify` set, they go through Postgres `NOTIFY` instead, so that subscribers
+//! of every replica see them.
+use crate::config;
+use crate::db::Madoguchi;
+use chrono::NaiveDateTime;
+use rocket::fairing::AdHoc;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::broadcast;
+use rocket_db_pools::Database;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::postgres::PgListener;
+use sqlx::{PgConnection, PgPool};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{error, warn};
+
+const CHANNEL: &str = "madoguchi_events";
+/// Events replayed at most to a resuming client.
+const REPLAY: i64 = 1000;
+
+static BUS: LazyLock<broadcast::Sender<Event>> = LazyLock::new(|| broadcast::channel(1024).0);
+/// Whether events go through `NOTIFY`, set by [`fairing`] when the rocket is built.
+static NOTIFY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[schema(as = EventKind)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+	BuildStarted,
+	BuildFinished,
+	BuildFailed,
+	/// A successful build after a failed one
+	BuildFixed,
+	PackageAdded,
+	PackageRemoved,
+}
+
+impl Kind {
+	const ALL: [Self; 6] = [
+		Self::BuildStarted,
+		Self::BuildFinished,
+		Self::BuildFailed,
+		Self::BuildFixed,
+		Self::PackageAdded,
+		Self::PackageRemoved,
+	];
+
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::BuildStarted => "build_started",
+			Self::BuildFinished => "build_finished",
+			Self::BuildFailed => "build_failed",
+			Self::BuildFixed => "build_fixed",
+			Self::PackageAdded => "package_added",
+			Self::PackageRemoved => "package_removed",
+		}
+	}
+
+	fn parse(s: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|k| k.as_str() == s)
+	}
+}
+
+#[derive(Clone, Debug, Serialize, utoipa::ToSchema)]
+pub struct Event {
+	pub id: i64,
+	pub kind: Kind,
+	pub repo: String,
+	/// The package, unknown for builds of directories madoguchi has never seen
+	pub name: Option<String>,
+	pub data: Value,
+	pub time: NaiveDateTime,
+}
+
+impl Event {
+	/// Whether the event is about `repo` and `name`, where `None` matches everything.
+	pub fn matches(&self, repo: Option<&str>, name: Option<&str>) -> bool {
+		repo.is_none_or(|r| r == self.repo) && name.is_none_or(|n| self.name.as_deref() == Some(n))
+	}
+}
+
+type EventRow = (i64, String, String, Option<String>, Value, NaiveDateTime);
+
+fn from_row((id, kind, repo, name, data, time): EventRow) -> Option<Event> {
+	Some(Event { id, kind: Kind::parse(&kind)?, repo, name, data, time })
+}
+
+fn notify() -> bool {
+	NOTIFY.load(Ordering::Relaxed)
+}
+
+/// Record an event and deliver it to the subscribers.
+///
+/// Errors are only logged: a missing event must never fail the ingestion of a build.
+pub async fn publish(
+	db: &mut PgConnection, kind: Kind, repo: &str, name: Option<&str>, data: Value,
+) {
+	let q = sqlx::query_as::<_, (i64, NaiveDateTime)>(
+		"INSERT INTO events(kind,repo,name,data) VALUES ($1,$2,$3,$4) RETURNING id,time",
+	);
+	let q = q.bind(kind.as_str()).bind(repo).bind(name).bind(&data);
+	let (id, time) = match q.fetch_one(&mut *db).await {
+		Ok(row) => row,
+		Err(err) => {
+			error!(?err, repo, name, kind = kind.as_str(), "Cannot record event");
+			return;
+		},
+	};
+	if notify() {
+		let q = sqlx::query("SELECT pg_notify($1,$2)").bind(CHANNEL).bind(id.to_string());
+		if let Err(err) = q.execute(db).await {
+			error!(?err, id, "Cannot notify event");
+		}
+		return;
+	}
+	let (repo, name) = (repo.to_owned(), name.map(str::to_owned));
+	BUS.send(Event { id, kind, repo, name, data, time }).ok();
+}
+
+/// Whether the last build of `name` on `arch` failed, i.e. a successful build now fixes it.
+pub async fn failing(db: &mut PgConnection, repo: &str, name: &str, arch: &str) -> bool {
+	let q = sqlx::query_scalar::<_, bool>(
+		"SELECT succ FROM builds WHERE (repo,pname,parch)=($1,$2,$3) ORDER BY epoch DESC LIMIT 1",
+	);
+	match q.bind(repo).bind(name).bind(arch).fetch_optional(db).await {
+		Ok(succ) => succ == Some(false),
+		Err(err) => {
+			error!(?err, repo, name, arch, "Cannot find last build");
+			false
+		},
+	}
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+	BUS.subscribe()
+}
+
+/// Events after `after` about `repo` and `name`, oldest first.
+pub async fn since(
+	db: &mut PgConnection, after: i64, repo: Option<&str>, name: Option<&str>,
+) -> sqlx::Result<Vec<Event>> {
+	let rows = sqlx::query_as::<_, EventRow>(
+		"SELECT id,kind,repo,name,data,time FROM events WHERE id>$1
+		AND ($2::text IS NULL OR repo=$2) AND ($3::text IS NULL OR name=$3) ORDER BY id LIMIT $4",
+	)
+	.bind(after)
+	.bind(repo)
+	.bind(name)
+	.bind(REPLAY)
+	.fetch_all(db)
+	.await?;
+	Ok(rows.into_iter().filter_map(from_row).collect())
+}
+
+/// The `Last-Event-ID` header sent by reconnecting `EventSource`s.
+pub struct LastEventId(pub Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+	type Error = std::convert::Infallible;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let id = request.headers().get_one("Last-Event-ID").and_then(|s| s.trim().parse().ok());
+		Outcome::Success(Self(id))
+	}
+}
+
+/// Forward notified events to the local subscribers.
+async fn listen(pool: &PgPool) -> sqlx::Result<()> {
+	let mut listener = PgListener::connect_with(pool).await?;
+	listener.listen(CHANNEL).await?;
+	loop {
+		let notification = listener.recv().await?;
+		let Ok(id) = notification.payload().parse::<i64>() else { continue };
+		let q = sqlx::query_as::<_, EventRow>(
+			"SELECT id,kind,repo,name,data,time FROM events WHERE id=$1",
+		);
+		if let Some(event) = q.bind(id).fetch_optional(pool).await?.and_then(from_row) {
+			BUS.send(event).ok();
+		}
+	}
+}
+
+async fn prune(pool: &PgPool, days: i32) {
+	let q = sqlx::query(
+		"DELETE FROM events WHERE time < (now() AT TIME ZONE 'utc') - make_interval(days => $1)",
+	);
+	if let Err(err) = q.bind(days).execute(pool).await {
+		error!(?err, "Cannot prune events");
+	}
+}
+
+/// Prunes events older than `retention_days` (default 7), and listens for notified events if
+/// `notify` is set.
+pub fn fairing(settings: &config::Events) -> AdHoc {
+	let days = i32::from(settings.retention_days.get());
+	NOTIFY.store(settings.notify, Ordering::Relaxed);
+	AdHoc::on_liftoff("Events", move |rocket| {
+		Box::pin(async move {
+			let Some(db) = Madoguchi::fetch(rocket) else { return };
+			let (pool, mut shutdown) = ((**db).clone(), rocket.shutdown());
+			if notify() {
+				let (pool, mut shutdown) = (pool.clone(), shutdown.clone());
+				rocket::tokio::spawn(async move {
+					loop {
+						rocket::tokio::select! {
+							Err(err) = listen(&pool) => warn!(?err, "Lost event notifications"),
+							() = &mut shutdown => break,
+						}
+						rocket::tokio::time::sleep(Duration::from_secs(5)).await;
+					}
+				});
+			}
+			rocket::tokio::spawn(async move {
+				let mut interval = rocket::tokio::time::interval(Duration::from_hours(1));
+				loop {
+					rocket::tokio::select! {
+						_ = interval.tick() => prune(&pool, days).await,
+						() = &mut shutdown => break,
+					}
+				}
+			});
+		})
+	})
+}
@@ -0,0 +1,182 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Atom feeds of new packages, updates and build failures.
+//!
+//! The version history of a package is the sequence of versions it was first built
+//! successfully with. Entry IDs only depend on the package and version (or build), so readers
+//! never see an entry twice.
+use chrono::{NaiveDateTime, Utc};
+use quick_xml::events::{BytesDecl, BytesText, Event};
+use quick_xml::Writer;
+use sqlx::PgConnection;
+
+const LIMIT: i64 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+	/// First versions of packages
+	New,
+	/// Later versions of packages
+	Updates,
+	/// Failed builds
+	Failures,
+}
+
+impl Kind {
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::New => "new",
+			Self::Updates => "updates",
+			Self::Failures => "failures",
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct Entry {
+	pub id: String,
+	pub title: String,
+	/// Path of the entry's link, relative to the base URL
+	pub link: String,
+	pub updated: NaiveDateTime,
+	pub summary: Option<String>,
+}
+
+type VersionRow = (String, String, Option<String>, NaiveDateTime, Option<String>);
+
+/// Versions of the packages of `repo` (or only of `name`), newest first.
+///
+/// `new` selects first versions if true, later versions if false, and both if `None`.
+async fn versions(
+	db: &mut PgConnection, repo: &str, name: Option<&str>, new: Option<bool>,
+) -> sqlx::Result<Vec<Entry>> {
+	let rows = sqlx::query_as::<_, VersionRow>(
+		"SELECT h.pname,h.pver||'-'||h.prel,h.prev,h.first,m.summary FROM (
+			SELECT pname,pver,prel,first,LAG(pver||'-'||prel) OVER w AS prev FROM (
+				SELECT pname,pver,prel,MIN(epoch) AS first FROM builds
+				WHERE repo=$1 AND succ AND ($2::text IS NULL OR pname=$2) GROUP BY pname,pver,prel
+			) v WINDOW w AS (PARTITION BY pname ORDER BY first)
+		) h LEFT JOIN pkg_meta m ON (m.repo,m.name)=($1,h.pname)
+		WHERE $3::bool IS NULL OR (h.prev IS NULL)=$3 ORDER BY h.first DESC LIMIT $4",
+	)
+	.bind(repo)
+	.bind(name)
+	.bind(new)
+	.bind(LIMIT)
+	.fetch_all(db)
+	.await?;
+	Ok((rows.into_iter())
+		.map(|(name, verrel, prev, updated, summary)| Entry {
+			id: format!("urn:madoguchi:{repo}:{name}:{verrel}"),
+			title: prev.map_or_else(
+				|| format!("{name} {verrel} added"),
+				|prev| format!("{name} updated to {verrel} (from {prev})"),
+			),
+			link: format!("/redirect/{repo}/packages/{name}/v/{verrel}"),
+			updated,
+			summary,
+		})
+		.collect())
+}
+
+type FailureRow = (String, String, String, String, String, NaiveDateTime);
+
+async fn failures(
+	db: &mut PgConnection, repo: &str, name: Option<&str>,
+) -> sqlx::Result<Vec<Entry>> {
+	let rows = sqlx::query_as::<_, FailureRow>(
+		"SELECT id,pname,pver,prel,parch,epoch FROM builds
+		WHERE repo=$1 AND NOT succ AND ($2::text IS NULL OR pname=$2)
+		ORDER BY epoch DESC LIMIT $3",
+	)
+	.bind(repo)
+	.bind(name)
+	.bind(LIMIT)
+	.fetch_all(db)
+	.await?;
+	Ok((rows.into_iter())
+		.map(|(id, name, ver, rel, arch, updated)| Entry {
+			id: format!("urn:madoguchi:{repo}:build:{id}:{name}:{arch}"),
+			title: format!("{name} {ver}-{rel} failed to build on {arch}"),
+			link: format!("/redirect/{repo}/builds/{id}"),
+			updated,
+			summary: None,
+		})
+		.collect())
+}
+
+/// The entries of the `kind` feed of `repo`.
+pub async fn entries(db: &mut PgConnection, repo: &str, kind: Kind) -> sqlx::Result<Vec<Entry>> {
+	match kind {
+		Kind::New => versions(db, repo, None, Some(true)).await,
+		Kind::Updates => versions(db, repo, None, Some(false)).await,
+		Kind::Failures => failures(db, repo, None).await,
+	}
+}
+
+/// The versions and build failures of package `name`, newest first.
+pub async fn package(db: &mut PgConnection, repo: &str, name: &str) -> sqlx::Result<Vec<Entry>> {
+	let mut entries = versions(&mut *db, repo, Some(name), None).await?;
+	entries.extend(failures(db, repo, Some(name)).await?);
+	entries.sort_by_key(|e| std::cmp::Reverse(e.updated));
+	entries.truncate(LIMIT.try_into().unwrap_or_default());
+	Ok(entries)
+}
+
+fn text<W: std::io::Write>(w: &mut Writer<W>, tag: &str, s: &str) -> std::io::Result<()> {
+	w.create_element(tag).write_text_content(BytesText::new(s))?;
+	Ok(())
+}
+
+fn link<W: std::io::Write>(w: &mut Writer<W>, href: &str, rel: &str) -> std::io::Result<()> {
+	w.create_element("link").with_attributes([("href", href), ("rel", rel)]).write_empty()?;
+	Ok(())
+}
+
+fn rfc3339(t: NaiveDateTime) -> String {
+	t.and_utc().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Render an Atom feed. `path` is the feed's own path and links are made absolute with `base`.
+pub fn render(
+	id: &str, title: &str, base: &str, path: &str, entries: &[Entry],
+) -> std::io::Result<String> {
+	let base = base.trim_end_matches('/');
+	let updated = entries.iter().map(|e| e.updated).max();
+	let updated = rfc3339(updated.unwrap_or_else(|| Utc::now().naive_utc()));
+	let mut w = Writer::new_with_indent(Vec::new(), b' ', 2);
+	w.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+	let feed = w.create_element("feed").with_attribute(("xmlns", "http://www.w3.org/2005/Atom"));
+	feed.write_inner_content(|w| {
+		text(w, "id", id)?;
+		text(w, "title", title)?;
+		text(w, "updated", &updated)?;
+		link(w, &format!("{base}{path}"), "self")?;
+		w.create_element("author").write_inner_content(|w| text(w, "name", "madoguchi"))?;
+		for e in entries {
+			w.create_element("entry").write_inner_content(|w| {
+				text(w, "id", &e.id)?;
+				text(w, "title", &e.title)?;
+				text(w, "updated", &rfc3339(e.updated))?;
+				link(w, &format!("{base}{}", e.link), "alternate")?;
+				if let Some(summary) = &e.summary {
+					text(w, "summary", summary)?;
+				}
+				Ok(())
+			})?;
+		}
+		Ok(())
+	})?;
+	String::from_utf8(w.into_inner()).map_err(std::io::Error::other)
+}
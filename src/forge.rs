@@ -0,0 +1,311 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Links to the forge hosting the packaging sources of a repo.
+//!
+//! Each repo has a forge kind which provides default URL templates, and may override any of
+//! them. Templates can use these placeholders:
+//!
+//! - `{repo}`: the repository URL, e.g. `https://github.com/terrapkg/packages`
+//! - `{repo_path}`: the repository URL without scheme and host, e.g. `terrapkg/packages`
+//! - `{owner}`: the first component of `{repo_path}`
+//! - `{ref}`: the commit if known, the repo's branch otherwise
+//! - `{path}`: a path in the repository
+//! - `{commit}`: a commit hash
+//! - `{run}`: a CI run ID
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, utoipa::ToSchema)]
+#[schema(as = ForgeKind)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+	#[default]
+	GitHub,
+	GitLab,
+	#[serde(alias = "gitea")]
+	Forgejo,
+	SourceHut,
+}
+
+impl TryFrom<String> for Kind {
+	type Error = String;
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		Self::parse(&s).ok_or(s)
+	}
+}
+
+impl Kind {
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::GitHub => "github",
+			Self::GitLab => "gitlab",
+			Self::Forgejo => "forgejo",
+			Self::SourceHut => "sourcehut",
+		}
+	}
+
+	pub fn parse(s: &str) -> Option<Self> {
+		serde_json::from_value(serde_json::Value::String(s.to_owned())).ok()
+	}
+
+	/// Name of the CI system, used as link text.
+	pub const fn ci_name(self) -> &'static str {
+		match self {
+			Self::GitHub => "gha",
+			Self::GitLab => "pipeline",
+			Self::Forgejo => "actions",
+			Self::SourceHut => "builds",
+		}
+	}
+
+	/// The default `(tree, raw, commit, run)` templates.
+	#[allow(clippy::literal_string_with_formatting_args)]
+	const fn templates(self) -> [&'static str; 4] {
+		match self {
+			Self::GitHub => [
+				"{repo}/tree/{ref}/{path}",
+				"https://raw.githubusercontent.com/{repo_path}/{ref}/{path}",
+				"{repo}/commit/{commit}",
+				"{repo}/actions/runs/{run}/",
+			],
+			Self::GitLab => [
+				"{repo}/-/tree/{ref}/{path}",
+				"{repo}/-/raw/{ref}/{path}",
+				"{repo}/-/commit/{commit}",
+				"{repo}/-/pipelines/{run}",
+			],
+			Self::Forgejo => [
+				"{repo}/src/{ref}/{path}",
+				"{repo}/raw/{ref}/{path}",
+				"{repo}/commit/{commit}",
+				"{repo}/actions/runs/{run}",
+			],
+			Self::SourceHut => [
+				"{repo}/tree/{ref}/item/{path}",
+				"{repo}/blob/{ref}/{path}",
+				"{repo}/commit/{commit}",
+				"https://builds.sr.ht/{owner}/job/{run}",
+			],
+		}
+	}
+}
+
+/// The forge settings of a repo, as stored in `repos`.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug, Default, utoipa::ToSchema)]
+pub struct Forge {
+	#[sqlx(rename = "forge", try_from = "String")]
+	#[serde(rename = "forge", default)]
+	pub kind: Kind,
+	/// Repository URL. When missing, `gh` is used as a tree URL, as before forges existed.
+	pub url: Option<String>,
+	pub branch: Option<String>,
+	pub tree_url: Option<String>,
+	pub raw_url: Option<String>,
+	pub commit_url: Option<String>,
+	pub run_url: Option<String>,
+	#[serde(skip)]
+	pub gh: String,
+}
+
+impl Forge {
+	/// The `i`th template of `(tree, raw, commit, run)`, possibly overridden.
+	fn template(&self, i: usize) -> &str {
+		let tpls = [&self.tree_url, &self.raw_url, &self.commit_url, &self.run_url];
+		tpls[i].as_deref().unwrap_or_else(|| self.kind.templates()[i])
+	}
+
+	#[allow(clippy::literal_string_with_formatting_args)]
+	fn render(&self, tpl: &str, r#ref: Option<&str>, path: &str, id: &str) -> String {
+		let repo = self.url.as_deref().unwrap_or_default().trim_end_matches('/');
+		let repo_path = repo.split_once("://").map_or(repo, |(_, s)| s);
+		let repo_path = repo_path.split_once('/').map_or("", |(_, s)| s);
+		let r#ref = r#ref.or(self.branch.as_deref()).unwrap_or("HEAD");
+		tpl.replace("{repo_path}", repo_path)
+			.replace("{repo}", repo)
+			.replace("{owner}", repo_path.split('/').next().unwrap_or_default())
+			.replace("{ref}", r#ref)
+			.replace("{path}", path.trim_matches('/'))
+			.replace("{commit}", id)
+			.replace("{run}", id)
+	}
+
+	/// Link to `path` in the tree at `commit`, or the branch if unknown.
+	pub fn tree(&self, commit: Option<&str>, path: &str) -> String {
+		if self.url.is_none() {
+			return format!("{}/{}", self.gh.trim_end_matches('/'), path.trim_matches('/'));
+		}
+		let tpl = self.template(0);
+		self.render(tpl, commit, path, "")
+	}
+
+	/// Link to the raw content of `path` at `commit`, or the branch if unknown.
+	pub fn raw(&self, commit: Option<&str>, path: &str) -> String {
+		if self.url.is_none() {
+			let tree = self.tree(commit, path);
+			return tree.replace("github.com", "raw.githubusercontent.com").replace("/tree/", "/");
+		}
+		let tpl = self.template(1);
+		self.render(tpl, commit, path, "")
+	}
+
+	pub fn commit(&self, commit: &str) -> String {
+		let tpl = self.template(2);
+		self.render(tpl, Some(commit), "", commit)
+	}
+
+	/// Link to CI run `run`.
+	pub fn run(&self, run: &str) -> String {
+		let tpl = self.template(3);
+		self.render(tpl, None, "", run)
+	}
+}
+
+/// Split a GitHub-style tree URL (`https://github.com/o/r/tree/branch`) into repo and branch.
+pub fn split_tree_url(gh: &str) -> Option<(&str, &str)> {
+	let (repo, branch) = gh.trim_end_matches('/').rsplit_once("/tree/")?;
+	(!branch.contains('/')).then_some((repo, branch))
+}
+
+pub async fn fetch(db: &mut PgConnection, repo: &str) -> sqlx::Result<Option<Forge>> {
+	sqlx::query_as(
+		"SELECT forge,url,branch,tree_url,raw_url,commit_url,run_url,gh FROM repos WHERE name=$1",
+	)
+	.bind(repo)
+	.fetch_optional(db)
+	.await
+}
+
+/// Update the forge settings of `repo`. If `forge.url` is missing, it is derived from `gh`.
+pub async fn store(db: &mut PgConnection, repo: &str, forge: &Forge) -> sqlx::Result<()> {
+	let (url, branch) = match (&forge.url, split_tree_url(&forge.gh)) {
+		(Some(url), _) => (Some(url.trim_end_matches('/')), forge.branch.as_deref()),
+		(None, Some((url, branch))) => (Some(url), forge.branch.as_deref().or(Some(branch))),
+		(None, None) => (None, forge.branch.as_deref()),
+	};
+	sqlx::query(
+		"UPDATE repos SET (forge,url,branch,tree_url,raw_url,commit_url,run_url)=($2,$3,$4,$5,$6,$7,$8)
+		WHERE name=$1",
+	)
+	.bind(repo)
+	.bind(forge.kind.as_str())
+	.bind(url)
+	.bind(branch)
+	.bind(&forge.tree_url)
+	.bind(&forge.raw_url)
+	.bind(&forge.commit_url)
+	.bind(&forge.run_url)
+	.execute(db)
+	.await?;
+	Ok(())
+}
+
+/// The forge of `repo` for notification links, logging errors.
+pub async fn fetch_logged(db: &mut PgConnection, repo: &str) -> Option<Forge> {
+	fetch(db, repo).await.unwrap_or_else(|err| {
+		tracing::error!(?err, repo, "Cannot fetch forge");
+		None
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn forge(kind: Kind, url: &str) -> Forge {
+		Forge {
+			kind,
+			url: Some(url.to_owned()),
+			branch: Some("f41".to_owned()),
+			..Forge::default()
+		}
+	}
+
+	#[test]
+	fn templates() {
+		let cases = [
+			(
+				Kind::GitHub,
+				"https://github.com/o/r",
+				[
+					"https://github.com/o/r/tree/f41/anda/x",
+					"https://raw.githubusercontent.com/o/r/abc/anda/x/anda.hcl",
+					"https://github.com/o/r/commit/abc",
+					"https://github.com/o/r/actions/runs/42/",
+				],
+			),
+			(
+				Kind::GitLab,
+				"https://gitlab.com/o/r/",
+				[
+					"https://gitlab.com/o/r/-/tree/f41/anda/x",
+					"https://gitlab.com/o/r/-/raw/abc/anda/x/anda.hcl",
+					"https://gitlab.com/o/r/-/commit/abc",
+					"https://gitlab.com/o/r/-/pipelines/42",
+				],
+			),
+			(
+				Kind::Forgejo,
+				"https://codeberg.org/o/r",
+				[
+					"https://codeberg.org/o/r/src/f41/anda/x",
+					"https://codeberg.org/o/r/raw/abc/anda/x/anda.hcl",
+					"https://codeberg.org/o/r/commit/abc",
+					"https://codeberg.org/o/r/actions/runs/42",
+				],
+			),
+			(
+				Kind::SourceHut,
+				"https://git.sr.ht/~o/r",
+				[
+					"https://git.sr.ht/~o/r/tree/f41/item/anda/x",
+					"https://git.sr.ht/~o/r/blob/abc/anda/x/anda.hcl",
+					"https://git.sr.ht/~o/r/commit/abc",
+					"https://builds.sr.ht/~o/job/42",
+				],
+			),
+		];
+		for (kind, url, [tree, raw, commit, run]) in cases {
+			let f = forge(kind, url);
+			assert_eq!(f.tree(None, "/anda/x/"), tree);
+			assert_eq!(f.raw(Some("abc"), "anda/x/anda.hcl"), raw);
+			assert_eq!(f.commit("abc"), commit);
+			assert_eq!(f.run("42"), run);
+		}
+	}
+
+	#[test]
+	fn overrides() {
+		let f = Forge {
+			tree_url: Some("https://cgit.example/{repo_path}/tree/{path}?h={ref}".to_owned()),
+			..forge(Kind::GitHub, "https://github.com/o/r")
+		};
+		assert_eq!(f.tree(Some("abc"), "anda"), "https://cgit.example/o/r/tree/anda?h=abc");
+		assert_eq!(f.commit("abc"), "https://github.com/o/r/commit/abc");
+		let headless = Forge { branch: None, ..f };
+		assert_eq!(headless.tree(None, ""), "https://cgit.example/o/r/tree/?h=HEAD");
+	}
+
+	#[test]
+	fn gh_fallback() {
+		let f = Forge { gh: "https://github.com/o/r/tree/f41/".to_owned(), ..Forge::default() };
+		assert_eq!(f.tree(None, "/anda/x/"), "https://github.com/o/r/tree/f41/anda/x");
+		assert_eq!(
+			f.raw(None, "anda/x/anda.hcl"),
+			"https://raw.githubusercontent.com/o/r/f41/anda/x/anda.hcl"
+		);
+		assert_eq!(split_tree_url(&f.gh), Some(("https://github.com/o/r", "f41")));
+		assert_eq!(split_tree_url("https://github.com/o/r"), None);
+		assert_eq!(split_tree_url("https://github.com/o/r/tree/a/b"), None);
+	}
+}
@@ -0,0 +1,154 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Liveness and readiness checks.
+//!
+//! `/health/live` only tells that the server answers. `/health/ready` checks a database
+//! round-trip and that the migrations are applied; `health_checks`, a list of `notify` and
+//! `storage`, adds the Discord webhook and the repodata of every repo. It answers
+//! 503 when a check fails, so that the pod stops receiving traffic. The report is public, the
+//! reasons of failures are only logged.
+use crate::config::{self, Config, HealthCheck};
+use crate::db::{Madoguchi, MIGRATOR};
+use crate::repodata;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::tokio::task::JoinSet;
+use rocket::{get, routes, Route, State};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn routes() -> Vec<Route> {
+	routes![version, live, ready]
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+	ok: bool,
+	latency_ms: f64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+	ok: bool,
+	version: &'static str,
+	checks: BTreeMap<&'static str, Check>,
+}
+
+/// Run check `name`. The report is public, so failures are only detailed in the logs.
+async fn check(name: &str, f: impl std::future::Future<Output = Result<(), String>>) -> Check {
+	let start = Instant::now();
+	let error = match rocket::tokio::time::timeout(TIMEOUT, f).await {
+		Ok(Ok(())) => None,
+		Ok(Err(err)) => {
+			error!(check = name, err, "Health check failed");
+			Some("Check failed, see the logs".to_owned())
+		},
+		Err(_) => {
+			error!(check = name, "Health check timed out");
+			Some(format!("No answer within {}s", TIMEOUT.as_secs()))
+		},
+	};
+	Check { ok: error.is_none(), latency_ms: start.elapsed().as_secs_f64() * 1000.0, error }
+}
+
+async fn database(pool: &PgPool) -> Result<(), String> {
+	sqlx::query("SELECT 1").execute(pool).await.map_err(|e| e.to_string())?;
+	Ok(())
+}
+
+async fn migrations(pool: &PgPool) -> Result<(), String> {
+	let q = sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success");
+	let applied = q.fetch_all(pool).await.map_err(|e| e.to_string())?;
+	let pending: Vec<_> = (MIGRATOR.iter())
+		.filter(|m| !applied.contains(&m.version))
+		.map(|m| m.version.to_string())
+		.collect();
+	if pending.is_empty() {
+		return Ok(());
+	}
+	Err(format!("Pending migrations: {}", pending.join(", ")))
+}
+
+async fn notify(url: &str) -> Result<(), String> {
+	// the URL of the webhook is its secret, keep it out of the report
+	let res = reqwest::get(url).await.map_err(|e| e.without_url().to_string())?;
+	res.error_for_status().map_err(|e| e.without_url().to_string())?;
+	Ok(())
+}
+
+/// Fetch the `repomd.xml` of every repo at once, so that the timeout covers the slowest one.
+async fn storage(pool: &PgPool, settings: &config::Repodata) -> Result<(), String> {
+	let q = sqlx::query_as::<_, (String, String)>("SELECT name,link FROM repos ORDER BY name");
+	let mut fetches = JoinSet::new();
+	for (repo, link) in q.fetch_all(pool).await.map_err(|e| e.to_string())? {
+		let base = repodata::base_for(settings, &repo, &link);
+		fetches.spawn(async move {
+			let repomd = repodata::fetch(&base, "repodata/repomd.xml").await;
+			repomd.map(drop).map_err(|e| format!("{repo}: {e}"))
+		});
+	}
+	let mut errors = vec![];
+	while let Some(res) = fetches.join_next().await {
+		match res {
+			Ok(Ok(())) => {},
+			Ok(Err(err)) => errors.push(err),
+			Err(err) => errors.push(err.to_string()),
+		}
+	}
+	if errors.is_empty() {
+		return Ok(());
+	}
+	errors.sort();
+	Err(errors.join("; "))
+}
+
+/// The version of madoguchi.
+#[get("/health")]
+const fn version() -> &'static str {
+	VERSION
+}
+
+#[get("/health/live")]
+fn live() -> Json<serde_json::Value> {
+	Json(serde_json::json!({ "ok": true, "version": VERSION }))
+}
+
+#[get("/health/ready")]
+async fn ready(db: &State<Madoguchi>, config: &State<Config>) -> (Status, Json<Report>) {
+	let pool: &PgPool = db;
+	let mut checks = BTreeMap::new();
+	checks.insert("database", check("database", database(pool)).await);
+	checks.insert("migrations", check("migrations", migrations(pool)).await);
+	for optional in &config.health_checks {
+		match optional {
+			HealthCheck::Notify => {
+				checks.insert("notify", check("notify", notify(&config.discord_webhook.0)).await)
+			},
+			HealthCheck::Storage => {
+				checks.insert("storage", check("storage", storage(pool, &config.repodata)).await)
+			},
+		};
+	}
+	let ok = checks.values().all(|c| c.ok);
+	let status = if ok { Status::Ok } else { Status::ServiceUnavailable };
+	(status, Json(Report { ok, version: VERSION, checks }))
+}
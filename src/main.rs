@@ -13,31 +13,40 @@
 // You should have received a copy of the GNU General Public License along with Madoguchi.
 // If not, see <https://www.gnu.org/licenses/>.
 //
+mod anda;
 mod api;
+mod badge;
+mod config;
 mod db;
-use rocket::{fairing, get, launch, response, routes, Build, Rocket};
+mod downloads;
+mod events;
+mod feeds;
+mod forge;
+mod health;
+mod metalink;
+mod metrics;
+mod mirrors;
+mod notify;
+mod osv;
+mod repodata;
+mod request_id;
+mod rpmver;
+mod tasks;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod updateinfo;
+mod upstream;
+use rocket::figment::Figment;
+use rocket::{fairing, Build, Rocket};
 use rocket_db_pools::Database;
+use rocket_dyn_templates::Template;
 use tracing::{error, info};
 use tracing_subscriber::{prelude::*, EnvFilter, Registry};
 
-#[get("/")]
-fn index() -> response::Redirect {
-	response::Redirect::to("https://terra.fyralabs.com/")
-}
-
-#[get("/health")]
-const fn health() -> &'static str {
-	env!("CARGO_PKG_VERSION")
-}
-
-fn chks() {
-	assert!(std::env::var("JWT_KEY").is_ok(), "JWT_KEY cannot be empty.");
-	assert!(std::env::var("DISCORD_WEBHOOK").is_ok(), "DISCORD_WEBHOOK cannot be empty.");
-}
-
 async fn migrate(rocket: Rocket<Build>) -> fairing::Result {
 	match db::Madoguchi::fetch(&rocket) {
-		Some(db) => match rocket_db_pools::sqlx::migrate!().run(&**db).await {
+		Some(db) => match db::MIGRATOR.run(&**db).await {
 			Ok(()) => Ok(rocket),
 			Err(e) => {
 				error!("Fail to init db: {e}");
@@ -48,31 +57,115 @@ async fn migrate(rocket: Rocket<Build>) -> fairing::Result {
 	}
 }
 
-#[launch]
-fn rocket() -> _ {
-	let _guard = sentry::init((
-		"https://d39dd8eeb873a16483dbc9976d84257a@o271654.ingest.us.sentry.io/4510524119973888",
-		sentry::ClientOptions {
-			release: sentry::release_name!(),
-			// Capture user IPs and potentially sensitive headers when using HTTP server integrations
-			// see https://docs.sentry.io/platforms/rust/data-management/data-collected for more info
-			send_default_pii: true,
-			..Default::default()
+/// Import OSV dumps from local files and match the packages against them, without the server.
+async fn import_osv(figment: &Figment, files: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+	if files.is_empty() {
+		return Err("Missing OSV dumps".into());
+	}
+	let url: String = figment.extract_inner("databases.madoguchi.url")?;
+	let pool = sqlx::PgPool::connect(&url).await?;
+	db::MIGRATOR.run(&pool).await?;
+	let mut db = pool.acquire().await?;
+	for file in files {
+		let dump = rocket::tokio::fs::read(file).await.map_err(|e| format!("{file}: {e}"))?;
+		let imported = osv::import(&mut db, &dump).await.map_err(|e| format!("{file}: {e}"))?;
+		println!("{file}: {imported} advisories imported");
+	}
+	let found = osv::rescan(&mut db).await?;
+	println!("{} new vulnerabilities", found.len());
+	for v in &found {
+		println!("  {} affects {} on {}: {} {}", v.id, v.name, v.repo, v.upstream, v.ver);
+	}
+	Ok(())
+}
+
+fn main() {
+	let dotenv = dotenv::dotenv();
+	let figment = config::figment();
+	let config = match config::load(&figment) {
+		Ok(config) => config,
+		Err(err) => {
+			config::report(&err);
+			std::process::exit(1);
 		},
-	));
-	if let Err(e) = dotenv::dotenv() {
+	};
+	match std::env::args().nth(1).as_deref() {
+		Some("check-config") => {
+			println!("The configuration of profile {} is valid:", figment.profile());
+			println!("{config:#?}");
+			return;
+		},
+		Some("import-osv") => {
+			let files: Vec<_> = std::env::args().skip(2).collect();
+			if let Err(err) = rocket::execute(import_osv(&figment, &files)) {
+				eprintln!("{err}");
+				std::process::exit(1);
+			}
+			return;
+		},
+		_ => {},
+	}
+	// before the runtime starts, and kept until the server stops
+	let _sentry = telemetry::sentry(&config);
+	let otlp = match telemetry::otlp() {
+		Ok(otlp) => otlp,
+		Err(err) => {
+			eprintln!("Cannot export spans: {err}");
+			std::process::exit(1);
+		},
+	};
+	Registry::default()
+		.with(tracing_logfmt::layer().with_filter(EnvFilter::from_default_env()))
+		.with(otlp.as_ref().map(telemetry::layer))
+		.init();
+	if let Err(e) = dotenv {
 		tracing::warn!("Ignoring .env: {e}");
 	}
-	Registry::default().with(EnvFilter::from_default_env()).with(tracing_logfmt::layer()).init();
-	chks();
 	info!("Launching rocket 🚀");
-	rocket::build()
+	let launched = rocket::execute(rocket(figment, &config).launch());
+	// export the last spans
+	if let Err(err) = otlp.map_or(Ok(()), |provider| provider.shutdown()) {
+		error!(%err, "Cannot export spans");
+	}
+	if let Err(err) = launched {
+		error!("{err}");
+		std::process::exit(1);
+	}
+}
+
+fn rocket(figment: Figment, config: &config::Config) -> Rocket<Build> {
+	let webhook = notify::Webhook::new(config);
+	let mut rocket = rocket::custom(figment)
+		.attach(config::fairing())
+		.manage(webhook.clone())
 		.attach(db::Madoguchi::init())
 		.attach(rocket::fairing::AdHoc::try_on_ignite("Migrations", migrate))
-		.mount("/", routes![index, health])
-		.mount("/redirect", api::repology::routes())
-		.mount("/ci", api::ci::routes())
-		.mount("/ci5", api::ci5::routes())
-		.mount("/api", api::v4::routes())
-		.mount("/v4", api::v4::routes())
+		.attach(repodata::fairing(&config.repodata))
+		.attach(upstream::fairing(&config.upstream))
+		.attach(mirrors::fairing(webhook, &config.mirrors, &config.repodata))
+		.attach(events::fairing(&config.events))
+		.attach(request_id::fairing())
+		.attach(metrics::fairing())
+		.attach(Template::fairing());
+	let mounts = [
+		("/", health::routes()),
+		("/", metrics::routes(config)),
+		("/", api::web::routes()),
+		("/redirect", api::repology::routes()),
+		("/badge", api::badge::routes()),
+		("/feeds", api::feeds::routes()),
+		("/ci", api::ci::routes()),
+		("/ci5", api::ci5::routes()),
+		("/api", api::v4::routes()),
+		("/v4", api::v4::routes()),
+		("/v4", api::docs::routes()),
+	];
+	for (base, routes) in mounts {
+		rocket = rocket.mount(base, request_id::traced(routes));
+	}
+	// the pages under `/` keep the HTML error pages
+	for base in ["/redirect", "/badge", "/feeds", "/ci", "/ci5", "/api", "/v4"] {
+		rocket = rocket.register(base, api::problem::catchers());
+	}
+	rocket
 }
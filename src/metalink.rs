@@ -0,0 +1,221 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Metalinks for dnf (`metalink=` in `.repo` files), in the format served by
+//! Fedora's mirror manager.
+//!
+//! A metalink carries the checksums of the primary's `repomd.xml`, so dnf rejects mirrors with
+//! other metadata and fails over to the next one. Mirrors which are behind are left out, the
+//! others are ordered by locality then freshness, and the primary comes last.
+use crate::config::Config;
+use crate::mirrors::{self, Mirror};
+use crate::repodata::{self, parse_repomd, Error};
+use quick_xml::events::{BytesDecl, BytesText, Event};
+use quick_xml::Writer;
+use sha2::{Digest, Sha256, Sha512};
+use sqlx::PgConnection;
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// A URL of `repomd.xml` listed in a metalink.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Resource {
+	pub url: String,
+	pub location: Option<String>,
+}
+
+/// The `repomd.xml` URLs of `repo` for a client in `region`.
+///
+/// `mirrors` are expected freshest first, as returned by [`mirrors::available`]. `{arch}` in
+/// the URLs is replaced by `arch` if given.
+#[allow(clippy::literal_string_with_formatting_args)]
+pub fn resources(
+	repo: &str, arch: Option<&str>, mut mirrors: Vec<Mirror>, primary: &str, region: Option<&str>,
+) -> Vec<Resource> {
+	mirrors.sort_by_key(|m| !m.is_in(region));
+	let url = |base: &str| {
+		let url = format!("{}/repodata/repomd.xml", base.trim_end_matches('/'));
+		match arch {
+			Some(arch) => url.replace("{arch}", arch),
+			None => url,
+		}
+	};
+	let mut res: Vec<_> = (mirrors.into_iter())
+		.map(|m| Resource { url: url(&m.base(repo)), location: m.region })
+		.collect();
+	res.push(Resource { url: url(primary), location: None });
+	res
+}
+
+fn hash<W: std::io::Write>(w: &mut Writer<W>, kind: &str, digest: &str) -> std::io::Result<()> {
+	let e = w.create_element("hash").with_attribute(("type", kind));
+	e.write_text_content(BytesText::new(digest))?;
+	Ok(())
+}
+
+/// Render a metalink for `repomd`, the contents of the primary's `repomd.xml`.
+pub fn render(repomd: &[u8], resources: &[Resource]) -> Result<String, Error> {
+	let md = parse_repomd(repomd)?;
+	let timestamp = md.revision.and_then(|r| r.parse().ok()).or(md.timestamp);
+	let pubdate = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+	let mut w = Writer::new_with_indent(Vec::new(), b' ', 1);
+	w.write_event(Event::Decl(BytesDecl::new("1.0", Some("utf-8"), None)))?;
+	let attrs = [
+		("version", "3.0"),
+		("xmlns", "http://www.metalinker.org/"),
+		("type", "dynamic"),
+		("pubdate", &*pubdate),
+		("generator", "madoguchi"),
+		("xmlns:mm0", "http://fedorahosted.org/mirrormanager"),
+	];
+	w.create_element("metalink").with_attributes(attrs).write_inner_content(|w| {
+		w.create_element("files").write_inner_content(|w| {
+			let file = w.create_element("file").with_attribute(("name", "repomd.xml"));
+			file.write_inner_content(|w| {
+				if let Some(ts) = timestamp {
+					let e = w.create_element("mm0:timestamp");
+					e.write_text_content(BytesText::new(&ts.to_string()))?;
+				}
+				let size = repomd.len().to_string();
+				w.create_element("size").write_text_content(BytesText::new(&size))?;
+				w.create_element("verification").write_inner_content(|w| {
+					hash(w, "sha256", &format!("{:x}", Sha256::digest(repomd)))?;
+					hash(w, "sha512", &format!("{:x}", Sha512::digest(repomd)))
+				})?;
+				let res = w.create_element("resources").with_attribute(("maxconnections", "1"));
+				res.write_inner_content(|w| {
+					for (i, r) in resources.iter().enumerate() {
+						let proto = r.url.split_once("://").map_or("file", |(p, _)| p);
+						let pref = 100usize.saturating_sub(i).max(1).to_string();
+						let mut e = (w.create_element("url"))
+							.with_attributes([("protocol", proto), ("type", proto)]);
+						if let Some(loc) = &r.location {
+							e = e.with_attribute(("location", &*loc.to_uppercase()));
+						}
+						e = e.with_attribute(("preference", &*pref));
+						e.write_text_content(BytesText::new(&r.url))?;
+					}
+					Ok(())
+				})?;
+				Ok(())
+			})?;
+			Ok(())
+		})?;
+		Ok(())
+	})?;
+	String::from_utf8(w.into_inner()).map_err(|e| Error::Io(std::io::Error::other(e)))
+}
+
+type Cached = (Instant, Vec<u8>);
+
+/// The primary's `repomd.xml` by base URL, kept for `metalink.cache_ttl` seconds (default 60).
+static CACHE: LazyLock<RwLock<HashMap<String, Cached>>> = LazyLock::new(RwLock::default);
+
+async fn primary_repomd(base: &str, ttl: Duration) -> Result<Vec<u8>, Error> {
+	// a poisoned cache is bypassed
+	if let Ok(cache) = CACHE.read() {
+		if let Some((at, xml)) = cache.get(base) {
+			if at.elapsed() < ttl {
+				return Ok(xml.clone());
+			}
+		}
+	}
+	let xml = repodata::fetch(base, "repodata/repomd.xml").await?;
+	if let Ok(mut cache) = CACHE.write() {
+		cache.insert(base.to_owned(), (Instant::now(), xml.clone()));
+	}
+	Ok(xml)
+}
+
+/// Whether `arch` may be put in a repodata URL, i.e. is only made of `[A-Za-z0-9_]`.
+pub fn valid_arch(arch: &str) -> bool {
+	!arch.is_empty() && arch.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// The metalink of `repo` for a client in `region`, or `None` if the repo does not exist.
+#[allow(clippy::literal_string_with_formatting_args)]
+pub async fn metalink(
+	db: &mut PgConnection, config: &Config, repo: &str, arch: Option<&str>, region: Option<&str>,
+) -> Result<Option<String>, Error> {
+	let link = sqlx::query_scalar::<_, String>("SELECT link FROM repos WHERE name=$1");
+	let Some(link) = link.bind(repo).fetch_optional(&mut *db).await? else { return Ok(None) };
+	let base = repodata::base_for(&config.repodata, repo, &link);
+	let base = match arch {
+		Some(arch) => base.replace("{arch}", arch),
+		None => base,
+	};
+	let ttl = Duration::from_secs(config.metalink.cache_ttl);
+	let repomd = primary_repomd(&base, ttl).await?;
+	let mirrors = mirrors::available(db, repo).await?;
+	render(&repomd, &resources(repo, arch, mirrors, &link, region)).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const REPOMD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo">
+  <revision>1700000000</revision>
+</repomd>
+"#;
+
+	#[test]
+	fn arches() {
+		assert!(["x86_64", "aarch64", "i686", "noarch", "ppc64le"].into_iter().all(valid_arch));
+		assert!(!["", "../x86_64", "x86_64/..", "x86%2F64", "x86 64", "{arch}"]
+			.into_iter()
+			.any(valid_arch));
+	}
+
+	#[test]
+	fn metalink_xml() {
+		let mirror = |url: &str, region: &str| Mirror {
+			name: url.to_owned(),
+			url: url.to_owned(),
+			region: Some(region.to_owned()),
+			repos: vec![],
+			contact: None,
+		};
+		let mirrors = vec![mirror("https://us.example/", "us"), mirror("https://de.example", "de")];
+		let primary = "https://repos.example/terra/{arch}";
+		let res = resources("terra", Some("x86_64"), mirrors, primary, Some("de"));
+		let xml = render(REPOMD.as_bytes(), &res).unwrap();
+		let (head, rest) = xml.split_once(" pubdate=\"").unwrap();
+		let xml = format!("{head}{}", &rest[rest.find('"').unwrap() + 1..]);
+		let (sha256, sha512) = (Sha256::digest(REPOMD), Sha512::digest(REPOMD));
+		let expected = format!(
+			r#"<?xml version="1.0" encoding="utf-8"?>
+<metalink version="3.0" xmlns="http://www.metalinker.org/" type="dynamic" generator="madoguchi" xmlns:mm0="http://fedorahosted.org/mirrormanager">
+ <files>
+  <file name="repomd.xml">
+   <mm0:timestamp>1700000000</mm0:timestamp>
+   <size>{}</size>
+   <verification>
+    <hash type="sha256">{sha256:x}</hash>
+    <hash type="sha512">{sha512:x}</hash>
+   </verification>
+   <resources maxconnections="1">
+    <url protocol="https" type="https" location="DE" preference="100">https://de.example/terra/repodata/repomd.xml</url>
+    <url protocol="https" type="https" location="US" preference="99">https://us.example/terra/repodata/repomd.xml</url>
+    <url protocol="https" type="https" preference="98">https://repos.example/terra/x86_64/repodata/repomd.xml</url>
+   </resources>
+  </file>
+ </files>
+</metalink>"#,
+			REPOMD.len()
+		);
+		assert_eq!(xml, expected);
+	}
+}
@@ -0,0 +1,254 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Prometheus metrics, served at `/metrics` in the text exposition format.
+//!
+//! Requests, ingested builds and notifications are counted in-process; the pool usage and the
+//! failing packages are read when scraped. With `metrics_token` configured, scrapers must send
+//! it as a bearer token. With `metrics_address` (e.g. `127.0.0.1:9100`), `/metrics` is only
+//! served on that address instead of alongside the API.
+use crate::config::Config;
+use crate::db::Madoguchi;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{get, routes, Data, Request, Response, Route};
+use rocket_db_pools::Database;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::Instant;
+use tracing::{error, info};
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+	buckets: [u64; BUCKETS.len()],
+	sum: f64,
+	count: u64,
+}
+
+impl Histogram {
+	fn observe(&mut self, secs: f64) {
+		for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+			if secs <= le {
+				*bucket += 1;
+			}
+		}
+		self.sum += secs;
+		self.count += 1;
+	}
+}
+
+/// Values of a metric by their labels
+type Family<L, V> = LazyLock<Mutex<BTreeMap<L, V>>>;
+
+/// By method, route and status
+static REQUESTS: Family<(String, String, u16), Histogram> = LazyLock::new(Mutex::default);
+/// By repo, arch and result
+static BUILDS: Family<(String, String, &str), u64> = LazyLock::new(Mutex::default);
+/// By sink and result
+static NOTIFICATIONS: Family<(&str, &str), u64> = LazyLock::new(Mutex::default);
+static POOL: OnceLock<PgPool> = OnceLock::new();
+
+/// Count a build of `repo` on `arch` ingested from CI.
+pub fn build(repo: &str, arch: &str, succ: bool) {
+	let result = if succ { "success" } else { "failure" };
+	if let Ok(mut builds) = BUILDS.lock() {
+		*builds.entry((repo.to_owned(), arch.to_owned(), result)).or_default() += 1;
+	}
+}
+
+/// Count a notification sent through `sink`.
+pub fn notification(sink: &'static str, sent: bool) {
+	let result = if sent { "sent" } else { "failed" };
+	if let Ok(mut notifications) = NOTIFICATIONS.lock() {
+		*notifications.entry((sink, result)).or_default() += 1;
+	}
+}
+
+fn label(s: &str) -> String {
+	s.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	_ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn render_requests(out: &mut String) {
+	let Ok(requests) = REQUESTS.lock() else { return };
+	header(out, "madoguchi_http_requests_total", "counter", "HTTP requests by route and status.");
+	for ((method, route, status), h) in requests.iter() {
+		let (method, route) = (label(method), label(route));
+		let labels = format!(r#"method="{method}",route="{route}",status="{status}""#);
+		_ = writeln!(out, "madoguchi_http_requests_total{{{labels}}} {}", h.count);
+	}
+	let name = "madoguchi_http_request_duration_seconds";
+	header(out, name, "histogram", "Latency of HTTP requests by route and status.");
+	for ((method, route, status), h) in requests.iter() {
+		let (method, route) = (label(method), label(route));
+		let labels = format!(r#"method="{method}",route="{route}",status="{status}""#);
+		for (le, n) in BUCKETS.iter().zip(h.buckets) {
+			_ = writeln!(out, r#"{name}_bucket{{{labels},le="{le}"}} {n}"#);
+		}
+		_ = writeln!(out, r#"{name}_bucket{{{labels},le="+Inf"}} {}"#, h.count);
+		_ = writeln!(out, "{name}_sum{{{labels}}} {}", h.sum);
+		_ = writeln!(out, "{name}_count{{{labels}}} {}", h.count);
+	}
+}
+
+fn render_counters(out: &mut String) {
+	if let Ok(builds) = BUILDS.lock() {
+		let name = "madoguchi_builds_ingested_total";
+		header(out, name, "counter", "Builds reported by CI by repo, arch and result.");
+		for ((repo, arch, result), n) in builds.iter() {
+			let (repo, arch) = (label(repo), label(arch));
+			_ = writeln!(out, r#"{name}{{repo="{repo}",arch="{arch}",result="{result}"}} {n}"#);
+		}
+	}
+	if let Ok(notifications) = NOTIFICATIONS.lock() {
+		let name = "madoguchi_notifications_total";
+		header(out, name, "counter", "Notifications by sink and result.");
+		for ((sink, result), n) in notifications.iter() {
+			_ = writeln!(out, r#"{name}{{sink="{sink}",result="{result}"}} {n}"#);
+		}
+	}
+}
+
+async fn render_db(out: &mut String, pool: &PgPool) -> sqlx::Result<()> {
+	let (size, idle) = (pool.size(), pool.num_idle());
+	let name = "madoguchi_db_pool_connections";
+	header(out, name, "gauge", "Connections of the database pool by state.");
+	_ = writeln!(out, r#"{name}{{state="idle"}} {idle}"#);
+	_ = writeln!(out, r#"{name}{{state="busy"}} {}"#, (size as usize).saturating_sub(idle));
+	let name = "madoguchi_db_pool_max_connections";
+	header(out, name, "gauge", "Maximum connections of the database pool.");
+	_ = writeln!(out, "{name} {}", pool.options().get_max_connections());
+	let failing = sqlx::query_as::<_, (String, i64)>(
+		"SELECT r.name,(SELECT COUNT(*) FROM (SELECT DISTINCT ON (pname) succ FROM builds
+			WHERE repo=r.name ORDER BY pname, epoch DESC) b WHERE NOT succ)
+		FROM repos r ORDER BY r.name",
+	)
+	.fetch_all(pool)
+	.await?;
+	let name = "madoguchi_failing_packages";
+	header(out, name, "gauge", "Packages whose last build failed, by repo.");
+	for (repo, n) in failing {
+		_ = writeln!(out, r#"{name}{{repo="{}"}} {n}"#, label(&repo));
+	}
+	Ok(())
+}
+
+/// The bearer token of `metrics_token`, if it is configured.
+pub struct MetricsAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAuth {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let Some(config) = request.rocket().state::<Config>() else {
+			error!("The configuration is not managed");
+			return Outcome::Error((Status::InternalServerError, ()));
+		};
+		let Some(token) = &config.metrics_token else { return Outcome::Success(Self) };
+		let auth = request.headers().get_one("Authorization");
+		match auth.and_then(|s| s.strip_prefix("Bearer ")) {
+			Some(given) if given == token.0 => Outcome::Success(Self),
+			_ => Outcome::Error((Status::Unauthorized, ())),
+		}
+	}
+}
+
+#[get("/metrics")]
+async fn metrics(_auth: MetricsAuth) -> Result<(ContentType, String), Status> {
+	let mut out = String::new();
+	render_requests(&mut out);
+	render_counters(&mut out);
+	if let Some(pool) = POOL.get() {
+		if let Err(err) = render_db(&mut out, pool).await {
+			error!(?err, "Cannot count failing packages");
+			return Err(Status::InternalServerError);
+		}
+	}
+	Ok((ContentType::new("text", "plain").with_params(("version", "0.0.4")), out))
+}
+
+/// `/metrics`, unless it is served on `metrics_address`.
+pub fn routes(config: &Config) -> Vec<Route> {
+	if config.metrics_address.is_some() {
+		return vec![];
+	}
+	routes![metrics]
+}
+
+/// Measures the requests.
+pub struct Requests;
+
+#[rocket::async_trait]
+impl Fairing for Requests {
+	fn info(&self) -> Info {
+		Info { name: "Request metrics", kind: Kind::Request | Kind::Response }
+	}
+
+	async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+		req.local_cache(Instant::now);
+	}
+
+	async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+		let secs = req.local_cache(Instant::now).elapsed().as_secs_f64();
+		let route = req.route().map_or_else(|| "unmatched".to_owned(), |r| r.uri.to_string());
+		let labels = (req.method().to_string(), route, res.status().code);
+		if let Ok(mut requests) = REQUESTS.lock() {
+			requests.entry(labels).or_default().observe(secs);
+		}
+	}
+}
+
+/// Measures the requests, and serves `/metrics` on `metrics_address` if it is configured.
+pub fn fairing() -> AdHoc {
+	AdHoc::on_ignite("Metrics", |rocket| async {
+		let rocket = rocket.attach(Requests);
+		rocket.attach(AdHoc::on_liftoff("Metrics server", |rocket| {
+			Box::pin(async move {
+				if let Some(db) = Madoguchi::fetch(rocket) {
+					POOL.set((**db).clone()).ok();
+				}
+				let Some(config) = rocket.state::<Config>() else { return };
+				let Some(addr) = config.metrics_address else { return };
+				let figment = rocket::Config::figment()
+					.merge(("address", addr.ip()))
+					.merge(("port", addr.port()));
+				let server =
+					rocket::custom(figment).manage(config.clone()).mount("/", routes![metrics]);
+				let server = match server.ignite().await {
+					Ok(server) => server,
+					Err(err) => {
+						error!(%err, %addr, "Cannot serve metrics");
+						return;
+					},
+				};
+				let (stop, shutdown) = (server.shutdown(), rocket.shutdown());
+				rocket::tokio::spawn(async move {
+					shutdown.await;
+					stop.notify();
+				});
+				info!(%addr, "Serving metrics");
+				rocket::tokio::spawn(server.launch());
+			})
+		}))
+	})
+}
@@ -0,0 +1,429 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Download mirrors of the published repos.
+//!
+//! Mirrors are registered in `mirrors`. Every `mirrors.check_interval` seconds (default 5
+//! minutes), the `repomd.xml` of each repo on each mirror is compared with the primary's and the
+//! result is kept in `mirror_status`. A mirror is behind when it is unreachable or lags more than
+//! `mirrors.max_lag` seconds (default 6 hours); such mirrors are not used for redirects.
+use crate::config::{self, Config};
+use crate::notify::{send_webhook, Webhook};
+use crate::repodata::{self, parse_repomd, RepoMd};
+use crate::tasks::periodic;
+use chrono::NaiveDateTime;
+use rocket::fairing::AdHoc;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::{error, warn};
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, utoipa::ToSchema)]
+pub struct Mirror {
+	#[serde(skip_deserializing)]
+	pub name: String,
+	pub url: String,
+	#[serde(default)]
+	pub region: Option<String>,
+	/// Repos carried by the mirror, empty for all of them.
+	#[serde(default)]
+	pub repos: Vec<String>,
+	#[serde(default)]
+	pub contact: Option<String>,
+}
+
+impl Mirror {
+	/// Base URL of `repo` on this mirror.
+	#[allow(clippy::literal_string_with_formatting_args)]
+	pub fn base(&self, repo: &str) -> String {
+		let url = self.url.trim_end_matches('/');
+		if url.contains("{repo}") {
+			url.replace("{repo}", repo)
+		} else {
+			format!("{url}/{repo}")
+		}
+	}
+
+	pub fn is_in(&self, region: Option<&str>) -> bool {
+		region.is_some_and(|r| self.region.as_deref().is_some_and(|mr| mr.eq_ignore_ascii_case(r)))
+	}
+
+	pub fn carries(&self, repo: &str) -> bool {
+		self.repos.is_empty() || self.repos.iter().any(|r| r == repo)
+	}
+}
+
+pub async fn list(db: &mut PgConnection) -> sqlx::Result<Vec<Mirror>> {
+	sqlx::query_as("SELECT * FROM mirrors ORDER BY name").fetch_all(db).await
+}
+
+/// Add or update mirror `name`; returns whether it was added.
+pub async fn store(db: &mut PgConnection, name: &str, m: &Mirror) -> sqlx::Result<bool> {
+	sqlx::query_scalar(
+		"INSERT INTO mirrors(name,url,region,repos,contact) VALUES ($1,$2,$3,$4,$5)
+		ON CONFLICT (name) DO UPDATE SET (url,region,repos,contact)=($2,$3,$4,$5)
+		RETURNING xmax = 0",
+	)
+	.bind(name)
+	.bind(m.url.trim_end_matches('/'))
+	.bind(&m.region)
+	.bind(&m.repos)
+	.bind(&m.contact)
+	.fetch_one(db)
+	.await
+}
+
+/// Remove mirror `name` and its status; returns whether it existed.
+pub async fn delete(db: &mut PgConnection, name: &str) -> sqlx::Result<bool> {
+	let res = sqlx::query("DELETE FROM mirrors WHERE name=$1").bind(name).execute(db).await?;
+	Ok(res.rows_affected() != 0)
+}
+
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	sqlx::query("DELETE FROM mirror_status WHERE repo=$1").bind(repo).execute(db).await?;
+	Ok(())
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Pick one of `mirrors`, preferring those in `region`.
+///
+/// Requests are spread over the candidates in turn.
+pub fn pick<'a>(mirrors: &'a [Mirror], region: Option<&str>) -> Option<&'a Mirror> {
+	let local: Vec<_> = mirrors.iter().filter(|m| m.is_in(region)).collect();
+	let candidates = if local.is_empty() { mirrors.iter().collect() } else { local };
+	if candidates.is_empty() {
+		return None;
+	}
+	Some(candidates[NEXT.fetch_add(1, Ordering::Relaxed) % candidates.len()])
+}
+
+/// Mirrors carrying `repo` that are not behind, freshest first; unchecked mirrors are assumed
+/// up to date and come last.
+pub async fn available(db: &mut PgConnection, repo: &str) -> sqlx::Result<Vec<Mirror>> {
+	sqlx::query_as(
+		"SELECT m.* FROM mirrors m LEFT JOIN mirror_status s ON (s.mirror,s.repo)=(m.name,$1)
+		WHERE (cardinality(m.repos)=0 OR $1=ANY(m.repos)) AND NOT COALESCE(s.behind, FALSE)
+		ORDER BY s.lag NULLS LAST, m.name",
+	)
+	.bind(repo)
+	.fetch_all(db)
+	.await
+}
+
+/// URL of `path` in `repo`, on a mirror if possible, otherwise on the primary (`repos.link`).
+pub async fn url_for(
+	db: &mut PgConnection, repo: &str, path: &str, region: Option<&str>,
+) -> sqlx::Result<Option<String>> {
+	let mirrors = available(&mut *db, repo).await?;
+	if let Some(m) = pick(&mirrors, region) {
+		return Ok(Some(format!("{}/{path}", m.base(repo))));
+	}
+	let link = sqlx::query_scalar::<_, String>("SELECT link FROM repos WHERE name=$1");
+	let link = link.bind(repo).fetch_optional(db).await?;
+	Ok(link.map(|l| format!("{}/{path}", l.trim_end_matches('/'))))
+}
+
+/// The region of the client, from the header named by `mirrors.region_header`
+/// (default `CF-IPCountry`).
+pub struct Region(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Region {
+	type Error = std::convert::Infallible;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let Some(config) = request.rocket().state::<Config>() else {
+			return Outcome::Success(Self(None));
+		};
+		let region = request.headers().get_one(&config.mirrors.region_header);
+		Outcome::Success(Self(region.map(str::to_owned)))
+	}
+}
+
+/// The state of a repo on a mirror.
+#[derive(sqlx::FromRow, Serialize, Debug, Clone, Default, PartialEq, Eq, utoipa::ToSchema)]
+pub struct Check {
+	pub revision: Option<String>,
+	pub timestamp: Option<i64>,
+	/// Seconds behind the primary, if known
+	pub lag: Option<i64>,
+	pub behind: bool,
+	pub error: Option<String>,
+}
+
+/// Compare the `repomd.xml` of a mirror with the primary's.
+///
+/// The lag is zero if the revisions match, otherwise the difference of the timestamps. Without
+/// timestamps, a mirror with another revision is considered behind.
+pub fn compare(primary: &RepoMd, mirror: &RepoMd, max_lag: i64) -> Check {
+	let same = primary.revision.is_some() && primary.revision == mirror.revision;
+	let lag = if same {
+		Some(0)
+	} else {
+		primary.timestamp.zip(mirror.timestamp).map(|(p, m)| (p - m).max(0))
+	};
+	Check {
+		revision: mirror.revision.clone(),
+		timestamp: mirror.timestamp,
+		behind: lag.map_or(!same, |lag| lag > max_lag),
+		lag,
+		error: None,
+	}
+}
+
+async fn fetch_repomd(client: &reqwest::Client, base: &str) -> Result<RepoMd, repodata::Error> {
+	let url = format!("{}/repodata/repomd.xml", base.trim_end_matches('/'));
+	let xml = client.get(&url).send().await?.error_for_status()?.bytes().await?;
+	parse_repomd(&xml)
+}
+
+/// Check the repo at `base` on a mirror against the `primary` metadata.
+pub async fn check(client: &reqwest::Client, primary: &RepoMd, base: &str, max_lag: i64) -> Check {
+	match fetch_repomd(client, base).await {
+		Ok(md) => compare(primary, &md, max_lag),
+		Err(err) => {
+			warn!(%err, base, "Mirror is unreachable");
+			Check { behind: true, error: Some(err.to_string()), ..Check::default() }
+		},
+	}
+}
+
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+pub struct RepoStatus {
+	#[serde(flatten)]
+	pub check: Check,
+	pub checked: NaiveDateTime,
+}
+
+/// A mirror and the state of each repo it carries, without its contact.
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+pub struct MirrorStatus {
+	pub name: String,
+	pub url: String,
+	pub region: Option<String>,
+	pub repos: BTreeMap<String, RepoStatus>,
+}
+
+#[derive(sqlx::FromRow)]
+struct StatusRow {
+	mirror: String,
+	repo: String,
+	#[sqlx(flatten)]
+	check: Check,
+	checked: NaiveDateTime,
+}
+
+/// The last check results of all mirrors.
+pub async fn status(db: &mut PgConnection) -> sqlx::Result<Vec<MirrorStatus>> {
+	let mut rows: HashMap<_, BTreeMap<_, _>> = HashMap::new();
+	for r in
+		sqlx::query_as::<_, StatusRow>("SELECT * FROM mirror_status").fetch_all(&mut *db).await?
+	{
+		let status = RepoStatus { check: r.check, checked: r.checked };
+		rows.entry(r.mirror).or_default().insert(r.repo, status);
+	}
+	Ok((list(db).await?.into_iter())
+		.map(|m| MirrorStatus {
+			repos: rows.remove(&m.name).unwrap_or_default(),
+			name: m.name,
+			url: m.url,
+			region: m.region,
+		})
+		.collect())
+}
+
+async fn record(db: &PgPool, mirror: &str, repo: &str, c: &Check) -> sqlx::Result<()> {
+	sqlx::query(
+		"INSERT INTO mirror_status(mirror,repo,revision,timestamp,lag,behind,checked,error)
+		VALUES ($1,$2,$3,$4,$5,$6,$7,$8) ON CONFLICT (mirror,repo)
+		DO UPDATE SET (revision,timestamp,lag,behind,checked,error)=($3,$4,$5,$6,$7,$8)",
+	)
+	.bind(mirror)
+	.bind(repo)
+	.bind(&c.revision)
+	.bind(c.timestamp)
+	.bind(c.lag)
+	.bind(c.behind)
+	.bind(chrono::Utc::now().naive_utc())
+	.bind(&c.error)
+	.execute(db)
+	.await?;
+	Ok(())
+}
+
+async fn notify_behind(webhook: &Webhook, m: &Mirror, repo: &str, c: &Check) {
+	let why = match (&c.error, c.lag) {
+		(Some(err), _) => format!("is unreachable: {err}"),
+		(None, Some(lag)) => format!("is {}h{:02}m behind", lag / 3600, lag % 3600 / 60),
+		(None, None) => format!("serves revision {}", c.revision.as_deref().unwrap_or("?")),
+	};
+	let contact = m.contact.as_deref().map(|c| format!(" (contact: {c})")).unwrap_or_default();
+	let msg = format!(":turtle: Mirror **{}** of **{repo}** {why}{contact}", m.name);
+	send_webhook(webhook, msg).await;
+}
+
+async fn check_all(
+	pool: &PgPool, client: &reqwest::Client, webhook: &Webhook, settings: &config::Mirrors,
+	repodata: &config::Repodata,
+) {
+	let repos = sqlx::query_as::<_, (String, String)>("SELECT name,link FROM repos");
+	let repos = match repos.fetch_all(pool).await {
+		Ok(repos) => repos,
+		Err(err) => return error!(?err, "Cannot list repos for mirror check"),
+	};
+	let mirrors = sqlx::query_as::<_, Mirror>("SELECT * FROM mirrors").fetch_all(pool).await;
+	let mirrors = match mirrors {
+		Ok(mirrors) => mirrors,
+		Err(err) => return error!(?err, "Cannot list mirrors"),
+	};
+	let behind =
+		sqlx::query_as::<_, (String, String)>("SELECT mirror,repo FROM mirror_status WHERE behind");
+	let behind: HashSet<_> = match behind.fetch_all(pool).await {
+		Ok(behind) => behind.into_iter().collect(),
+		Err(err) => return error!(?err, "Cannot read mirror status"),
+	};
+	let max_lag = i64::from(settings.max_lag);
+	for (repo, link) in repos {
+		let carriers: Vec<_> = mirrors.iter().filter(|m| m.carries(&repo)).collect();
+		if carriers.is_empty() {
+			continue;
+		}
+		let base = repodata::base_for(repodata, &repo, &link);
+		let primary = match repodata::fetch(&base, "repodata/repomd.xml").await {
+			Ok(xml) => parse_repomd(&xml),
+			Err(err) => Err(err),
+		};
+		let primary = match primary {
+			Ok(md) => md,
+			Err(err) => {
+				error!(%err, repo, "Cannot read primary repomd.xml");
+				continue;
+			},
+		};
+		for m in carriers {
+			let c = check(client, &primary, &m.base(&repo), max_lag).await;
+			if let Err(err) = record(pool, &m.name, &repo, &c).await {
+				error!(?err, mirror = m.name, repo, "Cannot record mirror status");
+				continue;
+			}
+			if c.behind && !behind.contains(&(m.name.clone(), repo.clone())) {
+				notify_behind(webhook, m, &repo, &c).await;
+			}
+		}
+	}
+}
+
+/// Periodically check the registered mirrors, notifying `webhook` of those falling behind.
+pub fn fairing(webhook: Webhook, settings: &config::Mirrors, repodata: &config::Repodata) -> AdHoc {
+	let interval = Duration::from_secs(settings.check_interval.get());
+	let (settings, repodata) = (settings.clone(), repodata.clone());
+	periodic("Mirror check", Some(interval), move |pool| {
+		let (webhook, settings, repodata) = (webhook.clone(), settings.clone(), repodata.clone());
+		async move {
+			match reqwest::Client::builder().timeout(Duration::from_secs(10)).build() {
+				Ok(client) => check_all(&pool, &client, &webhook, &settings, &repodata).await,
+				Err(err) => error!(%err, "Cannot check mirrors"),
+			}
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::serve;
+
+	fn repomd(revision: &str, timestamp: i64) -> String {
+		format!(
+			r#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo">
+  <revision>{revision}</revision>
+  <data type="primary">
+    <location href="repodata/primary.xml.gz"/>
+    <timestamp>{timestamp}</timestamp>
+  </data>
+</repomd>"#
+		)
+	}
+
+	fn mirror(url: &str, region: Option<&str>) -> Mirror {
+		Mirror {
+			name: url.to_owned(),
+			url: url.to_owned(),
+			region: region.map(str::to_owned),
+			repos: vec![],
+			contact: None,
+		}
+	}
+
+	#[rocket::async_test]
+	async fn staleness() {
+		let primary = RepoMd {
+			revision: Some("r2".to_owned()),
+			timestamp: Some(1_760_007_200),
+			primary: None,
+		};
+		let base = serve(vec![
+			("/fresh/repodata/repomd.xml", repomd("r2", 1_760_007_200)),
+			("/lagging/repodata/repomd.xml", repomd("r1", 1_760_000_000)),
+			("/ancient/repodata/repomd.xml", repomd("r0", 1_700_000_000)),
+		])
+		.await;
+		let client = reqwest::Client::new();
+
+		let fresh = check(&client, &primary, &format!("{base}/fresh"), 3600).await;
+		assert_eq!(fresh.lag, Some(0));
+		assert!(!fresh.behind);
+
+		let lagging = check(&client, &primary, &format!("{base}/lagging"), 3600).await;
+		assert_eq!(lagging.revision.as_deref(), Some("r1"));
+		assert_eq!(lagging.lag, Some(7200));
+		assert!(lagging.behind);
+		let lagging = check(&client, &primary, &format!("{base}/lagging"), 8000).await;
+		assert!(!lagging.behind);
+
+		let ancient = check(&client, &primary, &format!("{base}/ancient"), 3600).await;
+		assert_eq!(ancient.lag, Some(60_007_200));
+		assert!(ancient.behind);
+
+		let gone = check(&client, &primary, &format!("{base}/gone"), 3600).await;
+		assert!(gone.behind);
+		assert!(gone.error.is_some());
+		assert_eq!(gone.lag, None);
+	}
+
+	#[test]
+	fn without_timestamps() {
+		let md = |r: &str| RepoMd { revision: Some(r.to_owned()), ..RepoMd::default() };
+		assert!(!compare(&md("a"), &md("a"), 0).behind);
+		let c = compare(&md("b"), &md("a"), 0);
+		assert!(c.behind);
+		assert_eq!(c.lag, None);
+	}
+
+	#[test]
+	fn picking() {
+		let mirrors = [mirror("https://a", Some("DE")), mirror("https://b", Some("US"))];
+		assert_eq!(pick(&mirrors, Some("us")).unwrap().url, "https://b");
+		let picks: HashSet<_> =
+			(0..4).map(|_| pick(&mirrors, Some("JP")).unwrap().url.clone()).collect();
+		assert_eq!(picks.len(), 2);
+		assert_eq!(pick(&[], None), None);
+		assert_eq!(mirrors[0].base("terra41"), "https://a/terra41");
+		assert_eq!(mirror("https://c/{repo}/os", None).base("terra41"), "https://c/terra41/os");
+	}
+}
@@ -0,0 +1,42 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+use crate::config::Config;
+use crate::metrics;
+use std::sync::Arc;
+use webhook::client::WebhookClient;
+
+/// The client of the `discord_webhook` of the configuration, managed by Rocket.
+#[derive(Clone)]
+pub struct Webhook(Arc<WebhookClient>);
+
+impl Webhook {
+	pub fn new(config: &Config) -> Self {
+		Self(Arc::new(WebhookClient::new(&config.discord_webhook.0)))
+	}
+}
+
+pub async fn send_webhook(webhook: &Webhook, s: String) {
+	let msg = webhook.0.send(|msg| {
+		msg.username("Terra Webhook (mg)")
+			.avatar_url("https://avatars.githubusercontent.com/u/114906088")
+			.content(&s)
+	});
+	match msg.await {
+		Ok(_) => metrics::notification("discord", true),
+		Err(err) => {
+			tracing::warn!(err, "Cannot send webhook");
+			metrics::notification("discord", false);
+		},
+	}
+}
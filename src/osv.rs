@@ -0,0 +1,448 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Security advisories in the [OSV format](https://ossf.github.io/osv-schema/).
+//!
+//! Advisories are imported from OSV JSON dumps, uploaded to the API or read from local files
+//! with `madoguchi import-osv <file>...`, and matched against the packaged versions in
+//! `pkgs`. Only advisories concerning a package we ship are kept (in `osv`), the matches are
+//! stored in `vulnerabilities` and recomputed by [`rescan`].
+use crate::db::Pkg;
+use crate::notify::{send_webhook, Webhook};
+use crate::rpmver::rpmvercmp;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, Connection, PgConnection};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Ecosystems whose advisories are about distro packages, not upstream projects.
+///
+/// They are only considered for packages with an explicit upstream name mapping.
+const DISTROS: &[&str] = &[
+	"AlmaLinux",
+	"Alpine",
+	"Debian",
+	"Mageia",
+	"openSUSE",
+	"Photon OS",
+	"Red Hat",
+	"Rocky Linux",
+	"SUSE",
+	"Ubuntu",
+	"Wolfi",
+];
+
+/// Package name prefixes used by Fedora packaging guidelines for language ecosystems.
+const PREFIXES: &[(&str, &str)] =
+	&[("python3-", "PyPI"), ("rust-", "crates.io"), ("nodejs-", "npm"), ("rubygem-", "RubyGems")];
+
+#[derive(Deserialize, Debug)]
+pub struct Vuln {
+	pub id: String,
+	#[serde(default)]
+	pub summary: String,
+	#[serde(default)]
+	pub aliases: Vec<String>,
+	#[serde(default)]
+	pub affected: Vec<Affected>,
+	#[serde(default)]
+	pub severity: Vec<Severity>,
+	#[serde(default)]
+	pub database_specific: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Affected {
+	pub package: Option<Package>,
+	#[serde(default)]
+	pub ranges: Vec<Range>,
+	#[serde(default)]
+	pub versions: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Package {
+	pub ecosystem: String,
+	pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Range {
+	#[serde(rename = "type")]
+	pub kind: String,
+	#[serde(default)]
+	pub events: Vec<Event>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Event {
+	pub introduced: Option<String>,
+	pub fixed: Option<String>,
+	pub last_affected: Option<String>,
+	pub limit: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Severity {
+	#[serde(rename = "type")]
+	pub kind: String,
+	pub score: String,
+}
+
+/// A packaged version affected by an advisory.
+#[derive(sqlx::FromRow, Serialize, Debug, utoipa::ToSchema)]
+pub struct Vulnerability {
+	pub id: String,
+	pub repo: String,
+	pub name: String,
+	pub ver: String,
+	pub ecosystem: String,
+	pub upstream: String,
+	pub summary: String,
+	pub aliases: Vec<String>,
+	pub severity: Option<String>,
+	pub fixed: Option<String>,
+	pub found: NaiveDateTime,
+}
+
+/// Normalise a package name so that e.g. `Foo_Bar` matches `foo-bar`.
+fn normalize(name: &str) -> String {
+	name.to_lowercase().replace(['_', '.'], "-")
+}
+
+fn is_distro(ecosystem: &str) -> bool {
+	DISTROS.contains(&ecosystem.split(':').next().unwrap_or_default())
+}
+
+/// The upstream `(ecosystem, name)` of a package without an explicit mapping.
+fn default_upstream(name: &str) -> (Option<&'static str>, &str) {
+	for (prefix, ecosystem) in PREFIXES {
+		if let Some(n) = name.strip_prefix(prefix) {
+			return (Some(ecosystem), n);
+		}
+	}
+	(None, name)
+}
+
+impl Range {
+	/// Whether `ver` falls in this range. `GIT` ranges are not supported.
+	fn contains(&self, ver: &str) -> bool {
+		if self.kind == "GIT" {
+			return false;
+		}
+		let mut events: Vec<_> = (self.events.iter())
+			.filter_map(|e| {
+				(e.introduced.as_deref().map(|v| (0, v)))
+					.or_else(|| e.fixed.as_deref().map(|v| (1, v)))
+					.or_else(|| e.last_affected.as_deref().map(|v| (2, v)))
+					.or_else(|| e.limit.as_deref().map(|v| (3, v)))
+			})
+			.collect();
+		events.sort_by(|a, b| rpmvercmp(a.1, b.1));
+		let mut affected = false;
+		for (kind, v) in events {
+			match (kind, rpmvercmp(v, ver)) {
+				(0, Ordering::Less | Ordering::Equal) => affected = true,
+				(1 | 3, Ordering::Less | Ordering::Equal) | (2, Ordering::Less) => affected = false,
+				_ => {},
+			}
+		}
+		affected
+	}
+
+	/// The lowest version fixing this range above `ver`.
+	fn fixed_after(&self, ver: &str) -> Option<&str> {
+		(self.events.iter())
+			.filter_map(|e| e.fixed.as_deref())
+			.filter(|f| rpmvercmp(f, ver) == Ordering::Greater)
+			.min_by(|a, b| rpmvercmp(a, b))
+	}
+}
+
+impl Affected {
+	pub fn contains(&self, ver: &str) -> bool {
+		self.versions.iter().any(|v| v == ver) || self.ranges.iter().any(|r| r.contains(ver))
+	}
+}
+
+impl Vuln {
+	fn severity(&self) -> Option<String> {
+		let db = self.database_specific.as_ref().and_then(|d| d.get("severity"));
+		(db.and_then(|s| s.as_str()).map(str::to_owned))
+			.or_else(|| self.severity.first().map(|s| format!("{}: {}", s.kind, s.score)))
+	}
+}
+
+/// The upstream names of all packages, as `(repo, name) => (ecosystem, upstream)`.
+async fn mappings(
+	db: &mut PgConnection,
+) -> sqlx::Result<HashMap<(String, String), (String, String)>> {
+	let q = sqlx::query_as::<_, (String, String, String, String)>(
+		"SELECT repo,name,ecosystem,upstream FROM upstream_names",
+	);
+	Ok(q.fetch_all(db).await?.into_iter().map(|(r, n, e, u)| ((r, n), (e, u))).collect())
+}
+
+/// Set the upstream `(ecosystem, name)` of a package, used for matching advisories.
+pub async fn set_upstream_name(
+	db: &mut PgConnection, repo: &str, name: &str, ecosystem: &str, upstream: &str,
+) -> sqlx::Result<()> {
+	sqlx::query(
+		"INSERT INTO upstream_names(repo,name,ecosystem,upstream) VALUES ($1,$2,$3,$4)
+		ON CONFLICT (repo,name) DO UPDATE SET (ecosystem,upstream)=($3,$4)",
+	)
+	.bind(repo)
+	.bind(name)
+	.bind(ecosystem)
+	.bind(upstream)
+	.execute(db)
+	.await?;
+	Ok(())
+}
+
+/// Store the advisories in `dump` that concern a package we ship.
+///
+/// `dump` is either a single OSV object or an array of them. Returns the number of stored
+/// advisories.
+pub async fn import(db: &mut PgConnection, dump: &[u8]) -> Result<usize, ImportError> {
+	let dump = match serde_json::from_slice(dump)? {
+		serde_json::Value::Array(vulns) => vulns,
+		vuln => vec![vuln],
+	};
+	let maps = mappings(db).await?;
+	let pkgs = sqlx::query_as::<_, (String, String)>("SELECT DISTINCT repo,name FROM pkgs");
+	let names: HashSet<_> = (pkgs.fetch_all(&mut *db).await?.into_iter())
+		.map(|(r, n)| {
+			maps.get(&(r, n.clone()))
+				.map_or_else(|| default_upstream(&n).1.to_owned(), |m| m.1.clone())
+		})
+		.map(|n| normalize(&n))
+		.collect();
+	let mut tx = db.begin().await?;
+	let mut n = 0;
+	for raw in dump {
+		let vuln: Vuln = serde_json::from_value(raw.clone())?;
+		let ours = (vuln.affected.iter().filter_map(|a| a.package.as_ref()))
+			.any(|p| names.contains(&normalize(&p.name)));
+		if !ours {
+			continue;
+		}
+		sqlx::query(
+			"INSERT INTO osv(id,data) VALUES ($1,$2) ON CONFLICT (id) DO UPDATE SET data=$2",
+		)
+		.bind(&vuln.id)
+		.bind(Json(raw))
+		.execute(&mut *tx)
+		.await?;
+		n += 1;
+	}
+	tx.commit().await?;
+	Ok(n)
+}
+
+/// Match all stored advisories against `pkgs` again, returning newly found vulnerabilities.
+pub async fn rescan(db: &mut PgConnection) -> sqlx::Result<Vec<Vulnerability>> {
+	let vulns: Vec<Vuln> = (sqlx::query_scalar::<_, Json<Vuln>>("SELECT data FROM osv"))
+		.fetch_all(&mut *db)
+		.await?
+		.into_iter()
+		.map(|j| j.0)
+		.collect();
+	let mut index: HashMap<String, Vec<(&Vuln, &Affected, &Package)>> = HashMap::new();
+	for v in &vulns {
+		for a in &v.affected {
+			if let Some(p) = &a.package {
+				index.entry(normalize(&p.name)).or_default().push((v, a, p));
+			}
+		}
+	}
+	let pkgs = sqlx::query_as::<_, Pkg>(
+		"SELECT DISTINCT ON (repo,name) * FROM pkgs ORDER BY repo, name, arch",
+	)
+	.fetch_all(&mut *db)
+	.await?;
+	let maps = mappings(db).await?;
+	let existing: HashMap<(String, String, String), NaiveDateTime> =
+		(sqlx::query_as::<_, (String, String, String, NaiveDateTime)>(
+			"SELECT id,repo,name,found FROM vulnerabilities",
+		))
+		.fetch_all(&mut *db)
+		.await?
+		.into_iter()
+		.map(|(i, r, n, f)| ((i, r, n), f))
+		.collect();
+
+	let now = chrono::Utc::now().naive_utc();
+	let mut found = vec![];
+	for pkg in pkgs {
+		let (eco, upstream) = maps
+			.get(&(pkg.repo.clone(), pkg.name.clone()))
+			.map_or_else(|| default_upstream(&pkg.name), |(e, u)| (Some(e.as_str()), u.as_str()));
+		for &(v, a, p) in index.get(&normalize(upstream)).into_iter().flatten() {
+			let eco_ok = eco.map_or_else(
+				|| !is_distro(&p.ecosystem),
+				|e| e.eq_ignore_ascii_case(p.ecosystem.split(':').next().unwrap_or_default()),
+			);
+			if !eco_ok || !a.contains(&pkg.ver) {
+				continue;
+			}
+			let key = (v.id.clone(), pkg.repo.clone(), pkg.name.clone());
+			found.push(Vulnerability {
+				found: existing.get(&key).copied().unwrap_or(now),
+				id: v.id.clone(),
+				repo: pkg.repo.clone(),
+				name: pkg.name.clone(),
+				ver: pkg.ver.clone(),
+				ecosystem: p.ecosystem.clone(),
+				upstream: p.name.clone(),
+				summary: v.summary.clone(),
+				aliases: v.aliases.clone(),
+				severity: v.severity(),
+				fixed: a.ranges.iter().find_map(|r| r.fixed_after(&pkg.ver)).map(str::to_owned),
+			});
+		}
+	}
+	found.sort_by(|a, b| (&a.id, &a.repo, &a.name).cmp(&(&b.id, &b.repo, &b.name)));
+	found.dedup_by(|a, b| (&a.id, &a.repo, &a.name) == (&b.id, &b.repo, &b.name));
+
+	let mut tx = db.begin().await?;
+	sqlx::query("DELETE FROM vulnerabilities").execute(&mut *tx).await?;
+	for v in &found {
+		sqlx::query(
+			"INSERT INTO vulnerabilities(id,repo,name,ver,ecosystem,upstream,summary,aliases,
+			severity,fixed,found) VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)",
+		)
+		.bind(&v.id)
+		.bind(&v.repo)
+		.bind(&v.name)
+		.bind(&v.ver)
+		.bind(&v.ecosystem)
+		.bind(&v.upstream)
+		.bind(&v.summary)
+		.bind(&v.aliases)
+		.bind(&v.severity)
+		.bind(&v.fixed)
+		.bind(v.found)
+		.execute(&mut *tx)
+		.await?;
+	}
+	tx.commit().await?;
+	found.retain(|v| !existing.contains_key(&(v.id.clone(), v.repo.clone(), v.name.clone())));
+	Ok(found)
+}
+
+/// Post a notification for each of the `found` vulnerabilities.
+pub async fn notify(webhook: &Webhook, found: &[Vulnerability]) {
+	for v in found {
+		let fixed = v.fixed.as_deref().map(|f| format!(", fixed in {f}")).unwrap_or_default();
+		let msg = format!(
+			":warning: **{}** affects **{}** on **{}**: {} {}{fixed}\n> {}",
+			v.id, v.name, v.repo, v.upstream, v.ver, v.summary
+		);
+		send_webhook(webhook, msg).await;
+	}
+}
+
+/// Vulnerabilities of `repo`, optionally only those of package `name`.
+pub async fn list(
+	db: &mut PgConnection, repo: &str, name: Option<&str>,
+) -> sqlx::Result<Vec<Vulnerability>> {
+	sqlx::query_as::<_, Vulnerability>(
+		"SELECT * FROM vulnerabilities WHERE repo=$1 AND ($2::text IS NULL OR name=$2)
+		ORDER BY name, id",
+	)
+	.bind(repo)
+	.bind(name)
+	.fetch_all(db)
+	.await
+}
+
+/// Remove the vulnerabilities and upstream name mappings of `repo`.
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	for table in ["vulnerabilities", "upstream_names"] {
+		sqlx::query(&format!("DELETE FROM {table} WHERE repo = $1"))
+			.bind(repo)
+			.execute(&mut *db)
+			.await?;
+	}
+	Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+	#[error("Malformed OSV data: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Database error: {0}")]
+	Db(#[from] sqlx::Error),
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn range(kind: &str, events: &[(&str, &str)]) -> Range {
+		let events = (events.iter())
+			.map(|&(event, v)| {
+				let v = Some(v.to_owned());
+				match event {
+					"introduced" => {
+						Event { introduced: v, fixed: None, last_affected: None, limit: None }
+					},
+					"fixed" => {
+						Event { introduced: None, fixed: v, last_affected: None, limit: None }
+					},
+					"last_affected" => {
+						Event { introduced: None, fixed: None, last_affected: v, limit: None }
+					},
+					_ => Event { introduced: None, fixed: None, last_affected: None, limit: v },
+				}
+			})
+			.collect();
+		Range { kind: kind.to_owned(), events }
+	}
+
+	#[test]
+	fn ranges() {
+		let fixed = range("ECOSYSTEM", &[("introduced", "0"), ("fixed", "2.31.0")]);
+		let last = range("ECOSYSTEM", &[("introduced", "1.0"), ("last_affected", "1.5")]);
+		let twice = range(
+			"SEMVER",
+			&[("introduced", "1.0"), ("fixed", "1.2"), ("introduced", "2.0"), ("fixed", "2.1")],
+		);
+		let limited = range("ECOSYSTEM", &[("introduced", "1.0"), ("limit", "3.0")]);
+		let git = range("GIT", &[("introduced", "0")]);
+		for (range, ver, affected) in [
+			(&fixed, "2.30.0", true),
+			(&fixed, "2.31.0", false),
+			(&fixed, "2.31.0~rc1", true),
+			(&fixed, "10.0", false),
+			(&last, "0.9", false),
+			(&last, "1.0", true),
+			(&last, "1.5", true),
+			(&last, "1.5.1", false),
+			(&twice, "1.1", true),
+			(&twice, "1.5", false),
+			(&twice, "2.0", true),
+			(&twice, "2.1", false),
+			(&limited, "2.9", true),
+			(&limited, "3.0", false),
+			(&git, "1.0", false),
+		] {
+			assert_eq!(range.contains(ver), affected, "{ver} in {:?}", range.events);
+		}
+		assert_eq!(twice.fixed_after("1.1"), Some("1.2"));
+		assert_eq!(twice.fixed_after("2.0"), Some("2.1"));
+		assert_eq!(fixed.fixed_after("3.0"), None);
+	}
+}
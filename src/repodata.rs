@@ -0,0 +1,505 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Reading of RPM repository metadata (`repodata/repomd.xml` and `primary.xml`).
+//!
+//! The published repository is the source of truth for what users can install, while `pkgs`
+//! only knows what CI reported. [`sync`] compares the two and keeps a snapshot of the
+//! published packages in `repodata_pkgs`.
+use crate::config;
+use crate::db::{Pkg, Repo};
+use crate::rpmver::rpmvercmp;
+use crate::tasks::periodic;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use rocket::fairing::AdHoc;
+use serde::Serialize;
+use sqlx::{Connection, PgConnection, PgPool};
+use std::collections::HashSet;
+use std::io::Read;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// The most bytes a primary metadata file may decompress to.
+const MAX_PRIMARY: u64 = 1 << 30;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Cannot read repodata: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Cannot download repodata: {0}")]
+	Http(#[from] reqwest::Error),
+	#[error("Malformed repodata: {0}")]
+	Xml(#[from] quick_xml::Error),
+	#[error("Database error: {0}")]
+	Db(#[from] sqlx::Error),
+	#[error("repomd.xml has no primary data")]
+	NoPrimary,
+	#[error("Unsupported compression for {0}")]
+	Unsupported(String),
+	#[error("{0} is larger than {1} bytes once decompressed")]
+	TooLarge(String, u64),
+}
+impl From<quick_xml::events::attributes::AttrError> for Error {
+	fn from(e: quick_xml::events::attributes::AttrError) -> Self {
+		Self::Xml(e.into())
+	}
+}
+
+/// The parts of `repomd.xml` we care about.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepoMd {
+	pub revision: Option<String>,
+	/// Timestamp of the primary data
+	pub timestamp: Option<i64>,
+	/// Location of `primary.xml.*`, relative to the repository root
+	pub primary: Option<String>,
+}
+
+/// A binary (or source) package listed in `primary.xml`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+pub struct RpmPkg {
+	pub name: String,
+	pub arch: String,
+	pub epoch: String,
+	pub ver: String,
+	pub rel: String,
+	pub location: String,
+	pub srpm: Option<String>,
+}
+
+/// Name, version, release and arch as encoded in an RPM file name.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Nevra<'a> {
+	pub name: &'a str,
+	pub ver: &'a str,
+	pub rel: &'a str,
+	pub arch: &'a str,
+}
+
+/// Split `foo-1.0-1.fc41.x86_64.rpm` into its components.
+pub fn parse_nevra(filename: &str) -> Option<Nevra<'_>> {
+	let s = filename.rsplit('/').next()?.strip_suffix(".rpm")?;
+	let (s, arch) = s.rsplit_once('.')?;
+	let (s, rel) = s.rsplit_once('-')?;
+	let (name, ver) = s.rsplit_once('-')?;
+	Some(Nevra { name, ver, rel, arch })
+}
+
+fn attr(e: &BytesStart, name: &[u8]) -> Result<Option<String>, Error> {
+	match e.try_get_attribute(name)? {
+		Some(a) => Ok(Some(a.unescape_value()?.into_owned())),
+		None => Ok(None),
+	}
+}
+
+pub fn parse_repomd(xml: &[u8]) -> Result<RepoMd, Error> {
+	let mut reader = Reader::from_reader(xml);
+	reader.config_mut().trim_text(true);
+	let (mut md, mut in_primary, mut tag) = (RepoMd::default(), false, Vec::new());
+	loop {
+		match reader.read_event()? {
+			Event::Start(e) => {
+				if e.local_name().as_ref() == b"data" {
+					in_primary = attr(&e, b"type")?.as_deref() == Some("primary");
+				}
+				tag = e.local_name().as_ref().to_vec();
+			},
+			Event::Empty(e) if in_primary && e.local_name().as_ref() == b"location" => {
+				md.primary = attr(&e, b"href")?;
+			},
+			Event::Text(t) => match tag.as_slice() {
+				b"revision" => md.revision = Some(t.unescape()?.into_owned()),
+				b"timestamp" if in_primary => md.timestamp = t.unescape()?.parse().ok(),
+				_ => {},
+			},
+			Event::End(e) => {
+				if e.local_name().as_ref() == b"data" {
+					in_primary = false;
+				}
+				tag.clear();
+			},
+			Event::Eof => return Ok(md),
+			_ => {},
+		}
+	}
+}
+
+pub fn parse_primary(xml: &[u8]) -> Result<Vec<RpmPkg>, Error> {
+	let mut reader = Reader::from_reader(xml);
+	reader.config_mut().trim_text(true);
+	let (mut pkgs, mut cur, mut tag) = (vec![], None::<RpmPkg>, Vec::new());
+	loop {
+		match reader.read_event()? {
+			Event::Start(e) => {
+				if e.local_name().as_ref() == b"package" {
+					cur = Some(RpmPkg::default());
+				}
+				tag = e.local_name().as_ref().to_vec();
+			},
+			Event::Empty(e) => {
+				let Some(p) = &mut cur else { continue };
+				match e.local_name().as_ref() {
+					b"version" => {
+						p.epoch = attr(&e, b"epoch")?.unwrap_or_else(|| "0".to_owned());
+						p.ver = attr(&e, b"ver")?.unwrap_or_default();
+						p.rel = attr(&e, b"rel")?.unwrap_or_default();
+					},
+					b"location" => p.location = attr(&e, b"href")?.unwrap_or_default(),
+					_ => {},
+				}
+			},
+			Event::Text(t) => {
+				let Some(p) = &mut cur else { continue };
+				match tag.as_slice() {
+					b"name" => p.name = t.unescape()?.into_owned(),
+					b"arch" => p.arch = t.unescape()?.into_owned(),
+					b"sourcerpm" => p.srpm = Some(t.unescape()?.into_owned()),
+					_ => {},
+				}
+			},
+			Event::End(e) => {
+				if e.local_name().as_ref() == b"package" {
+					pkgs.extend(cur.take());
+				}
+				tag.clear();
+			},
+			Event::Eof => return Ok(pkgs),
+			_ => {},
+		}
+	}
+}
+
+/// Decompress `raw` according to the extension of `location`, failing past `limit` bytes.
+fn decompress(location: &str, raw: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+	let reader: Box<dyn Read + '_> =
+		match std::path::Path::new(location).extension().and_then(std::ffi::OsStr::to_str) {
+			Some("gz") => Box::new(flate2::read::GzDecoder::new(raw)),
+			Some("zst") => Box::new(zstd::stream::read::Decoder::new(raw)?),
+			Some("xml") => Box::new(raw),
+			_ => return Err(Error::Unsupported(location.to_owned())),
+		};
+	let mut buf = vec![];
+	reader.take(limit + 1).read_to_end(&mut buf)?;
+	if buf.len() as u64 > limit {
+		return Err(Error::TooLarge(location.to_owned(), limit));
+	}
+	Ok(buf)
+}
+
+/// Read `path` relative to `base`, which is either an HTTP(S) URL or a local directory.
+pub async fn fetch(base: &str, path: &str) -> Result<Vec<u8>, Error> {
+	let url = format!("{}/{path}", base.trim_end_matches('/'));
+	if let Some(local) = url.strip_prefix("file://") {
+		return Ok(rocket::tokio::fs::read(local).await?);
+	}
+	if !url.contains("://") {
+		return Ok(rocket::tokio::fs::read(&url).await?);
+	}
+	Ok(reqwest::get(&url).await?.error_for_status()?.bytes().await?.to_vec())
+}
+
+/// Download and parse the repository metadata found at `base`.
+pub async fn load(base: &str) -> Result<(RepoMd, Vec<RpmPkg>), Error> {
+	let md = parse_repomd(&fetch(base, "repodata/repomd.xml").await?)?;
+	let primary = md.primary.as_deref().ok_or(Error::NoPrimary)?;
+	let xml = decompress(primary, &fetch(base, primary).await?, MAX_PRIMARY)?;
+	let pkgs = parse_primary(&xml)?;
+	Ok((md, pkgs))
+}
+
+/// Where to read the repodata of `repo` from.
+///
+/// By default this is `repos.link`, but `repodata.base` can point to a URL or local directory
+/// containing one subdirectory per repo (e.g. a local mirror).
+pub fn base_for(settings: &config::Repodata, repo: &str, link: &str) -> String {
+	(settings.base.as_deref())
+		.map_or_else(|| link.to_owned(), |base| format!("{}/{repo}", base.trim_end_matches('/')))
+}
+
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+pub struct Mismatch {
+	pub pkg: Pkg,
+	/// Published `ver-rel`s of the package
+	pub published: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default, utoipa::ToSchema)]
+pub struct SyncReport {
+	pub revision: Option<String>,
+	pub published: usize,
+	/// Published in the repository, but not known in `pkgs`
+	pub unknown: Vec<RpmPkg>,
+	/// Known in `pkgs`, but not published in the repository
+	pub unpublished: Vec<Pkg>,
+	/// Published, but not with the version recorded in `pkgs`
+	pub mismatched: Vec<Mismatch>,
+}
+
+/// Compare `pkgs` against the published `rpms`.
+///
+/// Subpackages and packages built from a known source RPM are not considered unknown.
+pub fn reconcile(known: Vec<Pkg>, subpkgs: &HashSet<String>, rpms: &[RpmPkg]) -> SyncReport {
+	let rpms: Vec<_> = rpms.iter().filter(|r| r.arch != "src").collect();
+	let names: HashSet<_> = known.iter().map(|p| p.name.as_str()).collect();
+	let unknown = (rpms.iter())
+		.filter(|r| !names.contains(r.name.as_str()) && !subpkgs.contains(&r.name))
+		.filter(|r| {
+			let srcname = r.srpm.as_deref().and_then(parse_nevra).map(|n| n.name);
+			!srcname.is_some_and(|n| names.contains(n))
+		})
+		.map(|&r| r.clone())
+		.collect();
+	let mut report = SyncReport { published: rpms.len(), unknown, ..SyncReport::default() };
+	for pkg in known {
+		let published: Vec<_> = (rpms.iter())
+			.filter(|r| r.name == pkg.name && r.arch == pkg.arch)
+			.map(|r| format!("{}-{}", r.ver, r.rel))
+			.collect();
+		if published.is_empty() {
+			report.unpublished.push(pkg);
+		} else if !published.contains(&format!("{}-{}", pkg.ver, pkg.rel)) {
+			report.mismatched.push(Mismatch { pkg, published });
+		}
+	}
+	report
+}
+
+/// Read the published repodata of `repo`, store a snapshot and reconcile `pkgs` against it.
+pub async fn sync(db: &mut PgConnection, repo: &str, base: &str) -> Result<SyncReport, Error> {
+	let (md, rpms) = load(base).await?;
+	let known = sqlx::query_as::<_, Pkg>("SELECT * FROM pkgs WHERE repo=$1 ORDER BY name")
+		.bind(repo)
+		.fetch_all(&mut *db)
+		.await?;
+	let subpkgs = sqlx::query_scalar::<_, String>("SELECT subpkg FROM pkg_subpkgs WHERE repo=$1")
+		.bind(repo)
+		.fetch_all(&mut *db)
+		.await?;
+	let mut report = reconcile(known, &subpkgs.into_iter().collect(), &rpms);
+	report.revision.clone_from(&md.revision);
+
+	let mut tx = db.begin().await?;
+	sqlx::query("DELETE FROM repodata_pkgs WHERE repo=$1").bind(repo).execute(&mut *tx).await?;
+	sqlx::query(
+		"INSERT INTO repodata_pkgs(repo,name,arch,epoch,ver,rel,location,srpm)
+		SELECT $1,* FROM UNNEST($2::text[],$3::text[],$4::text[],$5::text[],$6::text[],$7::text[],$8::text[])",
+	)
+	.bind(repo)
+	.bind(rpms.iter().map(|r| r.name.as_str()).collect::<Vec<_>>())
+	.bind(rpms.iter().map(|r| r.arch.as_str()).collect::<Vec<_>>())
+	.bind(rpms.iter().map(|r| r.epoch.as_str()).collect::<Vec<_>>())
+	.bind(rpms.iter().map(|r| r.ver.as_str()).collect::<Vec<_>>())
+	.bind(rpms.iter().map(|r| r.rel.as_str()).collect::<Vec<_>>())
+	.bind(rpms.iter().map(|r| r.location.as_str()).collect::<Vec<_>>())
+	.bind(rpms.iter().map(|r| r.srpm.as_deref()).collect::<Vec<_>>())
+	.execute(&mut *tx)
+	.await?;
+	sqlx::query(
+		"INSERT INTO repodata(repo,revision,synced) VALUES ($1,$2,$3)
+		ON CONFLICT (repo) DO UPDATE SET (revision,synced)=($2,$3)",
+	)
+	.bind(repo)
+	.bind(&md.revision)
+	.bind(chrono::Utc::now().naive_utc())
+	.execute(&mut *tx)
+	.await?;
+	tx.commit().await?;
+	Ok(report)
+}
+
+/// Path of an RPM relative to the repo root when repodata has not been synced, from
+/// `repodata.rpm_path_template` (e.g. `Packages/{initial}/{name}-{ver}-{rel}.{arch}.rpm`).
+#[allow(clippy::literal_string_with_formatting_args)]
+fn template_path(tpl: &str, name: &str, ver: &str, rel: &str, arch: &str) -> String {
+	let initial = name.chars().next().unwrap_or_default().to_lowercase().to_string();
+	(tpl.replace("{initial}", &initial).replace("{name}", name))
+		.replace("{ver}", ver)
+		.replace("{rel}", rel)
+		.replace("{arch}", arch)
+}
+
+type Candidate = (String, String, String, String);
+
+/// Path of the newest RPM of `name` relative to the repo root, preferring synced repodata.
+///
+/// `arch` defaults to `x86_64`, then `noarch`; use `src` for the source RPM.
+pub async fn locate_rpm(
+	db: &mut PgConnection, settings: &config::Repodata, repo: &str, name: &str, arch: Option<&str>,
+) -> sqlx::Result<Option<String>> {
+	let rank = |a: &str| {
+		arch.map_or_else(
+			|| match a {
+				"src" => 0,
+				"x86_64" => 3,
+				"noarch" => 2,
+				_ => 1,
+			},
+			|want| u8::from(a == want),
+		)
+	};
+	let newest = |a: &&Candidate, b: &&Candidate| {
+		(rank(&a.0).cmp(&rank(&b.0)))
+			.then_with(|| rpmvercmp(&a.1, &b.1))
+			.then_with(|| rpmvercmp(&a.2, &b.2))
+	};
+	let q = sqlx::query_as::<_, Candidate>(
+		"SELECT arch,ver,rel,location FROM repodata_pkgs WHERE (repo,name)=($1,$2)",
+	);
+	let rows = q.bind(repo).bind(name).fetch_all(&mut *db).await?;
+	if let Some(pkg) = rows.iter().filter(|p| rank(&p.0) != 0).max_by(newest) {
+		return Ok(Some(pkg.3.clone()));
+	}
+	let q = sqlx::query_as::<_, Candidate>(
+		"SELECT arch,ver,rel,'' FROM pkgs WHERE (repo,name)=($1,$2)",
+	);
+	let mut rows = q.bind(repo).bind(name).fetch_all(&mut *db).await?;
+	if arch == Some("src") {
+		// pkgs only has binary arches but the source RPM shares their version
+		for p in &mut rows {
+			"src".clone_into(&mut p.0);
+		}
+	}
+	let pkg = rows.iter().filter(|p| rank(&p.0) != 0).max_by(newest);
+	let tpl = &settings.rpm_path_template;
+	Ok(pkg.map(|(arch, ver, rel, _)| template_path(tpl, name, ver, rel, arch)))
+}
+
+/// Remove the repodata snapshot of `repo`.
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	for table in ["repodata_pkgs", "repodata"] {
+		sqlx::query(&format!("DELETE FROM {table} WHERE repo = $1"))
+			.bind(repo)
+			.execute(&mut *db)
+			.await?;
+	}
+	Ok(())
+}
+
+async fn sync_all(pool: &PgPool, settings: &config::Repodata) {
+	let repos = match sqlx::query_as::<_, Repo>("SELECT * FROM repos").fetch_all(pool).await {
+		Ok(repos) => repos,
+		Err(err) => return error!(?err, "Cannot list repos for repodata sync"),
+	};
+	for repo in repos {
+		let mut conn = match pool.acquire().await {
+			Ok(conn) => conn,
+			Err(err) => return error!(?err, "Cannot acquire connection for repodata sync"),
+		};
+		match sync(&mut conn, &repo.name, &base_for(settings, &repo.name, &repo.link)).await {
+			Ok(r) => info!(
+				repo = repo.name,
+				unknown = r.unknown.len(),
+				unpublished = r.unpublished.len(),
+				mismatched = r.mismatched.len(),
+				"Synced repodata"
+			),
+			Err(err) => error!(%err, repo = repo.name, "Cannot sync repodata"),
+		}
+	}
+}
+
+/// Periodically sync all repos every `sync_interval` seconds, if set.
+pub fn fairing(settings: &config::Repodata) -> AdHoc {
+	let interval = settings.sync_interval.map(|secs| Duration::from_secs(secs.get()));
+	let settings = settings.clone();
+	periodic("Repodata sync", interval, move |pool| {
+		let settings = settings.clone();
+		async move { sync_all(&pool, &settings).await }
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/repodata");
+
+	fn pkg(name: &str, ver: &str, rel: &str, arch: &str) -> Pkg {
+		Pkg {
+			name: name.to_owned(),
+			repo: "terra41".to_owned(),
+			ver: ver.to_owned(),
+			rel: rel.to_owned(),
+			arch: arch.to_owned(),
+			dirs: format!("anda/{name}"),
+		}
+	}
+
+	#[test]
+	fn nevra() {
+		let n = parse_nevra("Packages/f/foo-bar-1.2.3-4.fc41.x86_64.rpm").unwrap();
+		assert_eq!(n, Nevra { name: "foo-bar", ver: "1.2.3", rel: "4.fc41", arch: "x86_64" });
+		assert_eq!(parse_nevra("foo.x86_64.rpm"), None);
+		assert_eq!(parse_nevra("foo-1-1.x86_64.tar"), None);
+	}
+
+	#[rocket::async_test]
+	async fn load_gz() {
+		let (md, pkgs) = load(&format!("{FIXTURES}/gz")).await.unwrap();
+		assert_eq!(md.revision.as_deref(), Some("1760000000"));
+		assert_eq!(md.timestamp, Some(1_760_000_000));
+		assert_eq!(pkgs.len(), 5);
+		assert_eq!(
+			pkgs[0],
+			RpmPkg {
+				name: "foo".to_owned(),
+				arch: "x86_64".to_owned(),
+				epoch: "0".to_owned(),
+				ver: "1.2.3".to_owned(),
+				rel: "1.fc41".to_owned(),
+				location: "Packages/f/foo-1.2.3-1.fc41.x86_64.rpm".to_owned(),
+				srpm: Some("foo-1.2.3-1.fc41.src.rpm".to_owned()),
+			}
+		);
+	}
+
+	#[rocket::async_test]
+	async fn load_zst() {
+		let gz = load(&format!("{FIXTURES}/gz")).await.unwrap();
+		let zst = load(&format!("file://{FIXTURES}/zst")).await.unwrap();
+		assert_eq!(gz.1, zst.1);
+		assert_eq!(zst.0.primary.as_deref(), Some("repodata/primary.xml.zst"));
+	}
+
+	#[test]
+	fn decompress_limit() {
+		let primary = "repodata/primary.xml.gz";
+		let raw = std::fs::read(format!("{FIXTURES}/gz/{primary}")).unwrap();
+		let len = decompress(primary, &raw, MAX_PRIMARY).unwrap().len() as u64;
+		assert_eq!(decompress(primary, &raw, len).unwrap().len() as u64, len);
+		let err = decompress(primary, &raw, len - 1).unwrap_err();
+		assert!(matches!(err, Error::TooLarge(_, limit) if limit == len - 1), "{err}");
+		assert!(matches!(decompress("primary.xml", &[0; 8], 4), Err(Error::TooLarge(..))));
+	}
+
+	#[rocket::async_test]
+	async fn reconcile_fixture() {
+		let (_, rpms) = load(&format!("{FIXTURES}/gz")).await.unwrap();
+		let known = vec![
+			pkg("foo", "1.2.3", "1.fc41", "x86_64"),
+			pkg("bar", "2.0", "1.fc41", "noarch"),
+			pkg("gone", "1", "1.fc41", "x86_64"),
+		];
+		let subpkgs = HashSet::new();
+		let report = reconcile(known, &subpkgs, &rpms);
+		assert_eq!(report.published, 4);
+		// foo-devel comes from foo's source RPM
+		let unknown: Vec<_> = report.unknown.iter().map(|r| r.name.as_str()).collect();
+		assert_eq!(unknown, ["stray"]);
+		assert_eq!(report.unpublished.len(), 1);
+		assert_eq!(report.unpublished[0].name, "gone");
+		assert_eq!(report.mismatched.len(), 1);
+		assert_eq!(report.mismatched[0].published, ["2.1-1.fc41"]);
+	}
+}
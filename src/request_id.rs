@@ -0,0 +1,167 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Request IDs, to correlate the logs and Sentry events of a request.
+//!
+//! The ID is taken from the `X-Request-Id` header, e.g. set by a reverse proxy, or generated,
+//! and sent back in the `X-Request-Id` header of the response. Handlers run in a `request`
+//! span and a Sentry scope tagged with it; the span continues the trace of a W3C
+//! `traceparent` header.
+use opentelemetry::propagation::Extractor;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, HeaderMap};
+use rocket::request::{FromRequest, Outcome};
+use rocket::route::{self, Handler, Route};
+use rocket::{Data, Request, Response};
+use sentry::{Hub, SentryFutureExt};
+use std::sync::Arc;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const HEADER: &str = "X-Request-Id";
+
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+	/// The ID of `req`, taken from the request or generated on first use.
+	pub fn of<'r>(req: &'r Request<'_>) -> &'r str {
+		&req.local_cache(|| {
+			let given = req.headers().get_one(HEADER).filter(|id| valid(id));
+			Self(given.map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_owned))
+		})
+		.0
+	}
+}
+
+/// Only IDs that are safe to log and echo back are propagated.
+fn valid(id: &str) -> bool {
+	(1..=128).contains(&id.len())
+		&& id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+	type Error = std::convert::Infallible;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(Self(Self::of(request).to_owned()))
+	}
+}
+
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+	fn info(&self) -> Info {
+		Info { name: "Request IDs", kind: Kind::Request | Kind::Response }
+	}
+
+	async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+		RequestId::of(req);
+	}
+
+	async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+		res.set_header(Header::new(HEADER, RequestId::of(req).to_owned()));
+	}
+}
+
+pub const fn fairing() -> RequestIds {
+	RequestIds
+}
+
+/// Run the handlers of `routes` in the span and Sentry scope of their request.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+	(routes.into_iter())
+		.map(|mut route| {
+			route.handler = Box::new(Traced(route.handler));
+			route
+		})
+		.collect()
+}
+
+/// The headers of a request, to read the trace context from.
+struct Headers<'a> {
+	map: &'a HeaderMap<'a>,
+	names: Vec<String>,
+}
+
+impl<'a> Headers<'a> {
+	fn new(map: &'a HeaderMap<'a>) -> Self {
+		Self { map, names: map.iter().map(|h| h.name().to_string()).collect() }
+	}
+}
+
+impl Extractor for Headers<'_> {
+	fn get(&self, key: &str) -> Option<&str> {
+		self.map.get_one(key)
+	}
+
+	fn keys(&self) -> Vec<&str> {
+		self.names.iter().map(String::as_str).collect()
+	}
+}
+
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+	async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+		let id = RequestId::of(request);
+		let (method, uri) = (request.method(), request.uri());
+		let span = tracing::info_span!("request", request_id = id, %method, %uri);
+		let parent = opentelemetry::global::get_text_map_propagator(|p| {
+			p.extract(&Headers::new(request.headers()))
+		});
+		// fails without spans to export
+		_ = span.set_parent(parent);
+		let hub = Arc::new(Hub::new_from_top(Hub::current()));
+		hub.configure_scope(|scope| scope.set_tag("request_id", id));
+		self.0.handle(request, data).instrument(span).bind_hub(hub).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use opentelemetry::trace::TracerProvider as _;
+	use opentelemetry_sdk::propagation::TraceContextPropagator;
+	use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+	use rocket::local::asynchronous::Client;
+	use tracing::instrument::WithSubscriber;
+	use tracing_subscriber::prelude::*;
+
+	#[rocket::get("/")]
+	const fn index() -> &'static str {
+		"ok"
+	}
+
+	#[rocket::async_test]
+	async fn continues_the_trace_of_the_caller() {
+		let exporter = InMemorySpanExporter::default();
+		let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+		opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+		let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+		let subscriber = tracing_subscriber::registry().with(layer);
+		let rocket = rocket::build().mount("/", traced(rocket::routes![index]));
+		let client = Client::untracked(rocket).await.unwrap();
+		let parent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+		let req = client.get("/").header(Header::new("traceparent", parent));
+		assert_eq!(req.dispatch().with_subscriber(subscriber).await.status().code, 200);
+		let spans = exporter.get_finished_spans().unwrap();
+		let request = spans.iter().find(|s| s.name == "request").unwrap();
+		assert_eq!(request.span_context.trace_id().to_string(), "0af7651916cd43dd8448eb211c80319c");
+		assert_eq!(request.parent_span_id.to_string(), "b7ad6b7169203331");
+	}
+}
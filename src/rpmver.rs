@@ -0,0 +1,124 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Version comparison with the same semantics as `rpmvercmp()` in librpm.
+use std::cmp::Ordering;
+
+const fn is_sep(c: u8) -> bool {
+	!c.is_ascii_alphanumeric() && c != b'~' && c != b'^'
+}
+
+fn split_seg(s: &[u8], numeric: bool) -> (&[u8], &[u8]) {
+	let end = (s.iter())
+		.position(|c| if numeric { !c.is_ascii_digit() } else { !c.is_ascii_alphabetic() })
+		.unwrap_or(s.len());
+	s.split_at(end)
+}
+
+/// Compare two version (or release) strings like `rpmvercmp()`.
+///
+/// `~` sorts before anything (including the end of the string) and `^` sorts after the end
+/// of the string but before anything else.
+pub fn rpmvercmp(a: &str, b: &str) -> Ordering {
+	if a == b {
+		return Ordering::Equal;
+	}
+	let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+	while !a.is_empty() || !b.is_empty() {
+		while a.first().is_some_and(|&c| is_sep(c)) {
+			a = &a[1..];
+		}
+		while b.first().is_some_and(|&c| is_sep(c)) {
+			b = &b[1..];
+		}
+		match (a.first(), b.first()) {
+			(Some(b'~'), Some(b'~')) | (Some(b'^'), Some(b'^')) => {
+				(a, b) = (&a[1..], &b[1..]);
+				continue;
+			},
+			(Some(b'~'), _) => return Ordering::Less,
+			(_, Some(b'~')) => return Ordering::Greater,
+			(None, Some(b'^')) | (Some(b'^'), Some(_)) => return Ordering::Less,
+			(Some(b'^'), None) | (Some(_), Some(b'^')) => return Ordering::Greater,
+			(None, _) | (_, None) => break,
+			_ => {},
+		}
+		let numeric = a[0].is_ascii_digit();
+		let ((sa, ra), (sb, rb)) = (split_seg(a, numeric), split_seg(b, numeric));
+		if sb.is_empty() {
+			// segments of different types: numeric ones are newer
+			return if numeric { Ordering::Greater } else { Ordering::Less };
+		}
+		let ord = if numeric {
+			let sa = &sa[sa.iter().position(|&c| c != b'0').unwrap_or(sa.len())..];
+			let sb = &sb[sb.iter().position(|&c| c != b'0').unwrap_or(sb.len())..];
+			sa.len().cmp(&sb.len()).then_with(|| sa.cmp(sb))
+		} else {
+			sa.cmp(sb)
+		};
+		if ord != Ordering::Equal {
+			return ord;
+		}
+		(a, b) = (ra, rb);
+	}
+	match (a.is_empty(), b.is_empty()) {
+		(true, true) => Ordering::Equal,
+		(true, false) => Ordering::Less,
+		(false, _) => Ordering::Greater,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use Ordering::{Equal, Greater, Less};
+
+	#[test]
+	fn like_librpm() {
+		for (a, b, ord) in [
+			("1.0", "1.0", Equal),
+			("1.0", "2.0", Less),
+			("2.0.1", "2.0", Greater),
+			("2.0.1a", "2.0.1", Greater),
+			("1.0", "1_0", Equal),
+			// alphanumeric segments
+			("5.5p1", "5.5p2", Less),
+			("5.5p10", "5.5p1", Greater),
+			("10xyz", "10.1xyz", Less),
+			("xyz10", "xyz10.1", Less),
+			("a", "b", Less),
+			("1.a", "1.1", Less),
+			("2a", "2.0", Less),
+			("6.0.rc1", "6.0", Greater),
+			("1b.fc17", "1.fc17", Less),
+			// leading zeros
+			("1.05", "1.5", Equal),
+			("1.0010", "1.9", Greater),
+			("1.002", "1.10", Less),
+			// tilde
+			("1.0~rc1", "1.0", Less),
+			("1.0~rc1", "1.0~rc2", Less),
+			("1.0~rc1~git123", "1.0~rc1", Less),
+			("1.0~rc1", "1.0arc1", Less),
+			// caret
+			("1.0^", "1.0", Greater),
+			("1.0^git1", "1.0^git2", Less),
+			("1.0^git1", "1.01", Less),
+			("1.0^git1~pre", "1.0^git1", Less),
+			("1.0^20160101", "1.0.1", Less),
+		] {
+			assert_eq!(rpmvercmp(a, b), ord, "{a} vs {b}");
+			assert_eq!(rpmvercmp(b, a), ord.reverse(), "{b} vs {a}");
+		}
+	}
+}
@@ -0,0 +1,49 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Background tasks run at a fixed interval while the server is up.
+use crate::db::Madoguchi;
+use rocket::fairing::AdHoc;
+use rocket_db_pools::Database;
+use sqlx::PgPool;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Run `task` every `interval` from liftoff until shutdown, the first time right away.
+///
+/// Nothing runs without an interval or a database.
+pub fn periodic<F, Fut>(name: &'static str, interval: Option<Duration>, task: F) -> AdHoc
+where
+	F: Fn(PgPool) -> Fut + Send + Sync + 'static,
+	Fut: Future<Output = ()> + Send + 'static,
+{
+	let task = Arc::new(task);
+	AdHoc::on_liftoff(name, move |rocket| {
+		let task = Arc::clone(&task);
+		Box::pin(async move {
+			let Some(interval) = interval else { return };
+			let Some(db) = Madoguchi::fetch(rocket) else { return };
+			let (pool, mut shutdown) = ((**db).clone(), rocket.shutdown());
+			rocket::tokio::spawn(async move {
+				let mut ticks = rocket::tokio::time::interval(interval);
+				loop {
+					rocket::tokio::select! {
+						_ = ticks.tick() => task(pool.clone()).await,
+						() = &mut shutdown => break,
+					}
+				}
+			});
+		})
+	})
+}
@@ -0,0 +1,210 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Error reports to Sentry and the export of `tracing` spans to an OpenTelemetry collector.
+//!
+//! Sentry is only enabled with the `sentry_dsn` of the configuration, tuned with
+//! `sentry_environment`, `sentry_sample_rate` (default 1) and `sentry_send_pii` (default false,
+//! i.e. no IPs and headers are sent).
+//!
+//! Spans are exported with the OpenTelemetry SDK, configured by the standard `OTEL_*`
+//! variables rather than the configuration: with `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) set, the spans enabled by `OTEL_TRACES_FILTER`
+//! (default `info`) are sent in batches over OTLP/HTTP, with `OTEL_EXPORTER_OTLP_HEADERS`,
+//! `OTEL_SERVICE_NAME` (default `madoguchi`) and the `OTEL_BSP_*` batch settings. Failed
+//! exports are retried, and exports that still fail or spans dropped from a full queue are
+//! logged. Requests carrying a W3C `traceparent` continue the trace of their caller.
+use crate::config::Config;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{ExporterBuildError, WithHttpConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanExporter};
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Start reporting to Sentry, if `sentry_dsn` is set. Reports are sent until the guard drops.
+pub fn sentry(config: &Config) -> Option<sentry::ClientInitGuard> {
+	Some(sentry::init(sentry::ClientOptions {
+		dsn: Some(config.sentry_dsn.clone()?),
+		release: sentry::release_name!(),
+		environment: config.sentry_environment.clone().map(Into::into),
+		sample_rate: config.sentry_sample_rate,
+		send_default_pii: config.sentry_send_pii,
+		..Default::default()
+	}))
+}
+
+/// The spans sent to the collector, `OTEL_TRACES_FILTER` or `info`.
+///
+/// The spans of the SDK itself are never exported, as each export would make more.
+pub fn filter() -> EnvFilter {
+	let filter =
+		EnvFilter::try_from_env("OTEL_TRACES_FILTER").unwrap_or_else(|_| EnvFilter::new("info"));
+	["opentelemetry", "opentelemetry_sdk", "opentelemetry_otlp", "opentelemetry_http"]
+		.into_iter()
+		.filter_map(|target| format!("{target}=off").parse().ok())
+		.fold(filter, EnvFilter::add_directive)
+}
+
+/// The headers of `OTEL_EXPORTER_OTLP_HEADERS`, as comma-separated and percent-encoded
+/// `key=value` pairs. Pairs without a key or a value are skipped.
+fn headers(list: &str) -> HashMap<String, String> {
+	(list.split(','))
+		.filter_map(|pair| {
+			let (key, value) = pair.split_once('=')?;
+			let (key, value) = (key.trim(), decode(value.trim()));
+			(!key.is_empty() && !value.is_empty()).then(|| (key.to_owned(), value))
+		})
+		.collect()
+}
+
+/// `s` with its `%XX` escapes decoded.
+fn decode(s: &str) -> String {
+	let mut bytes = Vec::with_capacity(s.len());
+	let mut rest = s.as_bytes();
+	while let [b, tail @ ..] = rest {
+		let hex =
+			(tail.get(..2)).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+		if let (b'%', Some(byte)) = (b, hex) {
+			bytes.push(byte);
+			rest = &tail[2..];
+		} else {
+			bytes.push(*b);
+			rest = tail;
+		}
+	}
+	String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A provider batching the spans to `exporter`, which also propagates `traceparent`.
+fn provider(exporter: impl SpanExporter + 'static) -> SdkTracerProvider {
+	let mut resource = Resource::builder();
+	if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+		resource = resource.with_service_name("madoguchi");
+	}
+	opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+	(SdkTracerProvider::builder())
+		.with_batch_exporter(exporter)
+		.with_resource(resource.build())
+		.build()
+}
+
+/// The provider exporting spans over OTLP/HTTP, if an OTLP endpoint is set.
+///
+/// It must be shut down to export the last spans.
+pub fn otlp() -> Result<Option<SdkTracerProvider>, ExporterBuildError> {
+	let set = |var| std::env::var(var).is_ok_and(|s| !s.is_empty());
+	if !set("OTEL_EXPORTER_OTLP_ENDPOINT") && !set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+		return Ok(None);
+	}
+	let list = std::env::var("OTEL_EXPORTER_OTLP_TRACES_HEADERS")
+		.or_else(|_| std::env::var("OTEL_EXPORTER_OTLP_HEADERS"))
+		.unwrap_or_default();
+	let exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+	let exporter = exporter.with_headers(headers(&list)).build()?;
+	Ok(Some(provider(exporter)))
+}
+
+/// The layer sending the spans enabled by [`filter`] to `provider`.
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+{
+	let tracer = provider.tracer("madoguchi");
+	tracing_opentelemetry::layer().with_tracer(tracer).with_filter(filter())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use opentelemetry_otlp::WithExportConfig;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::sync::mpsc;
+	use std::time::Duration;
+	use tracing_subscriber::prelude::*;
+
+	/// The lower-cased head of the next request on `conn`, once its body is read.
+	fn request(conn: &mut BufReader<TcpStream>) -> Option<String> {
+		let mut head = String::new();
+		loop {
+			let mut line = String::new();
+			if conn.read_line(&mut line).ok()? == 0 {
+				return None;
+			}
+			if line == "\r\n" {
+				break;
+			}
+			head.push_str(&line.to_ascii_lowercase());
+		}
+		let len = (head.lines())
+			.find_map(|l| l.strip_prefix("content-length:")?.trim().parse().ok())
+			.unwrap_or(0);
+		conn.read_exact(&mut vec![0; len]).ok()?;
+		Some(head)
+	}
+
+	/// A collector failing the first export, which sends the heads of the requests it gets.
+	fn collector() -> (String, mpsc::Receiver<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+		let (tx, rx) = mpsc::channel();
+		std::thread::spawn(move || {
+			let mut status = "503 Service Unavailable";
+			for conn in listener.incoming() {
+				let Ok(conn) = conn else { return };
+				let mut conn = BufReader::new(conn);
+				while let Some(head) = request(&mut conn) {
+					let res = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n");
+					_ = conn.get_mut().write_all(res.as_bytes());
+					status = "200 OK";
+					_ = tx.send(head);
+				}
+			}
+		});
+		(endpoint, rx)
+	}
+
+	#[test]
+	fn parse_headers() {
+		let parsed = headers(" authorization = Bearer%20secret,x-tenant=a%2Cb,=x,empty=,bad=%zz%4");
+		let expected = [("authorization", "Bearer secret"), ("x-tenant", "a,b"), ("bad", "%zz%4")];
+		assert_eq!(parsed, expected.map(|(k, v)| (k.to_owned(), v.to_owned())).into());
+		assert!(headers("").is_empty());
+	}
+
+	#[test]
+	fn spans_reach_the_collector() {
+		let (endpoint, requests) = collector();
+		let exporter = (opentelemetry_otlp::SpanExporter::builder().with_http())
+			.with_endpoint(endpoint)
+			.with_headers(headers("authorization=Bearer%20secret"));
+		let provider = provider(exporter.build().unwrap());
+		let subscriber = tracing_subscriber::registry().with(layer(&provider));
+		tracing::subscriber::with_default(subscriber, || {
+			tracing::info_span!("request").in_scope(|| tracing::info!("Handled"));
+		});
+		provider.force_flush().unwrap();
+		let next = || requests.recv_timeout(Duration::from_secs(5)).unwrap();
+		// the first export fails and is retried
+		for head in [next(), next()] {
+			assert!(head.starts_with("post /v1/traces "), "{head}");
+			assert!(head.contains("content-type: application/x-protobuf"), "{head}");
+			assert!(head.contains("authorization: bearer secret"), "{head}");
+		}
+		provider.shutdown().unwrap();
+	}
+}
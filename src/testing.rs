@@ -0,0 +1,39 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Helpers shared by the tests of several modules.
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::TcpListener;
+
+/// Serve `files` (path → body) over HTTP on a local port; returns the base URL.
+pub async fn serve(files: Vec<(&'static str, String)>) -> String {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	rocket::tokio::spawn(async move {
+		loop {
+			let Ok((mut sock, _)) = listener.accept().await else { return };
+			let mut buf = vec![0; 4096];
+			let n = sock.read(&mut buf).await.unwrap_or_default();
+			let req = String::from_utf8_lossy(&buf[..n]);
+			let path = req.split_whitespace().nth(1).unwrap_or_default();
+			let res = files.iter().find(|(p, _)| *p == path).map_or_else(
+				|| "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_owned(),
+				|(_, body)| {
+					format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}", body.len())
+				},
+			);
+			_ = sock.write_all(res.as_bytes()).await;
+		}
+	});
+	format!("http://{addr}")
+}
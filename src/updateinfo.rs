@@ -0,0 +1,394 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Update advisories, rendered as `updateinfo.xml` for `dnf updateinfo`.
+//!
+//! Advisories are created automatically when a build changes the version of a package and
+//! can be amended (type, references, description) by admins afterwards.
+use chrono::{Datelike, NaiveDateTime};
+use quick_xml::events::{BytesDecl, BytesText, Event};
+use quick_xml::Writer;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use std::collections::HashMap;
+
+const DATE_FMT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, utoipa::ToSchema)]
+#[schema(as = AdvisoryKind)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+	Bugfix,
+	Security,
+	Enhancement,
+}
+impl Kind {
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::Bugfix => "bugfix",
+			Self::Security => "security",
+			Self::Enhancement => "enhancement",
+		}
+	}
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug, utoipa::ToSchema)]
+pub struct Reference {
+	#[serde(skip)]
+	pub advisory: String,
+	pub kind: String,
+	pub href: String,
+	pub ref_id: Option<String>,
+	pub title: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Serialize, Debug, utoipa::ToSchema)]
+pub struct AdvisoryPkg {
+	#[serde(skip)]
+	pub advisory: String,
+	pub name: String,
+	pub epoch: String,
+	pub ver: String,
+	pub rel: String,
+	pub arch: String,
+	pub filename: String,
+}
+
+#[derive(sqlx::FromRow, Serialize, Debug, utoipa::ToSchema)]
+pub struct Advisory {
+	pub id: String,
+	pub repo: String,
+	pub kind: String,
+	pub title: String,
+	pub description: String,
+	pub severity: Option<String>,
+	pub issued: NaiveDateTime,
+	pub updated: NaiveDateTime,
+	#[sqlx(skip)]
+	pub refs: Vec<Reference>,
+	#[sqlx(skip)]
+	pub pkgs: Vec<AdvisoryPkg>,
+}
+
+/// Changes to an advisory; missing fields are left as is.
+#[derive(Deserialize, Debug, utoipa::ToSchema)]
+pub struct AdvisoryEdit {
+	pub kind: Option<Kind>,
+	pub title: Option<String>,
+	pub description: Option<String>,
+	pub severity: Option<String>,
+	pub refs: Option<Vec<Reference>>,
+}
+
+/// A successfully built package.
+pub struct Built<'a> {
+	pub name: &'a str,
+	/// Taken from the synced repodata when not given, otherwise `0`
+	pub epoch: Option<&'a str>,
+	pub ver: &'a str,
+	pub rel: &'a str,
+	pub arch: &'a str,
+}
+
+/// Record a successful build in the advisories.
+///
+/// `old` is the `(ver, rel)` that was in `pkgs` before the build. A new advisory is created
+/// for the first build of a package and when the version differs from the built one; builds
+/// for other arches of the same `ver-rel` are added to the existing advisory.
+pub async fn record_build(
+	db: &mut PgConnection, repo: &str, old: Option<(&str, &str)>, built: &Built<'_>,
+) -> sqlx::Result<()> {
+	let Built { name, ver, rel, arch, .. } = *built;
+	let mut tx = db.begin().await?;
+	let existing = sqlx::query_scalar(
+		"SELECT a.id FROM advisories a JOIN advisory_pkgs p ON p.advisory=a.id
+		WHERE a.repo=$1 AND p.name=$2 AND p.ver=$3 AND p.rel=$4 LIMIT 1",
+	)
+	.bind(repo)
+	.bind(name)
+	.bind(ver)
+	.bind(rel)
+	.fetch_optional(&mut *tx)
+	.await?;
+	let title = format!("{name}-{ver}-{rel}");
+	let id = match (existing, old) {
+		(Some(id), _) => id,
+		(None, Some((over, orel))) if (over, orel) != (ver, rel) => {
+			let kind = if over == ver { Kind::Bugfix } else { Kind::Enhancement };
+			let desc = format!("Update {name} from {over}-{orel} to {ver}-{rel}.");
+			create(&mut tx, repo, kind, &title, &desc).await?
+		},
+		(None, None) => {
+			let desc = format!("New package {name} {ver}-{rel}.");
+			create(&mut tx, repo, Kind::Enhancement, &title, &desc).await?
+		},
+		_ => return Ok(()),
+	};
+	let epoch = match built.epoch {
+		Some(epoch) => epoch.to_owned(),
+		None => sqlx::query_scalar(
+			"SELECT epoch FROM repodata_pkgs WHERE (repo,name,ver,rel,arch)=($1,$2,$3,$4,$5) LIMIT 1",
+		)
+		.bind(repo)
+		.bind(name)
+		.bind(ver)
+		.bind(rel)
+		.bind(arch)
+		.fetch_optional(&mut *tx)
+		.await?
+		.unwrap_or_else(|| "0".to_owned()),
+	};
+	sqlx::query(
+		"INSERT INTO advisory_pkgs(advisory,name,epoch,ver,rel,arch,filename)
+		VALUES ($1,$2,$3,$4,$5,$6,$7) ON CONFLICT (advisory,name,arch) DO NOTHING",
+	)
+	.bind(&id)
+	.bind(name)
+	.bind(epoch)
+	.bind(ver)
+	.bind(rel)
+	.bind(arch)
+	.bind(format!("{name}-{ver}-{rel}.{arch}.rpm"))
+	.execute(&mut *tx)
+	.await?;
+	tx.commit().await
+}
+
+/// Create an empty advisory, returning its ID (e.g. `TERRA41-2026-0001`).
+pub async fn create(
+	db: &mut PgConnection, repo: &str, kind: Kind, title: &str, description: &str,
+) -> sqlx::Result<String> {
+	let now = chrono::Utc::now().naive_utc();
+	let seq: i64 = sqlx::query_scalar("SELECT nextval('advisory_seq')").fetch_one(&mut *db).await?;
+	let id = format!("{}-{}-{seq:04}", repo.to_uppercase(), now.year());
+	sqlx::query(
+		"INSERT INTO advisories(id,repo,kind,title,description,issued,updated)
+		VALUES ($1,$2,$3,$4,$5,$6,$6)",
+	)
+	.bind(&id)
+	.bind(repo)
+	.bind(kind.as_str())
+	.bind(title)
+	.bind(description)
+	.bind(now)
+	.execute(&mut *db)
+	.await?;
+	Ok(id)
+}
+
+/// Apply `edit` to the advisory. Returns `false` if it does not exist.
+pub async fn edit(
+	db: &mut PgConnection, repo: &str, id: &str, edit: &AdvisoryEdit,
+) -> sqlx::Result<bool> {
+	let mut tx = db.begin().await?;
+	let res = sqlx::query(
+		"UPDATE advisories SET kind=COALESCE($3,kind),title=COALESCE($4,title),
+		description=COALESCE($5,description),severity=COALESCE($6,severity),updated=$7
+		WHERE (id,repo)=($1,$2)",
+	)
+	.bind(id)
+	.bind(repo)
+	.bind(edit.kind.map(Kind::as_str))
+	.bind(&edit.title)
+	.bind(&edit.description)
+	.bind(&edit.severity)
+	.bind(chrono::Utc::now().naive_utc())
+	.execute(&mut *tx)
+	.await?;
+	if res.rows_affected() == 0 {
+		return Ok(false);
+	}
+	if let Some(refs) = &edit.refs {
+		sqlx::query("DELETE FROM advisory_refs WHERE advisory=$1")
+			.bind(id)
+			.execute(&mut *tx)
+			.await?;
+		for r in refs {
+			sqlx::query(
+				"INSERT INTO advisory_refs(advisory,kind,href,ref_id,title) VALUES ($1,$2,$3,$4,$5)
+				ON CONFLICT (advisory,href) DO NOTHING",
+			)
+			.bind(id)
+			.bind(&r.kind)
+			.bind(&r.href)
+			.bind(&r.ref_id)
+			.bind(&r.title)
+			.execute(&mut *tx)
+			.await?;
+		}
+	}
+	tx.commit().await?;
+	Ok(true)
+}
+
+/// Delete an advisory. Returns `false` if it does not exist.
+pub async fn delete(db: &mut PgConnection, repo: &str, id: &str) -> sqlx::Result<bool> {
+	let q = sqlx::query("DELETE FROM advisories WHERE (id,repo)=($1,$2)").bind(id).bind(repo);
+	Ok(q.execute(db).await?.rows_affected() != 0)
+}
+
+/// Remove all advisories of `repo`.
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	sqlx::query("DELETE FROM advisories WHERE repo=$1").bind(repo).execute(db).await?;
+	Ok(())
+}
+
+/// All advisories of `repo` with their references and packages, newest first.
+pub async fn list(db: &mut PgConnection, repo: &str) -> sqlx::Result<Vec<Advisory>> {
+	let mut advisories = sqlx::query_as::<_, Advisory>(
+		"SELECT * FROM advisories WHERE repo=$1 ORDER BY issued DESC, id DESC",
+	)
+	.bind(repo)
+	.fetch_all(&mut *db)
+	.await?;
+	let refs = sqlx::query_as::<_, Reference>(
+		"SELECT r.* FROM advisory_refs r JOIN advisories a ON a.id=r.advisory WHERE a.repo=$1",
+	)
+	.bind(repo)
+	.fetch_all(&mut *db)
+	.await?;
+	let pkgs = sqlx::query_as::<_, AdvisoryPkg>(
+		"SELECT p.* FROM advisory_pkgs p JOIN advisories a ON a.id=p.advisory WHERE a.repo=$1
+		ORDER BY p.name, p.arch",
+	)
+	.bind(repo)
+	.fetch_all(&mut *db)
+	.await?;
+	let idx: HashMap<_, _> =
+		advisories.iter().enumerate().map(|(i, a)| (a.id.clone(), i)).collect();
+	for r in refs {
+		if let Some(&i) = idx.get(&r.advisory) {
+			advisories[i].refs.push(r);
+		}
+	}
+	for p in pkgs {
+		if let Some(&i) = idx.get(&p.advisory) {
+			advisories[i].pkgs.push(p);
+		}
+	}
+	Ok(advisories)
+}
+
+fn text<W: std::io::Write>(w: &mut Writer<W>, tag: &str, s: &str) -> std::io::Result<()> {
+	w.create_element(tag).write_text_content(BytesText::new(s))?;
+	Ok(())
+}
+
+/// Render the advisories of `repo` as `updateinfo.xml`.
+pub fn render(repo: &str, advisories: &[Advisory]) -> std::io::Result<String> {
+	let mut w = Writer::new_with_indent(Vec::new(), b' ', 2);
+	w.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+	w.create_element("updates").write_inner_content(|w| {
+		for a in advisories {
+			let attrs = [
+				("from", "madoguchi"),
+				("status", "stable"),
+				("type", a.kind.as_str()),
+				("version", "2"),
+			];
+			w.create_element("update").with_attributes(attrs).write_inner_content(|w| {
+				text(w, "id", &a.id)?;
+				text(w, "title", &a.title)?;
+				let issued = a.issued.format(DATE_FMT).to_string();
+				let updated = a.updated.format(DATE_FMT).to_string();
+				w.create_element("issued").with_attribute(("date", &*issued)).write_empty()?;
+				w.create_element("updated").with_attribute(("date", &*updated)).write_empty()?;
+				text(w, "release", repo)?;
+				text(w, "severity", a.severity.as_deref().unwrap_or("None"))?;
+				text(w, "summary", &a.title)?;
+				text(w, "description", &a.description)?;
+				w.create_element("references").write_inner_content(|w| {
+					for r in &a.refs {
+						let mut e = w
+							.create_element("reference")
+							.with_attributes([("href", &*r.href), ("type", &*r.kind)]);
+						if let Some(id) = &r.ref_id {
+							e = e.with_attribute(("id", &**id));
+						}
+						if let Some(title) = &r.title {
+							e = e.with_attribute(("title", &**title));
+						}
+						e.write_empty()?;
+					}
+					Ok(())
+				})?;
+				w.create_element("pkglist").write_inner_content(|w| {
+					let coll = w.create_element("collection").with_attribute(("short", repo));
+					coll.write_inner_content(|w| {
+						text(w, "name", repo)?;
+						for p in &a.pkgs {
+							let attrs = [
+								("name", &*p.name),
+								("version", &*p.ver),
+								("release", &*p.rel),
+								("epoch", &*p.epoch),
+								("arch", &*p.arch),
+							];
+							let e = w.create_element("package").with_attributes(attrs);
+							e.write_inner_content(|w| text(w, "filename", &p.filename))?;
+						}
+						Ok(())
+					})?;
+					Ok(())
+				})?;
+				Ok(())
+			})?;
+		}
+		Ok(())
+	})?;
+	String::from_utf8(w.into_inner()).map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FIXTURE: &str =
+		include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/updateinfo.xml"));
+
+	fn date(s: &str) -> NaiveDateTime {
+		NaiveDateTime::parse_from_str(s, DATE_FMT).unwrap()
+	}
+
+	#[test]
+	fn render_fixture() {
+		let pkg = |arch: &str| AdvisoryPkg {
+			advisory: "TERRA41-2026-0007".to_owned(),
+			name: "mpv".to_owned(),
+			epoch: "1".to_owned(),
+			ver: "0.39.0".to_owned(),
+			rel: "2.fc41".to_owned(),
+			arch: arch.to_owned(),
+			filename: format!("mpv-0.39.0-2.fc41.{arch}.rpm"),
+		};
+		let advisory = Advisory {
+			id: "TERRA41-2026-0007".to_owned(),
+			repo: "terra41".to_owned(),
+			kind: "security".to_owned(),
+			title: "mpv-0.39.0-2.fc41".to_owned(),
+			description: "Update mpv from 0.38.0-1.fc41 to 0.39.0-2.fc41 & fix <CVE>.".to_owned(),
+			severity: Some("Important".to_owned()),
+			issued: date("2026-03-01 12:00:00"),
+			updated: date("2026-03-02 08:30:00"),
+			refs: vec![Reference {
+				advisory: "TERRA41-2026-0007".to_owned(),
+				kind: "cve".to_owned(),
+				href: "https://www.cve.org/CVERecord?id=CVE-2026-0001".to_owned(),
+				ref_id: Some("CVE-2026-0001".to_owned()),
+				title: None,
+			}],
+			pkgs: vec![pkg("aarch64"), pkg("x86_64")],
+		};
+		assert_eq!(render("terra41", &[advisory]).unwrap(), FIXTURE.trim_end());
+	}
+}
@@ -0,0 +1,359 @@
+//! This file is part of Madoguchi.
+//!
+//! Madoguchi is free software: you can redistribute it and/or modify it under the terms of
+//! the GNU General Public License as published by the Free Software Foundation, either
+//! version 3 of the License, or (at your option) any later version.
+//!
+//! Madoguchi is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+//! without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+//! See the GNU General Public License for more details.
+//!
+//! You should have received a copy of the GNU General Public License along with Madoguchi.
+//! If not, see <https://www.gnu.org/licenses/>.
+//!
+//! Latest upstream versions of packages.
+//!
+//! Versions are either pushed by the update bot or looked up by a [`Checker`] selected by the
+//! package's source, written as `<checker>:<project>` (e.g. `github:terrapkg/anda`,
+//! `pypi:requests`, `crates:ripgrep`, `local:foo`).
+use crate::config::{self, Secret};
+use crate::rpmver::rpmvercmp;
+use crate::tasks::periodic;
+use chrono::NaiveDateTime;
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{error, info};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Cannot read local versions: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Cannot query upstream: {0}")]
+	Http(#[from] reqwest::Error),
+	#[error("Malformed upstream response: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Database error: {0}")]
+	Db(#[from] sqlx::Error),
+	#[error("Unknown upstream source `{0}`")]
+	BadSource(String),
+	#[error("No version found for `{0}`")]
+	NoVersion(String),
+}
+
+/// Looks up the latest released version of an upstream project.
+#[rocket::async_trait]
+pub trait Checker: Send + Sync {
+	async fn latest(&self, client: &reqwest::Client, project: &str) -> Result<String, Error>;
+}
+
+fn http_client() -> reqwest::Result<reqwest::Client> {
+	reqwest::Client::builder().user_agent(concat!("madoguchi/", env!("CARGO_PKG_VERSION"))).build()
+}
+
+/// GitHub releases, for `owner/repo`, authenticated with `upstream.github_token` if set.
+pub struct GitHub {
+	base: String,
+	token: Option<Secret>,
+}
+#[rocket::async_trait]
+impl Checker for GitHub {
+	async fn latest(&self, client: &reqwest::Client, project: &str) -> Result<String, Error> {
+		#[derive(Deserialize)]
+		struct Release {
+			tag_name: String,
+		}
+		let mut req = client.get(format!("{}/repos/{project}/releases/latest", self.base));
+		if let Some(Secret(token)) = &self.token {
+			req = req.bearer_auth(token);
+		}
+		let r: Release = req.send().await?.error_for_status()?.json().await?;
+		Ok(r.tag_name.trim_start_matches('v').to_owned())
+	}
+}
+
+/// The Python Package Index.
+pub struct PyPi {
+	base: String,
+}
+#[rocket::async_trait]
+impl Checker for PyPi {
+	async fn latest(&self, client: &reqwest::Client, project: &str) -> Result<String, Error> {
+		let url = format!("{}/pypi/{project}/json", self.base);
+		let r: serde_json::Value = client.get(url).send().await?.error_for_status()?.json().await?;
+		let ver = r.pointer("/info/version").and_then(|v| v.as_str());
+		ver.map(str::to_owned).ok_or_else(|| Error::NoVersion(project.to_owned()))
+	}
+}
+
+/// crates.io, preferring the latest stable version.
+pub struct Crates {
+	base: String,
+}
+#[rocket::async_trait]
+impl Checker for Crates {
+	async fn latest(&self, client: &reqwest::Client, project: &str) -> Result<String, Error> {
+		let url = format!("{}/api/v1/crates/{project}", self.base);
+		let r: serde_json::Value = client.get(url).send().await?.error_for_status()?.json().await?;
+		let ver = (r.pointer("/crate/max_stable_version").and_then(|v| v.as_str()))
+			.or_else(|| r.pointer("/crate/newest_version").and_then(|v| v.as_str()));
+		ver.map(str::to_owned).ok_or_else(|| Error::NoVersion(project.to_owned()))
+	}
+}
+
+/// A JSON object of `project => version` read from a file, for development and offline setups.
+pub struct Local {
+	path: PathBuf,
+}
+#[rocket::async_trait]
+impl Checker for Local {
+	async fn latest(&self, _: &reqwest::Client, project: &str) -> Result<String, Error> {
+		let versions: BTreeMap<String, String> =
+			serde_json::from_slice(&rocket::tokio::fs::read(&self.path).await?)?;
+		versions.get(project).cloned().ok_or_else(|| Error::NoVersion(project.to_owned()))
+	}
+}
+
+/// The checker and project of a `<checker>:<project>` source.
+pub fn parse_source<'s>(
+	settings: &config::Upstream, source: &'s str,
+) -> Result<(Box<dyn Checker>, &'s str), Error> {
+	let (name, project) =
+		source.split_once(':').ok_or_else(|| Error::BadSource(source.to_owned()))?;
+	let checker: Box<dyn Checker> = match name {
+		"github" => Box::new(GitHub {
+			base: settings.github_api.clone(),
+			token: settings.github_token.clone(),
+		}),
+		"pypi" => Box::new(PyPi { base: settings.pypi.clone() }),
+		"crates" => Box::new(Crates { base: settings.crates.clone() }),
+		"local" => Box::new(Local { path: settings.local.clone() }),
+		_ => return Err(Error::BadSource(source.to_owned())),
+	};
+	Ok((checker, project))
+}
+
+#[derive(sqlx::FromRow, Serialize, Debug, utoipa::ToSchema)]
+pub struct Upstream {
+	#[serde(skip)]
+	pub repo: String,
+	#[serde(skip)]
+	pub name: String,
+	pub source: Option<String>,
+	pub ver: Option<String>,
+	pub checked: Option<NaiveDateTime>,
+	pub error: Option<String>,
+}
+
+/// Changes pushed for a package; missing fields are left as is.
+#[derive(Deserialize, Debug, utoipa::ToSchema)]
+pub struct UpstreamEdit {
+	pub source: Option<String>,
+	pub ver: Option<String>,
+}
+
+pub async fn fetch(
+	db: &mut PgConnection, repo: &str, name: &str,
+) -> sqlx::Result<Option<Upstream>> {
+	sqlx::query_as("SELECT * FROM upstream WHERE (repo,name)=($1,$2)")
+		.bind(repo)
+		.bind(name)
+		.fetch_optional(db)
+		.await
+}
+
+/// Apply `edit` to the upstream info of a package. A pushed version counts as a check.
+pub async fn set(
+	db: &mut PgConnection, settings: &config::Upstream, repo: &str, name: &str, edit: &UpstreamEdit,
+) -> Result<(), Error> {
+	if let Some(source) = &edit.source {
+		parse_source(settings, source)?;
+	}
+	let checked = edit.ver.as_ref().map(|_| chrono::Utc::now().naive_utc());
+	sqlx::query(
+		"INSERT INTO upstream(repo,name,source,ver,checked) VALUES ($1,$2,$3,$4,$5)
+		ON CONFLICT (repo,name) DO UPDATE SET source=COALESCE($3,upstream.source),
+		ver=COALESCE($4,upstream.ver),checked=COALESCE($5,upstream.checked),
+		error=CASE WHEN $4 IS NULL THEN upstream.error END",
+	)
+	.bind(repo)
+	.bind(name)
+	.bind(&edit.source)
+	.bind(&edit.ver)
+	.bind(checked)
+	.execute(db)
+	.await?;
+	Ok(())
+}
+
+/// Run the checkers of all packages in `repo` that have a source, returning the results.
+pub async fn check(
+	db: &mut PgConnection, settings: &config::Upstream, repo: &str,
+) -> Result<Vec<Upstream>, Error> {
+	let client = http_client()?;
+	let mut pkgs = sqlx::query_as::<_, Upstream>(
+		"SELECT * FROM upstream WHERE repo=$1 AND source IS NOT NULL ORDER BY name",
+	)
+	.bind(repo)
+	.fetch_all(&mut *db)
+	.await?;
+	for u in &mut pkgs {
+		let Some(source) = &u.source else { continue };
+		let res = match parse_source(settings, source) {
+			Ok((checker, project)) => checker.latest(&client, project).await,
+			Err(e) => Err(e),
+		};
+		u.checked = Some(chrono::Utc::now().naive_utc());
+		match res {
+			Ok(ver) => (u.ver, u.error) = (Some(ver), None),
+			Err(e) => u.error = Some(e.to_string()),
+		}
+		sqlx::query("UPDATE upstream SET ver=$3,checked=$4,error=$5 WHERE (repo,name)=($1,$2)")
+			.bind(&u.repo)
+			.bind(&u.name)
+			.bind(&u.ver)
+			.bind(u.checked)
+			.bind(&u.error)
+			.execute(&mut *db)
+			.await?;
+	}
+	Ok(pkgs)
+}
+
+/// A package whose upstream version is newer than the packaged one.
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+pub struct Outdated {
+	pub name: String,
+	pub ver: String,
+	pub upstream: String,
+	pub source: Option<String>,
+	pub checked: Option<NaiveDateTime>,
+}
+
+/// Packages of `repo` that are behind upstream, compared with `rpmvercmp`.
+pub async fn outdated(db: &mut PgConnection, repo: &str) -> sqlx::Result<Vec<Outdated>> {
+	let rows = sqlx::query_as::<_, OutdatedRow>(
+		"SELECT p.name,p.ver,u.ver,u.source,u.checked FROM pkgs p
+		JOIN upstream u ON (u.repo,u.name)=(p.repo,p.name) WHERE p.repo=$1 AND u.ver IS NOT NULL",
+	)
+	.bind(repo)
+	.fetch_all(db)
+	.await?;
+	Ok(behind(rows))
+}
+
+type OutdatedRow = (String, String, String, Option<String>, Option<NaiveDateTime>);
+
+/// The rows whose upstream version is newer than the newest packaged one of the same name.
+fn behind(rows: Vec<OutdatedRow>) -> Vec<Outdated> {
+	let mut newest: BTreeMap<String, Outdated> = BTreeMap::new();
+	for (name, ver, upstream, source, checked) in rows {
+		if let Some(o) = newest.get(&name) {
+			if rpmvercmp(&o.ver, &ver) != Ordering::Less {
+				continue;
+			}
+		}
+		newest.insert(name.clone(), Outdated { name, ver, upstream, source, checked });
+	}
+	(newest.into_values()).filter(|o| rpmvercmp(&o.upstream, &o.ver) == Ordering::Greater).collect()
+}
+
+/// Remove the upstream info of all packages in `repo`.
+pub async fn delete_repo(db: &mut PgConnection, repo: &str) -> sqlx::Result<()> {
+	sqlx::query("DELETE FROM upstream WHERE repo=$1").bind(repo).execute(db).await?;
+	Ok(())
+}
+
+async fn check_all(pool: &PgPool, settings: &config::Upstream) {
+	let repos =
+		match sqlx::query_scalar::<_, String>("SELECT name FROM repos").fetch_all(pool).await {
+			Ok(repos) => repos,
+			Err(err) => return error!(?err, "Cannot list repos for upstream check"),
+		};
+	for repo in repos {
+		let mut conn = match pool.acquire().await {
+			Ok(conn) => conn,
+			Err(err) => return error!(?err, "Cannot acquire connection for upstream check"),
+		};
+		match check(&mut conn, settings, &repo).await {
+			Ok(r) => info!(
+				repo,
+				checked = r.len(),
+				failed = r.iter().filter(|u| u.error.is_some()).count(),
+				"Checked upstream versions"
+			),
+			Err(err) => error!(?err, repo, "Cannot check upstream versions"),
+		}
+	}
+}
+
+/// Periodically check upstream versions every `check_interval` seconds, if set.
+pub fn fairing(settings: &config::Upstream) -> AdHoc {
+	let interval = settings.check_interval.map(|secs| Duration::from_secs(secs.get()));
+	let settings = settings.clone();
+	periodic("Upstream check", interval, move |pool| {
+		let settings = settings.clone();
+		async move { check_all(&pool, &settings).await }
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::serve;
+
+	fn row(name: &str, ver: &str, upstream: &str) -> OutdatedRow {
+		(name.to_owned(), ver.to_owned(), upstream.to_owned(), None, None)
+	}
+
+	#[test]
+	fn outdated_against_the_newest_build() {
+		let rows = vec![
+			row("foo", "1.9", "1.10"),
+			row("bar", "2.0", "2.0"),
+			row("baz", "1.10", "1.9"),
+			row("qux", "1.0", "1.1"),
+			row("qux", "1.1", "1.1"),
+			row("rc", "1.0~rc1", "1.0"),
+		];
+		let names: Vec<_> = behind(rows).into_iter().map(|o| (o.name, o.ver)).collect();
+		assert_eq!(
+			names,
+			[("foo".to_owned(), "1.9".to_owned()), ("rc".to_owned(), "1.0~rc1".to_owned())]
+		);
+	}
+
+	#[rocket::async_test]
+	async fn checkers() {
+		let base = serve(vec![
+			("/repos/o/r/releases/latest", r#"{"tag_name":"v1.2.3"}"#.to_owned()),
+			("/pypi/requests/json", r#"{"info":{"version":"2.32.3"}}"#.to_owned()),
+			("/pypi/empty/json", r#"{"info":{}}"#.to_owned()),
+			(
+				"/api/v1/crates/serde",
+				r#"{"crate":{"max_stable_version":"1.0.210","newest_version":"2.0.0-rc.1"}}"#
+					.to_owned(),
+			),
+			(
+				"/api/v1/crates/pre",
+				r#"{"crate":{"max_stable_version":null,"newest_version":"0.1.0-alpha"}}"#
+					.to_owned(),
+			),
+		])
+		.await;
+		let client = http_client().unwrap();
+		let github = GitHub { base: base.clone(), token: None };
+		assert_eq!(github.latest(&client, "o/r").await.unwrap(), "1.2.3");
+		assert!(matches!(github.latest(&client, "o/missing").await, Err(Error::Http(_))));
+		let pypi = PyPi { base: base.clone() };
+		assert_eq!(pypi.latest(&client, "requests").await.unwrap(), "2.32.3");
+		assert!(matches!(pypi.latest(&client, "empty").await, Err(Error::NoVersion(_))));
+		let crates = Crates { base };
+		assert_eq!(crates.latest(&client, "serde").await.unwrap(), "1.0.210");
+		assert_eq!(crates.latest(&client, "pre").await.unwrap(), "0.1.0-alpha");
+	}
+}
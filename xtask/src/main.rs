@@ -15,6 +15,17 @@ fn try_main() -> Result<(), DynError> {
 	let task = env::args().nth(1);
 	match task.as_deref() {
 		Some("generate-jwt-key") => generate_jwt_key()?,
+		Some("ingest-logs") => ingest_logs(env::args().skip(2).collect())?,
+		Some("check-config") => madoguchi(&["check-config"])?,
+		Some("import-osv") => {
+			let files: Vec<String> = env::args().skip(2).collect();
+			if files.is_empty() {
+				return Err("Missing OSV dumps".into());
+			}
+			let mut args = vec!["import-osv"];
+			args.extend(files.iter().map(String::as_str));
+			madoguchi(&args)?;
+		},
 		_ => print_help(),
 	}
 	Ok(())
@@ -23,7 +34,13 @@ fn try_main() -> Result<(), DynError> {
 fn print_help() {
 	eprintln!(
 		"Tasks:
-generate-jwt-key            generates a JWT key for use in the JWT_KEY enviroment variable
+generate-jwt-key            generates a JWT key for the jwt_key setting (or JWT_KEY)
+check-config                validates the configuration from Rocket.toml, .env and the
+                            environment, as madoguchi would read it, without starting it
+import-osv <file>...        imports OSV dumps (JSON arrays or single advisories) into the
+                            configured database and matches the packages against them
+ingest-logs <url> <log>...  uploads mirror access logs (plain or gzipped) to count downloads;
+                            <url> is the madoguchi instance, the token is read from MADOGUCHI_TOKEN
 "
 	)
 }
@@ -34,3 +51,34 @@ fn generate_jwt_key() -> Result<(), DynError> {
 
 	Ok(())
 }
+
+/// Run madoguchi with `args`, e.g. one of its subcommands.
+fn madoguchi(args: &[&str]) -> Result<(), DynError> {
+	let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+	let status = std::process::Command::new(cargo)
+		.args(["run", "--quiet", "--package", "madoguchi", "--"])
+		.args(args)
+		.status()?;
+	if !status.success() {
+		return Err(format!("madoguchi {} failed", args[0]).into());
+	}
+	Ok(())
+}
+
+fn ingest_logs(args: Vec<String>) -> Result<(), DynError> {
+	let (url, logs) = args.split_first().ok_or("Missing madoguchi URL")?;
+	if logs.is_empty() {
+		return Err("Missing access logs".into());
+	}
+	let token = env::var("MADOGUCHI_TOKEN").map_err(|_| "MADOGUCHI_TOKEN is not set")?;
+	let url = format!("{}/v4/downloads/import", url.trim_end_matches('/'));
+	for log in logs {
+		let file = std::fs::File::open(log)?;
+		let mut res = ureq::post(&url)
+			.header("Authorization", &format!("Bearer {token}"))
+			.content_type("text/plain")
+			.send(file)?;
+		println!("{log}: {}", res.body_mut().read_to_string()?);
+	}
+	Ok(())
+}